@@ -1,47 +1,23 @@
-#![allow(dead_code)]
-
 use chrono::NaiveDate;
 use regex::Regex;
-use std::io;
+use std::{io, path};
 use std::fs::File;
 use std::io::BufRead;
 use std::collections::BTreeMap;
 use crate::gen;
 use util::parse;
 
-const FILE_IMPORT_BOOKS_PERSONAL: &str = r"E:\ConnectedText Restructure 2020-10-17\Audible Books Personal.txt";
-const FILE_IMPORT_PURCHASE_DATES_PERSONAL: &str = r"E:\ConnectedText Restructure 2020-10-17\Audible Books Purchase History Personal.txt";
-const FILE_IMPORT_BOOKS_QUADRAVEN: &str = r"E:\ConnectedText Restructure 2020-10-17\Audible Books Quadraven.txt";
-const FILE_IMPORT_PURCHASE_DATES_QUADRAVEN: &str = r"E:\ConnectedText Restructure 2020-10-17\Audible Books Purchase History Quadraven.txt";
-const PATH_HOME_PROJECT_SOURCE: &str = r"E:\ConnectedText Restructure\Home Project";
-const PATH_HOME_PROJECT_DEST: &str = r"E:\ConnectedText Restructure 2020-10-17\Home Dest";
-const PATH_HOME_PROJECT_DEST_FIXED: &str = r"E:\ConnectedText Restructure 2020-10-17\Home Dest Fixed";
-const PATH_TOOLS_PROJECT_DEST: &str = r"E:\ConnectedText Restructure 2020-10-17\Tools Dest";
-const PATH_TOOLS_PROJECT_DEST_FIXED: &str = r"E:\ConnectedText Restructure 2020-10-17\Tools Dest Fixed";
-const PATH_GEN_BOOKS: &str = r"E:\ConnectedText Restructure 2020-10-17\Gen Books";
-
-pub fn main() {
-    println!("\nAudible start\n");
-
-    // import::fix_file_names(path::Path::new(FILE_FULL_EXPORT), path::Path::new(PATH_TOOLS_PROJECT_DEST), path::Path::new(PATH_TOOLS_PROJECT_DEST_FIXED));
-    // import::fix_file_names(path::Path::new(FILE_FULL_EXPORT), path::Path::new(PATH_HOME_PROJECT_DEST), path::Path::new(PATH_HOME_PROJECT_DEST_FIXED));
-    // gen_from_audible_books(FILE_IMPORT_BOOKS_PERSONAL, FILE_IMPORT_PURCHASE_DATES_PERSONAL, "personal", PATH_GEN_BOOKS);
-    // gen_from_audible_books(FILE_IMPORT_BOOKS_QUADRAVEN, FILE_IMPORT_PURCHASE_DATES_QUADRAVEN, "Quadraven", PATH_GEN_BOOKS);
-
-    println!("\nAudible done\n");
+pub fn gen_from_audible_books(path_file_books: &path::Path, path_file_purchase_dates: &path::Path, account_name: &str, path_gen: &path::Path) -> io::Result<()> {
+    let books = import_audible_books(path_file_books, path_file_purchase_dates, account_name)?;
+    gen::gen_book_text_files(path_gen, books)
 }
 
-fn gen_from_audible_books(file_import_books: &str, file_import_purchase_dates: &str, account_name: &str, path_gen: &str) {
-    let books = import_audible_books(file_import_books, file_import_purchase_dates, account_name);
-    gen::gen_book_text_files(path_gen, books);
-}
-
-pub fn import_audible_books(file_import_books: &str, file_import_purchase_dates: &str, account_name: &str) -> Vec<BookForAudible> {
-    let purchase_dates = import_audible_purchase_dates(file_import_purchase_dates);
+pub fn import_audible_books(path_file_books: &path::Path, path_file_purchase_dates: &path::Path, account_name: &str) -> io::Result<Vec<BookForAudible>> {
+    let purchase_dates = import_audible_purchase_dates(path_file_purchase_dates)?;
     let mut v = vec![];
     let mut book = make_empty_audible_book(account_name);
     let mut title_line = false;
-    let file = File::open(file_import_books).unwrap();
+    let file = File::open(path_file_books)?;
     for line_result in io::BufReader::new(file).lines() {
         if book.title.len() > 0 && book.short_title.len() > 0 && book.acquired_date.is_none() {
             // Figure out the aquired date.
@@ -53,12 +29,11 @@ pub fn import_audible_books(file_import_books: &str, file_import_purchase_dates:
                 if let Some(date) = date {
                     book.acquired_date = Some(*date);
                 } else {
-                    dbg!(&book);
-                    panic!("No match for purchase date.");
+                    return Err(invalid_data(path_file_purchase_dates, &format!("No purchase date for {:?}.", book.title)));
                 }
             }
         }
-        let line = line_result.unwrap().trim().to_string();
+        let line = line_result?.trim().to_string();
         //rintln!("{}", line);
         if line.contains("By  cover art") {
             if book.short_title.len() > 0 {
@@ -98,45 +73,41 @@ pub fn import_audible_books(file_import_books: &str, file_import_purchase_dates:
         }
     }
     v.push(book.clone());
-    dbg!(&v);
-    v
+    Ok(v)
 }
 
-pub fn import_audible_purchase_dates(file_import: &str) -> BTreeMap<String, NaiveDate> {
+pub fn import_audible_purchase_dates(path_file: &path::Path) -> io::Result<BTreeMap<String, NaiveDate>> {
     let mut purchase_dates = BTreeMap::new();
-    dbg!(&file_import);
-    let file = File::open(file_import).unwrap();
-    let lines: Vec<String> = io::BufReader::new(file).lines().map(|x| x.unwrap().trim().to_string()).collect::<Vec<_>>();
+    let file = File::open(path_file)?;
+    let lines: Vec<String> = io::BufReader::new(file).lines().map(|x| x.map(|line| line.trim().to_string())).collect::<io::Result<Vec<_>>>()?;
     let mut date = NaiveDate::from_ymd(1900, 1, 1);
     let date_regex = Regex::new(r"^\d{2}-\d{2}-\d{2}$").unwrap();
     // let date_regex = Regex::new(r"18").unwrap();
     //bg!(lines);
     for line_index in 0..lines.len() {
         if lines[line_index].starts_with("By: ") {
-            // See if the next line has a date.
-            let date_line = lines[line_index + 1].clone();
-            if date_regex.is_match(&date_line) {
-                //rintln!("{}", &lines[line_index + 1]);
-                let m = u32::from_str_radix(&date_line[..2], 10).unwrap();
-                assert!(m > 0);
-                assert!(m <= 12);
-                let d = u32::from_str_radix(&date_line[3..5], 10).unwrap();
-                assert!(d > 0);
-                assert!(d <= 31);
-                let y = 2000 + i32::from_str_radix(&date_line[6..8], 10).unwrap();
-                assert!(y >= 2014);
-                assert!(y <= 2020);
-                date = NaiveDate::from_ymd(y, m, d);
-                //bg!(&date);
+            // See if the next line has a date. The regex guarantees the digits, so only the
+            // month and day ranges need checking.
+            if let Some(date_line) = lines.get(line_index + 1).filter(|x| date_regex.is_match(x)) {
+                let m = date_line[..2].parse::<u32>().unwrap_or(0);
+                let d = date_line[3..5].parse::<u32>().unwrap_or(0);
+                let y = 2000 + date_line[6..8].parse::<i32>().unwrap_or(0);
+                date = NaiveDate::from_ymd_opt(y, m, d)
+                    .ok_or_else(|| invalid_data(path_file, &format!("Line {}: {:?} isn't a valid MM-DD-YY date.", line_index + 2, date_line)))?;
             }
             // The title is one line before the "By: " line.
+            if line_index == 0 {
+                return Err(invalid_data(path_file, "Line 1: \"By: \" line with no title before it."));
+            }
             let title = parse::after(&lines[line_index - 1], "By: ").trim().to_string();
             purchase_dates.insert(title, date.clone());
         }
     }
-    dbg!(&purchase_dates);
-    dbg!(&purchase_dates.len());
-    purchase_dates
+    Ok(purchase_dates)
+}
+
+fn invalid_data(path_file: &path::Path, message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path_file.display(), message))
 }
 
 fn make_empty_audible_book(account_name: &str) -> BookForAudible {
@@ -0,0 +1,135 @@
+use std::{fs, io, path};
+use serde::Deserialize;
+use crate::simple::{cache, import, paths};
+use crate::simple::input::Encoding;
+use crate::simple::model::Wiki;
+
+// The locations of the ConnectedText projects and related files, read from a TOML file such as
+// connectedtext.example.toml. Relative paths are taken to be relative to the config file.
+
+pub const FILE_CONFIG_DEFAULT: &str = "connectedtext.toml";
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    #[serde(default, rename = "project")]
+    pub projects: Vec<ProjectConfig>,
+    #[serde(default, rename = "audible_account")]
+    pub audible_accounts: Vec<AudibleAccountConfig>,
+    pub audible_gen_folder: Option<path::PathBuf>,
+    pub bookmarks_file: Option<path::PathBuf>,
+    // A folder of WARC files or a CSV file of HTTP statuses for checking URL links offline.
+    pub link_snapshot: Option<path::PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProjectConfig {
+    pub name: String,
+    // The {{Topic}}-delimited export of the whole project.
+    pub export_file: path::PathBuf,
+    // The folder with one .TXT file per topic.
+    pub topic_folder: Option<path::PathBuf>,
+    // Defaults to the Images folder inside the topic folder.
+    pub image_folder: Option<path::PathBuf>,
+    #[serde(default = "default_encoding")]
+    pub encoding: String,
+    // Where to keep what was parsed from the export file so that the next load only parses the
+    // topics that changed. No cache if not given.
+    pub cache_file: Option<path::PathBuf>,
+    // The same for the topic folder, used by watch. It has to be a different file from
+    // cache_file since each cache belongs to the file or folder it was made from.
+    pub folder_cache_file: Option<path::PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AudibleAccountConfig {
+    pub name: String,
+    pub books_file: path::PathBuf,
+    pub purchases_file: path::PathBuf,
+}
+
+fn default_encoding() -> String {
+    "auto".to_string()
+}
+
+impl Config {
+    pub fn load(path_file: &path::Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path_file)?;
+        let mut config: Config = toml::from_str(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path_file.display(), e)))?;
+        let path_base = match path_file.parent() {
+            Some(path_base) if !path_base.as_os_str().is_empty() => path_base.to_path_buf(),
+            _ => std::env::current_dir()?,
+        };
+        config.resolve_paths(&path_base);
+        for project in config.projects.iter() {
+            if Encoding::from_config_name(&project.encoding).is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: unknown encoding {:?} for project {:?}.", path_file.display(), project.encoding, project.name)));
+            }
+        }
+        Ok(config)
+    }
+
+    fn resolve_paths(&mut self, path_base: &path::Path) {
+        for project in self.projects.iter_mut() {
+            resolve_path(path_base, &mut project.export_file);
+            resolve_optional_path(path_base, &mut project.topic_folder);
+            resolve_optional_path(path_base, &mut project.image_folder);
+            resolve_optional_path(path_base, &mut project.cache_file);
+            resolve_optional_path(path_base, &mut project.folder_cache_file);
+        }
+        for account in self.audible_accounts.iter_mut() {
+            resolve_path(path_base, &mut account.books_file);
+            resolve_path(path_base, &mut account.purchases_file);
+        }
+        resolve_optional_path(path_base, &mut self.audible_gen_folder);
+        resolve_optional_path(path_base, &mut self.bookmarks_file);
+        resolve_optional_path(path_base, &mut self.link_snapshot);
+    }
+
+    pub fn project(&self, project_name: &str) -> Option<&ProjectConfig> {
+        self.projects.iter().find(|project| project.name.eq_ignore_ascii_case(project_name))
+    }
+
+    pub fn audible_account(&self, account_name: &str) -> Option<&AudibleAccountConfig> {
+        self.audible_accounts.iter().find(|account| account.name.eq_ignore_ascii_case(account_name))
+    }
+}
+
+impl ProjectConfig {
+    pub fn import_topics_cached(&self) -> io::Result<Wiki> {
+        match &self.cache_file {
+            Some(cache_file) => {
+                let (wiki, update) = cache::import_topics_cached(&self.export_file, &self.name, self.encoding(), cache_file)?;
+                update.print();
+                Ok(wiki)
+            },
+            None => import::import_topics_with_encoding(&self.export_file, &self.name, self.encoding()),
+        }
+    }
+
+    pub fn encoding(&self) -> Option<Encoding> {
+        // None means detect it. Config::load() has already checked the name, so an unknown one
+        // only gets here from a ProjectConfig built some other way, and is detected too.
+        Encoding::from_config_name(&self.encoding).flatten()
+    }
+
+    pub fn image_folder(&self) -> Option<path::PathBuf> {
+        match (&self.image_folder, &self.topic_folder) {
+            (Some(image_folder), _) => Some(image_folder.clone()),
+            (None, Some(topic_folder)) => Some(paths::resolve_ci_or_join(topic_folder, paths::FOLDER_IMAGES)),
+            (None, None) => None,
+        }
+    }
+}
+
+fn resolve_path(path_base: &path::Path, path: &mut path::PathBuf) {
+    if path.is_relative() {
+        *path = path_base.join(&path);
+    }
+}
+
+fn resolve_optional_path(path_base: &path::Path, path: &mut Option<path::PathBuf>) {
+    if let Some(path) = path {
+        resolve_path(path_base, path);
+    }
+}
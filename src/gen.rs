@@ -1,11 +1,11 @@
-use std::path;
+use std::{io, path};
 use util::html;
 use crate::*;
 use chrono::NaiveDate;
 use itertools::Itertools;
-use std::fs::File;
-use std::io::Write;
 use crate::audible::BookForAudible;
+use crate::simple::bookmarks::BookmarkFolder;
+use crate::simple::plan::{ChangePlan, ConflictHandling, FileOperation};
 
 pub fn gen_page_from_chrome_bookmarks(path_file: &path::Path) {
     assert!(path_file.is_file());
@@ -26,6 +26,28 @@ fn gen_section_from_chrome_bookmarks(s: &mut String, depth: usize, bookmark_set:
     }
 }
 
+pub fn gen_page_from_bookmarks(root: &BookmarkFolder) -> String {
+    // The browser's top-level folders, such as the bookmarks bar, are the first level of headings.
+    let mut s = String::new();
+    for bookmark in &root.bookmarks {
+        gen_url_line_label_first(&mut s, &bookmark.url, &bookmark.title);
+    }
+    for folder in &root.folders {
+        gen_section_from_bookmarks(&mut s, 1, folder);
+    }
+    s
+}
+
+fn gen_section_from_bookmarks(s: &mut String, depth: usize, folder: &BookmarkFolder) {
+    gen_header(s, depth, &folder.name);
+    for bookmark in &folder.bookmarks {
+        gen_url_line_label_first(s, &bookmark.url, &bookmark.title);
+    }
+    for child in &folder.folders {
+        gen_section_from_bookmarks(s, depth + 1, child);
+    }
+}
+
 pub fn gen_header(s: &mut String, depth: usize, label: &str) {
     let header_delimiter = "=".repeat(depth);
     s.push_str(&format!("\n\n{}{}{}\n", header_delimiter, label, header_delimiter));
@@ -35,7 +57,12 @@ pub fn gen_url_line_label_first(s: &mut String, url: &str, label: &str) {
     s.push_str(&format!("\n * {}: [[$URL:{}]]", label, url));
 }
 
-pub fn gen_book_text_files(path_gen: &str, books: Vec<BookForAudible>) {
+pub fn gen_book_text_files(path_gen: &path::Path, books: Vec<BookForAudible>) -> io::Result<()> {
+    plan_book_text_files(path_gen, books).apply(ConflictHandling::Fail).map(|_| ())
+}
+
+pub fn plan_book_text_files(path_gen: &path::Path, books: Vec<BookForAudible>) -> ChangePlan {
+    let mut plan = ChangePlan::new("Generate book topics");
     let added_date = NaiveDate::from_ymd(2020, 10, 18);
     for book in books.iter() {
         let category = gen_category("Books");
@@ -55,42 +82,43 @@ pub fn gen_book_text_files(path_gen: &str, books: Vec<BookForAudible>) {
         let text = format!("{}\n\n{{|\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
                 category, title, series, author, narrator, format, location, year, added, acquired,
                 read, started, completed);
-        let gen_file_name = format!("{}\\{}", path_gen, topic_name_to_file_name(&book.short_title));
-        dbg!(&gen_file_name, &text);
-
-        let mut file = File::create(&gen_file_name).unwrap();
-        file.write_all(text.as_bytes()).expect("could not write file");
+        let path_file_gen = path_gen.join(topic_name_to_file_name(&book.short_title));
+        plan.push(FileOperation::Create { path_dest: path_file_gen, content: text });
     }
+    plan
 }
 
-fn gen_category(value: &str) -> String {
+pub fn gen_category(value: &str) -> String {
     // Example:
     // [[$CATEGORY:Books]]
     format!("[[$CATEGORY:{}]]", value).to_string()
 }
 
-fn gen_table_field_string_single(label: &str, value: Option<&str>) -> String {
+pub fn gen_table_field_string_single(label: &str, value: Option<&str>) -> String {
     // Example:
     // ||Title||[[Title:=Flowers for Algernon]]||
     let value = value.unwrap_or(CT_DUMMY_VALUE);
     format!("||{}||[[{}:={}]]||", label, label, value).to_string()
 }
 
-fn gen_table_field_string_multiple(label: &str, values: &Vec<String>) -> String {
+pub fn gen_table_field_string_multiple(label: &str, values: &Vec<String>) -> String {
     // Example:
     // ||Author||[[Author:=Jason Fried]], [[Author:=Heinemeier David Hansson]], [[Author:=Matthew Linderman]]||
-    assert!(!values.is_empty());
+    // A book with no narrator, for instance, gets the placeholder value.
+    if values.is_empty() {
+        return gen_table_field_string_single(label, None);
+    }
     let list = values.iter().map(|x| format!("[[{}:={}]]", label, x).to_string()).join(", ");
     format!("||{}||{}||", label, list).to_string()
 }
 
-fn gen_table_field_date_single(label: &str, value: Option<NaiveDate>) -> String {
+pub fn gen_table_field_date_single(label: &str, value: Option<NaiveDate>) -> String {
     // Example:
     // ||Added||[[Added:=20171128]]||
     gen_table_field_string_single(label, value.map(|x| x.format("%Y%m%d").to_string()).as_deref())
 }
 
-fn gen_table_field_bool_single(label: &str, value: Option<bool>) -> String {
+pub fn gen_table_field_bool_single(label: &str, value: Option<bool>) -> String {
     // Example:
     // ||Read||[[Read:=Yes]]||
     gen_table_field_string_single(label, value.map(|x| if x { "Yes" } else { "No" }))
@@ -11,6 +11,7 @@ extern crate util;
 pub use util::*;
 
 pub mod audible;
+pub mod config;
 pub mod gen;
 pub mod simple;
 pub mod rc_refcell;
@@ -1,103 +1,665 @@
-#![allow(dead_code)]
-#![allow(unused_variables)]
-#![allow(unused_imports)]
-#![allow(unused_assignments)]
-#![allow(unused_mut)]
-
-use std::path;
+use std::{env, io, path, process};
 
 // use util::html;
 // use util::format;
 use util::group::Grouper;
-use std::fmt::Write;
 use std::collections::BTreeMap;
 
 use connectedtext::*;
 use simple::*;
+use simple::model::Wiki;
+use config::Config;
 
 //#[macro_use]
 //extern crate util;
 
-const FILE_FULL_EXPORT: &str = r"E:\ConnectedText Restructure 2020-10-17\Home Export One File\Wiki Export.TXT";
-const FILE_IMPORT_TOOLS: &str = r"Tools.txt";
-const FILE_IMPORT_HOME: &str = r"Home.txt";
-const PATH_HOME_ARCHIVE_PROJECT_SOURCE: &str = r"E:\ConnectedText Restructure\Home Archive Project";
-const PATH_HOME_ARCHIVE_PROJECT_DEST: &str = r"E:\ConnectedText Restructure\Home Archive Project Dest";
-const PATH_TOOLS_PROJECT_SOURCE: &str = r"E:\ConnectedText Restructure\Tools Project";
+// Exit codes, so that scripts can tell a clean run from one that found something and from one
+// that failed.
+const EXIT_OK: i32 = 0;
+const EXIT_FINDINGS: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_ERROR: i32 = 3;
 
-const PATH_CHROME_BOOKMARKS: &str = r"E:\Temp\bookmarks_1_29_20.html";
+const USAGE: &str = r"Usage: connectedtext <command> [--config FILE] [options]
 
-fn main() {
-    println!("\nConnectedText start\n");
+Commands:
+  import          [--project NAME[=FILE] ...]
+  lint            [--project NAME[=FILE] ...] [--stream]
+  export          [--project NAME[=FILE] ...] --dest FOLDER [--format txt|md|html] [--stream] [--attachments]
+  report          [--project NAME[=FILE] ...] --kind links|added-dates|derived-added-dates|attributes|categories|domains
+  query           [--project NAME[=FILE] ...] [--category NAME] [--attr NAME=VALUE ...] [--text TEXT] [--links-to TOPIC] [--stream]
+  fix-file-names  --project NAME --dest FOLDER [--export FILE] [--source FOLDER]
+  copy-images     --source FOLDER --dest FOLDER, or --project NAME --dest FOLDER
+  images          --project NAME[=FILE] [--images FOLDER] [--delete-orphans] [--fix-case]
+  attachments     --project NAME[=FILE] [--folder FOLDER]
+  link-rot        [--project NAME[=FILE] ...] [--snapshot FILE|FOLDER] [--archive-prefix URL] [--rewrite] [--update-redirects]
+  audible         --account NAME [--books FILE] [--purchases FILE] [--dest FOLDER]
+  bookmarks       [--file FILE] [--format chrome|firefox|html] [--topics --project NAME[=FILE] [--category NAME]]
+                  [--compare [--project NAME[=FILE] ...] [--staging TOPIC --project NAME[=FILE] [--category NAME]]]
+  apply-plan      --plan FILE
+  compare         --project NAME [--export FILE] [--folder FOLDER]
+  reconcile       --project NAME [--export FILE] [--folder FOLDER]
+  watch           --project NAME [--folder FOLDER] [--export FILE] [--dest FOLDER [--format txt|md|html]] [--interval SECONDS] [--no-lint]
 
-    log::clear();
+export, fix-file-names, copy-images, audible, bookmarks with --topics or --staging, images with
+--delete-orphans or --fix-case and link-rot with --rewrite write files. They also take:
+  --dry-run                           Print what would be done without touching any files.
+  --save-plan FILE                    Save what would be done as JSON for apply-plan instead.
+  --on-conflict fail|skip|overwrite|rename
+                                      What to do when a destination file already exists. The
+                                      default is fail, which stops before anything is written.
+
+The project folders, export files, Audible account files and bookmarks file come from the config
+file, which is connectedtext.toml in the current folder unless --config is given. See
+connectedtext.example.toml. Anything given on the command line overrides the config file.
+
+Each --project loads a {{Topic}}-delimited export file under the given project name, either the
+export_file from the config file or the one given, as in --project Tools=Tools.txt. With no
+--project, every project in the config file is loaded.
+
+compare loads a project from both its export file and its folder of one .TXT file per topic and
+lists the topics that differ, with exit code 1 if any do.
+
+reconcile matches the topics in a full export against the files in a project's folder of one .TXT
+file per topic and lists topics without files, files without topics, topics that map to the same
+file name and files whose content differs from the export, each with a suggested fix. The exit
+code is 1 if there are any.
+
+images checks the $IMG references in a project's topics against its image_folder, or the Images
+folder in its topic_folder, and lists missing images, images no topic refers to, images that are
+the same file under different names and references whose case doesn't match the file, with the
+total image size of the largest topics. --delete-orphans deletes the images no topic refers to and
+--fix-case renames images to the case the topics use.
+
+attachments does the same for the files that topics link to with $FILE, such as PDFs, which are
+relative to the project's topic_folder. export --attachments copies them into the export for every
+project with a topic_folder, keeping their paths so that the links in the exported topics work.
 
-    // gen::gen_page_from_chrome_bookmarks(path::Path::new(PATH_CHROME_BOOKMARKS));
-    // audible::main();
-    // try_load_topics();
-    // try_load_links();
-    // dbg!(count_topics_in_tools());
-    // catalog_attributes();
-    // catalog_categories();
-    // import_topics().report_added_dates();
-    import_topics().report_derived_added_dates();
+link-rot checks the $URL links and bare URLs in the topics against a snapshot of fetching them made
+elsewhere, so nothing goes out to the network. The snapshot is a folder of WARC files or a CSV file
+of url,status[,location,archive_url] rows, by default the link_snapshot from the config file. It
+lists the links that are dead or redirected by topic, with exit code 1 if there are any.
+--archive-prefix, such as https://web.archive.org/web/, gives archived copies to URLs the snapshot
+has none for. --rewrite changes dead links to their archived copies in the project's topic_folder,
+and with --update-redirects changes redirected links to where they end up.
 
+bookmarks reads Chrome's Bookmarks file, a Firefox JSON backup or bookmarks exported as HTML by
+any browser, working out which from the content unless --format is given, and prints them as one
+page with a heading for each folder. With --topics it writes a topic for each folder instead into
+the project's topic_folder, in the category Bookmarks unless --category is given, with links to the
+parent and child folders' topics and an Added date from the earliest bookmark. Bookmarks that any
+topic in the wiki already links to are left out, and topics that already exist only get the links
+and bookmarks they don't have yet. With --compare it lists which bookmarks
+are new, which are already in the wiki's URL links and which are near duplicates, meaning links to
+the same page with a different scheme, query string or fragment. --staging also writes the new ones
+into the given topic, with a heading for each folder, to be sorted by hand.
 
-    //bg!(&util::log::get_sorted());
-    //import::test_delimited_entries();
+watch keeps checking a folder of one .TXT file per topic, by default the project's topic_folder,
+and when files change prints the lint diagnostics that are new or resolved and, with --dest,
+exports the changed topics again. The files are matched to topics through the export file, by
+default the project's export_file, and a project's folder_cache_file keeps what was parsed from
+them between runs. It runs until stopped.
 
-    println!("\nConnectedText done\n");
+--stream reads each export file one topic at a time instead of loading the whole wiki, for exports
+too big to fit in memory. It can't be combined with --links-to, --dry-run, --save-plan or
+--attachments.
+
+Exit codes: 0 = success, 1 = lint diagnostics found, no topics matched or a dry run found
+conflicts, 2 = bad arguments, 3 = error while running.";
+
+const FLAG_NAMES: [&str; 10] = ["attachments", "compare", "delete-orphans", "dry-run", "fix-case", "no-lint", "rewrite", "stream", "topics", "update-redirects"];
+
+#[derive(Debug)]
+enum CliError {
+    Usage(String),
+    Io(io::Error),
 }
 
-fn run_import() {
-    let path_file_full_export = path::Path::new(FILE_FULL_EXPORT);
-    // let path_source = path::Path::new(PATH_HOME_PROJECT_SOURCE);
-    // let path_dest = path::Path::new(PATH_HOME_PROJECT_DEST);
-    let path_source = path::Path::new(PATH_HOME_ARCHIVE_PROJECT_SOURCE);
-    let path_dest = path::Path::new(PATH_HOME_ARCHIVE_PROJECT_DEST);
-    // let path_source = path::Path::new(PATH_TOOLS_PROJECT_SOURCE);
-    // let path_dest = path::Path::new(PATH_TOOLS_PROJECT_DEST);
+impl From<io::Error> for CliError {
+    fn from(error: io::Error) -> Self {
+        CliError::Io(error)
+    }
+}
 
-    // import::fix_file_names(path_file_full_export, path_source, path_dest).ok();
-    // dbg!(&import::get_image_file_names(path::Path::new(PATH_HOME_PROJECT_DEST)));
-    import::copy_image_files(path_source, path_dest).ok();
+type CliResult = Result<i32, CliError>;
 
-    // dbg!(&import::get_all_topic_names(path_file_full_export));
-    // dbg!(&import::reconcile_files_and_topics(path_file_full_export, path_source));
-    // import::fix_file_names(path::Path::new(PATH_TOOLS_PROJECT_SOURCE), path::Path::new(PATH_TOOLS_PROJECT_DEST)).ok();
-    // dbg!(&import::get_image_file_names(path::Path::new(PATH_TOOLS_PROJECT_DEST)));
-    // import::copy_image_files(path::Path::new(PATH_TOOLS_PROJECT_SOURCE), path::Path::new(PATH_TOOLS_PROJECT_DEST)).ok();
+struct Args {
+    command: String,
+    options: Vec<(String, String)>,
+    flags: Vec<String>,
+}
 
+impl Args {
+    fn parse(args: Vec<String>, flag_names: &[&str]) -> Result<Self, CliError> {
+        // Options are "--name value" or "--name=value" and may be repeated. Flags are options
+        // without a value and must be listed in flag_names.
+        let mut args = args.into_iter();
+        let command = args.next().ok_or_else(|| CliError::Usage("No command given.".to_string()))?;
+        let mut options = vec![];
+        let mut flags = vec![];
+        while let Some(arg) = args.next() {
+            let name = match arg.strip_prefix("--") {
+                Some(name) => name.to_string(),
+                None => return Err(CliError::Usage(format!("Unexpected argument {:?}.", arg))),
+            };
+            if let Some((name, value)) = name.split_once("=") {
+                options.push((name.to_string(), value.to_string()));
+            } else if flag_names.contains(&name.as_str()) {
+                flags.push(name);
+            } else {
+                let value = args.next().ok_or_else(|| CliError::Usage(format!("No value for --{}.", name)))?;
+                options.push((name, value));
+            }
+        }
+        Ok(Self { command, options, flags })
+    }
+
+    fn values(&self, name: &str) -> Vec<&str> {
+        self.options.iter().filter(|(x, _)| x == name).map(|(_, value)| value.as_str()).collect()
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.values(name).last().copied()
+    }
+
+    fn required(&self, name: &str) -> Result<&str, CliError> {
+        self.value(name).ok_or_else(|| CliError::Usage(format!("--{} is required for {}.", name, self.command)))
+    }
+
+    fn required_path(&self, name: &str) -> Result<path::PathBuf, CliError> {
+        // The library functions assert on relative paths, so make them absolute here.
+        let path = path::PathBuf::from(self.required(name)?);
+        Ok(if path.is_absolute() { path } else { env::current_dir()?.join(path) })
+    }
+
+    fn has_flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|x| x == name)
+    }
 }
 
-fn count_topics_in_tools() -> usize {
-    import::import_topics(FILE_IMPORT_TOOLS, "Tools").topics.len()
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.is_empty() || args[0] == "help" || args[0] == "--help" {
+        println!("{}", USAGE);
+        process::exit(if args.is_empty() { EXIT_USAGE } else { EXIT_OK });
+    }
+    log::clear();
+    let exit_code = match Args::parse(args, &FLAG_NAMES).and_then(|args| run(&args, load_config(&args)?.as_ref())) {
+        Ok(exit_code) => exit_code,
+        Err(CliError::Usage(message)) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            EXIT_USAGE
+        },
+        Err(CliError::Io(error)) => {
+            eprintln!("Error: {}", error);
+            EXIT_ERROR
+        },
+    };
+    process::exit(exit_code);
 }
 
-fn import_topics() -> crate::model::Wiki {
-    let mut wiki = import::import_topics(FILE_IMPORT_TOOLS, "Tools");
-    wiki.append(import::import_topics(FILE_IMPORT_HOME, "Home"));
-    import::add_links(&mut wiki);
-    wiki
+fn run(args: &Args, config: Option<&Config>) -> CliResult {
+    match args.command.as_ref() {
+        "import" => run_import(args, config),
+        "lint" => run_lint(args, config),
+        "export" => run_export(args, config),
+        "report" => run_report(args, config),
+        "query" => run_query(args, config),
+        "fix-file-names" => run_fix_file_names(args, config),
+        "copy-images" => run_copy_images(args, config),
+        "audible" => run_audible(args, config),
+        "bookmarks" => run_bookmarks(args, config),
+        "apply-plan" => run_apply_plan(args),
+        "watch" => run_watch(args, config),
+        "compare" => run_compare(args, config),
+        "reconcile" => run_reconcile(args, config),
+        "images" => run_images(args, config),
+        "attachments" => run_attachments(args, config),
+        "link-rot" => run_link_rot(args, config),
+        _ => Err(CliError::Usage(format!("Unknown command {:?}.", args.command))),
+    }
+}
+
+fn load_config(args: &Args) -> Result<Option<Config>, CliError> {
+    match args.value("config") {
+        Some(file_config) => Ok(Some(Config::load(path::Path::new(file_config))?)),
+        None => {
+            let path_file_config = path::Path::new(config::FILE_CONFIG_DEFAULT);
+            if path_file_config.is_file() {
+                Ok(Some(Config::load(path_file_config)?))
+            } else {
+                Ok(None)
+            }
+        },
+    }
+}
+
+fn config_project<'a>(config: Option<&'a Config>, project_name: &str) -> Result<&'a config::ProjectConfig, CliError> {
+    config.and_then(|config| config.project(project_name))
+        .ok_or_else(|| CliError::Usage(format!("Project {:?} is not in the config file.", project_name)))
+}
+
+fn path_from_args_or_config(args: &Args, name: &str, from_config: Option<path::PathBuf>) -> Result<path::PathBuf, CliError> {
+    match (args.value(name), from_config) {
+        (Some(_), _) => args.required_path(name),
+        (None, Some(path)) => Ok(path),
+        (None, None) => Err(CliError::Usage(format!("--{} is required for {} unless it's in the config file.", name, args.command))),
+    }
+}
+
+fn run_plan(args: &Args, plan: plan::ChangePlan) -> CliResult {
+    // Every command that writes files goes through here so that --dry-run, --save-plan and
+    // --on-conflict work the same way for all of them.
+    let conflict_handling = conflict_handling(args)?;
+    if args.has_flag("dry-run") {
+        plan.print();
+        return Ok(if plan.conflicts().is_empty() { EXIT_OK } else { EXIT_FINDINGS });
+    }
+    if args.value("save-plan").is_some() {
+        let path_file_plan = args.required_path("save-plan")?;
+        plan.save(&path_file_plan)?;
+        println!("Saved {} operation(s) to {}", plan.operations.len(), path_file_plan.to_str().unwrap());
+        return Ok(EXIT_OK);
+    }
+    for note in plan.notes.iter() {
+        println!("{}", note);
+    }
+    plan.apply(conflict_handling)?.print();
+    Ok(EXIT_OK)
+}
+
+fn run_change_set(args: &Args, config: Option<&Config>, wiki: &Wiki, change_set: change::ChangeSet) -> CliResult {
+    // Write the topics a change set touches to their projects' topic folders, through run_plan()
+    // like any other command that writes files. The wiki is the one the change set was made from.
+    change_set.print_preview();
+    if !change_set.problems.is_empty() {
+        println!("\nNothing was written because of the problems above.");
+        return Ok(EXIT_FINDINGS);
+    }
+    let mut paths_project = BTreeMap::new();
+    for project_name in change_set.project_names() {
+        let path_folder = config_project(config, &project_name)?.topic_folder.clone()
+            .ok_or_else(|| CliError::Usage(format!("No topic_folder for project {:?} in the config file.", project_name)))?;
+        paths_project.insert(project_name, path_folder);
+    }
+    run_plan(args, change_set.plan_files_by_project(wiki, &paths_project)?)
+}
+
+fn conflict_handling(args: &Args) -> Result<plan::ConflictHandling, CliError> {
+    match args.value("on-conflict") {
+        Some(name) => plan::ConflictHandling::from_name(name).ok_or_else(|| CliError::Usage(format!("Unknown conflict handling {:?}.", name))),
+        None => Ok(plan::ConflictHandling::Fail),
+    }
 }
 
-fn try_load_topics() {
-    let topics = import_topics();
-    dbg!(&topics);
+fn project_files(args: &Args, config: Option<&Config>) -> Result<Vec<(String, path::PathBuf, Option<input::Encoding>)>, CliError> {
+    // A file given on the command line has its encoding detected, while a project from the
+    // config file uses the encoding set there.
+    let mut projects: Vec<(String, path::PathBuf, Option<input::Encoding>)> = vec![];
+    for project in args.values("project") {
+        match project.split_once("=") {
+            Some((project_name, file_import)) => projects.push((project_name.to_string(), path::PathBuf::from(file_import), None)),
+            None => {
+                let project_config = config_project(config, project)?;
+                projects.push((project_config.name.clone(), project_config.export_file.clone(), project_config.encoding()));
+            },
+        }
+    }
+    if projects.is_empty() {
+        if let Some(config) = config {
+            projects = config.projects.iter().map(|project| (project.name.clone(), project.export_file.clone(), project.encoding())).collect();
+        }
+    }
+    if projects.is_empty() {
+        return Err(CliError::Usage(format!("At least one --project NAME=FILE or a config file is required for {}.", args.command)));
+    }
+    for (_, path_file_import, _) in projects.iter() {
+        if !path_file_import.is_file() {
+            return Err(CliError::Io(io::Error::new(io::ErrorKind::NotFound, format!("No such file {:?}.", path_file_import))));
+        }
+    }
+    Ok(projects)
 }
 
-fn try_load_links() {
-    let mut wiki = import_topics();
+fn load_wiki(args: &Args, config: Option<&Config>) -> Result<Wiki, CliError> {
+    let mut wiki = Wiki::new();
+    for (project_name, path_file_import, encoding) in project_files(args, config)? {
+        // Projects from the config file may have a cache, but only for their own export file.
+        let project_config = config.and_then(|config| config.project(&project_name)).filter(|x| x.export_file == path_file_import);
+        match project_config {
+            Some(project_config) => wiki.append(project_config.import_topics_cached()?),
+            None => wiki.append(import::import_topics_with_encoding(&path_file_import, &project_name, encoding)?),
+        }
+    }
     import::add_links(&mut wiki);
+    Ok(wiki)
 }
 
-fn catalog_attributes() {
-    let wiki = import_topics();
+fn run_import(args: &Args, config: Option<&Config>) -> CliResult {
+    let wiki = load_wiki(args, config)?;
+    println!("{} topics, {} attribute types", wiki.topics.len(), wiki.attribute_types.len());
+    Ok(EXIT_OK)
+}
+
+fn run_lint(args: &Args, config: Option<&Config>) -> CliResult {
+    let diagnostics = if args.has_flag("stream") {
+        let mut diagnostics = vec![];
+        for (project_name, path_file_import, encoding) in project_files(args, config)? {
+            diagnostics.append(&mut lint::lint_export_file(&path_file_import, &project_name, encoding)?);
+        }
+        diagnostics
+    } else {
+        lint::lint_wiki(&load_wiki(args, config)?)
+    };
+    lint::Diagnostic::print_list(&diagnostics);
+    println!("\n{} diagnostic(s)", diagnostics.len());
+    Ok(if diagnostics.is_empty() { EXIT_OK } else { EXIT_FINDINGS })
+}
+
+fn export_format(args: &Args) -> Result<export::ExportFormat, CliError> {
+    match args.value("format") {
+        Some(name) => export::ExportFormat::from_name(name).ok_or_else(|| CliError::Usage(format!("Unknown export format {:?}.", name))),
+        None => Ok(export::ExportFormat::Text),
+    }
+}
+
+fn run_export(args: &Args, config: Option<&Config>) -> CliResult {
+    let export_format = export_format(args)?;
+    let path_dest = args.required_path("dest")?;
+    if args.has_flag("stream") {
+        return run_export_stream(args, config, &path_dest, export_format);
+    }
+    let wiki = load_wiki(args, config)?;
+    let mut plan = export::plan_export_wiki(&wiki, &path_dest, export_format);
+    if args.has_flag("attachments") {
+        let paths_folder = config.map(|config| config.projects.iter()
+            .filter_map(|project| project.topic_folder.clone().map(|x| (project.name.to_lowercase(), x)))
+            .collect())
+            .unwrap_or_default();
+        plan.append(attachments::plan_copy_attachments(&wiki, &paths_folder, &path_dest));
+    }
+    run_plan(args, plan)
+}
+
+fn run_export_stream(args: &Args, config: Option<&Config>, path_dest: &path::Path, export_format: export::ExportFormat) -> CliResult {
+    // There's no plan to print or save since that would mean holding every topic in memory.
+    if args.has_flag("dry-run") || args.value("save-plan").is_some() || args.has_flag("attachments") {
+        return Err(CliError::Usage("--stream can't be combined with --dry-run, --save-plan or --attachments.".to_string()));
+    }
+    let conflict_handling = conflict_handling(args)?;
+    let mut report = plan::ApplyReport::default();
+    for (project_name, path_file_import, encoding) in project_files(args, config)? {
+        // A first pass for the topic names, so that the file names are the same as they'd be
+        // without --stream.
+        let topic_names = stream::topic_names(&path_file_import, &project_name, encoding)?;
+        let mut names = export::ExportNames::new(export_format);
+        names.add_project(&project_name, topic_names.iter().map(|x| x.as_str()));
+        let mut topics = stream::TopicStream::open(&path_file_import, &project_name, encoding)?;
+        let text_format = topics.text_format();
+        report.append(export::export_topics(topics.by_ref(), path_dest, &names, text_format, conflict_handling)?);
+        for problem in topics.problems.iter() {
+            println!("{}: {}", path_file_import.to_str().unwrap(), problem);
+        }
+    }
+    report.print();
+    Ok(EXIT_OK)
+}
+
+fn run_report(args: &Args, config: Option<&Config>) -> CliResult {
+    let kind = args.required("kind")?;
+    let wiki = load_wiki(args, config)?;
+    match kind {
+        "links" => wiki.report_link_groups(),
+        "added-dates" => wiki.report_added_dates(),
+        "derived-added-dates" => wiki.report_derived_added_dates(),
+        "attributes" => catalog_attributes(&wiki),
+        "categories" => catalog_categories(&wiki),
+        "domains" => urls::report_domains(&wiki),
+        _ => return Err(CliError::Usage(format!("Unknown report kind {:?}.", kind))),
+    };
+    Ok(EXIT_OK)
+}
+
+fn run_query(args: &Args, config: Option<&Config>) -> CliResult {
+    let mut attr_filters = vec![];
+    for attr in args.values("attr") {
+        let (attr_name, attr_value) = attr.split_once("=")
+            .ok_or_else(|| CliError::Usage(format!("Expected NAME=VALUE for --attr but got {:?}.", attr)))?;
+        attr_filters.push((attr_name, attr_value));
+    }
+    let category = args.value("category");
+    let text = args.value("text").map(|x| x.to_lowercase());
+    let links_to = args.value("links-to");
+    let is_match = |topic: &model::Topic| {
+        if let Some(category) = category {
+            if !topic.category.as_ref().map_or(false, |x| x.eq_ignore_ascii_case(category)) {
+                return false;
+            }
+        }
+        if !attr_filters.iter().all(|(attr_name, attr_value)| topic.attributes.get(*attr_name).map_or(false, |values| values.iter().any(|x| x == attr_value))) {
+            return false;
+        }
+        if let Some(text) = &text {
+            if !topic.content.to_lowercase().contains(text) {
+                return false;
+            }
+        }
+        true
+    };
+    let mut count = 0;
+    if args.has_flag("stream") {
+        // --links-to needs the link index of the whole wiki.
+        if links_to.is_some() {
+            return Err(CliError::Usage("--stream can't be combined with --links-to.".to_string()));
+        }
+        for (project_name, path_file_import, encoding) in project_files(args, config)? {
+            for topic in stream::TopicStream::open(&path_file_import, &project_name, encoding)? {
+                let topic = topic?;
+                if is_match(&topic) {
+                    println!("{}: {}", topic.project_name, topic.name);
+                    count += 1;
+                }
+            }
+        }
+        println!("\n{} topic(s)", count);
+        return Ok(if count > 0 { EXIT_OK } else { EXIT_FINDINGS });
+    }
+    let wiki = load_wiki(args, config)?;
+    let link_index = wiki.link_index();
+    for (key, topic) in wiki.topics.iter() {
+        if !is_match(topic) {
+            continue;
+        }
+        if let Some(links_to) = links_to {
+            let is_linked = link_index.get(&Wiki::link_key(&topic.project_name, links_to)).map_or(false, |keys| keys.contains(key));
+            if !is_linked {
+                continue;
+            }
+        }
+        println!("{}: {}", topic.project_name, topic.name);
+        count += 1;
+    }
+    println!("\n{} topic(s)", count);
+    Ok(if count > 0 { EXIT_OK } else { EXIT_FINDINGS })
+}
+
+fn run_fix_file_names(args: &Args, config: Option<&Config>) -> CliResult {
+    let project_config = match args.value("project") {
+        Some(project_name) => Some(config_project(config, project_name)?),
+        None => None,
+    };
+    let path_file_full_export = path_from_args_or_config(args, "export", project_config.map(|x| x.export_file.clone()))?;
+    let path_source = path_from_args_or_config(args, "source", project_config.and_then(|x| x.topic_folder.clone()))?;
+    let path_dest = args.required_path("dest")?;
+    let encoding = project_config.and_then(|x| x.encoding());
+    run_plan(args, import::plan_fix_file_names(&path_file_full_export, &path_source, &path_dest, encoding)?)
+}
+
+fn run_copy_images(args: &Args, config: Option<&Config>) -> CliResult {
+    let project_config = match args.value("project") {
+        Some(project_name) => Some(config_project(config, project_name)?),
+        None => None,
+    };
+    // --source is a topic folder with the images in its Images folder, while the project's
+    // image_folder may be anywhere.
+    let path_image_source = match args.value("source") {
+        Some(_) => import::get_image_path(&args.required_path("source")?),
+        None => path_from_args_or_config(args, "source", project_config.and_then(|x| x.image_folder()))?,
+    };
+    let path_dest = args.required_path("dest")?;
+    run_plan(args, import::plan_copy_image_files(&path_image_source, &path_dest)?)
+}
+
+fn run_audible(args: &Args, config: Option<&Config>) -> CliResult {
+    let account_name = args.required("account")?;
+    let account_config = config.and_then(|config| config.audible_account(account_name));
+    let path_file_books = path_from_args_or_config(args, "books", account_config.map(|x| x.books_file.clone()))?;
+    let path_file_purchase_dates = path_from_args_or_config(args, "purchases", account_config.map(|x| x.purchases_file.clone()))?;
+    let path_gen = path_from_args_or_config(args, "dest", config.and_then(|x| x.audible_gen_folder.clone()))?;
+    // A books or purchases file that can't be read or doesn't parse is a problem with the
+    // arguments rather than a failure partway through a run.
+    let books = audible::import_audible_books(&path_file_books, &path_file_purchase_dates, account_name)
+        .map_err(|e| CliError::Usage(e.to_string()))?;
+    run_plan(args, gen::plan_book_text_files(&path_gen, books))
+}
+
+fn run_bookmarks(args: &Args, config: Option<&Config>) -> CliResult {
+    let path_file = path_from_args_or_config(args, "file", config.and_then(|x| x.bookmarks_file.clone()))?;
+    let format = match args.value("format") {
+        Some(name) => Some(bookmarks::BookmarkFormat::from_name(name).ok_or_else(|| CliError::Usage(format!("Unknown bookmark format {:?}.", name)))?),
+        None => None,
+    };
+    let root = bookmarks::import_bookmarks(&path_file, format)?;
+    if args.has_flag("compare") {
+        let wiki = load_wiki(args, config)?;
+        let comparison = wiki.compare_bookmarks(&root);
+        comparison.print();
+        if let Some(topic_name) = args.value("staging") {
+            let project_name = parse::before(args.required("project")?, "=");
+            let change_set = wiki.plan_staging_topic(project_name, topic_name, args.value("category").unwrap_or(bookmarks::DEFAULT_CATEGORY), &comparison);
+            return run_change_set(args, config, &wiki, change_set);
+        }
+        return Ok(EXIT_OK);
+    }
+    if args.has_flag("topics") {
+        let project_name = parse::before(args.required("project")?, "=");
+        let wiki = load_wiki(args, config)?;
+        let change_set = wiki.plan_bookmark_topics(project_name, &root, args.value("category").unwrap_or(bookmarks::DEFAULT_CATEGORY));
+        return run_change_set(args, config, &wiki, change_set);
+    }
+    println!("{}", gen::gen_page_from_bookmarks(&root));
+    Ok(EXIT_OK)
+}
+
+fn run_apply_plan(args: &Args) -> CliResult {
+    // The plan was saved with --save-plan, possibly on another day, so conflicts are checked
+    // against the files as they are now.
+    let plan = plan::ChangePlan::load(&args.required_path("plan")?)?;
+    run_plan(args, plan)
+}
+
+fn run_compare(args: &Args, config: Option<&Config>) -> CliResult {
+    let project_name = args.required("project")?;
+    let project_config = config.and_then(|config| config.project(project_name));
+    let path_file_import = path_from_args_or_config(args, "export", project_config.map(|x| x.export_file.clone()))?;
+    let path_folder = path_from_args_or_config(args, "folder", project_config.and_then(|x| x.topic_folder.clone()))?;
+    let encoding = project_config.and_then(|x| x.encoding());
+    let mut wiki_file = Wiki::new();
+    wiki_file.append(import::import_topics_with_encoding(&path_file_import, project_name, encoding)?);
+    let mut wiki_folder = Wiki::new();
+    wiki_folder.append(import::import_topics_from_folder(&path_folder, project_name, encoding, Some(&path_file_import))?);
+    let comparison = wiki_file.compare(&wiki_folder);
+    comparison.print("export file", "topic folder");
+    println!("\n{} topics in the export file, {} in the topic folder", wiki_file.topics.len(), wiki_folder.topics.len());
+    Ok(if comparison.is_empty() { EXIT_OK } else { EXIT_FINDINGS })
+}
+
+fn run_reconcile(args: &Args, config: Option<&Config>) -> CliResult {
+    let project_name = args.required("project")?;
+    let project_config = config.and_then(|config| config.project(project_name));
+    let path_file_full_export = path_from_args_or_config(args, "export", project_config.map(|x| x.export_file.clone()))?;
+    let path_folder = path_from_args_or_config(args, "folder", project_config.and_then(|x| x.topic_folder.clone()))?;
+    let encoding = project_config.and_then(|x| x.encoding());
+    let report = reconcile::reconcile(&path_file_full_export, &path_folder, encoding)?;
+    report.print();
+    Ok(if report.is_empty() { EXIT_OK } else { EXIT_FINDINGS })
+}
+
+fn run_images(args: &Args, config: Option<&Config>) -> CliResult {
+    let project_name = parse::before(args.required("project")?, "=");
+    let project_config = config.and_then(|config| config.project(project_name));
+    let path_images = path_from_args_or_config(args, "images", project_config.and_then(|x| x.image_folder()))?;
+    let wiki = load_wiki(args, config)?;
+    let audit = images::audit_images(&wiki, &path_images)?;
+    audit.print();
+    let delete_orphans = args.has_flag("delete-orphans");
+    let fix_case = args.has_flag("fix-case");
+    if delete_orphans || fix_case {
+        return run_plan(args, audit.plan_cleanup(delete_orphans, fix_case));
+    }
+    Ok(if audit.is_empty() { EXIT_OK } else { EXIT_FINDINGS })
+}
+
+fn run_attachments(args: &Args, config: Option<&Config>) -> CliResult {
+    let project_name = parse::before(args.required("project")?, "=");
+    let project_config = config.and_then(|config| config.project(project_name));
+    let path_folder = path_from_args_or_config(args, "folder", project_config.and_then(|x| x.topic_folder.clone()))?;
+    let wiki = load_wiki(args, config)?;
+    let audit = attachments::audit_attachments(&wiki, &path_folder)?;
+    audit.print();
+    Ok(if audit.is_empty() { EXIT_OK } else { EXIT_FINDINGS })
+}
+
+fn run_link_rot(args: &Args, config: Option<&Config>) -> CliResult {
+    let path_snapshot = path_from_args_or_config(args, "snapshot", config.and_then(|x| x.link_snapshot.clone()))?;
+    let snapshot = link_rot::LinkSnapshot::load(&path_snapshot, args.value("archive-prefix"))?;
+    let wiki = load_wiki(args, config)?;
+    let report = link_rot::check_links(&wiki, &snapshot);
+    report.print();
+    if args.has_flag("rewrite") {
+        // The rewritten topics are saved in the encoding and line endings their projects were
+        // loaded with, through the wiki passed to run_change_set().
+        let change_set = report.plan_rewrite(&wiki, args.has_flag("update-redirects"));
+        return run_change_set(args, config, &wiki, change_set);
+    }
+    Ok(if report.is_empty() { EXIT_OK } else { EXIT_FINDINGS })
+}
+
+fn run_watch(args: &Args, config: Option<&Config>) -> CliResult {
+    let project_name = args.required("project")?;
+    let project_config = config.and_then(|config| config.project(project_name));
+    let path_folder = path_from_args_or_config(args, "folder", project_config.and_then(|x| x.topic_folder.clone()))?;
+    let encoding = project_config.and_then(|x| x.encoding());
+    let interval = match args.value("interval") {
+        Some(seconds) => seconds.parse::<u64>().map_err(|_| CliError::Usage(format!("Expected a number of seconds for --interval but got {:?}.", seconds)))?,
+        None => 2,
+    };
+    let steps = watch::WatchSteps {
+        lint: !args.has_flag("no-lint"),
+        export: match args.value("dest") {
+            Some(_) => Some((args.required_path("dest")?, export_format(args)?)),
+            None => None,
+        },
+    };
+    // The topic names come from matching the files against the export file when there is one,
+    // as for compare.
+    let path_file_full_export = match args.value("export") {
+        Some(_) => Some(args.required_path("export")?),
+        None => project_config.map(|x| x.export_file.clone()).filter(|x| x.is_file()),
+    };
+    let path_cache_file = project_config.and_then(|x| x.folder_cache_file.clone());
+    let mut folder_watch = watch::FolderWatch::new(&path_folder, project_name, encoding, path_file_full_export.as_deref(), path_cache_file.as_deref())?;
+    println!("Watching {}", path_folder.to_str().unwrap());
+    folder_watch.watch(&steps, std::time::Duration::from_secs(interval))?;
+    Ok(EXIT_OK)
+}
+
+fn catalog_attributes(wiki: &Wiki) {
     let mut attributes: BTreeMap<String, AttributeForCatalog> = BTreeMap::new();
     for topic in wiki.topics.values() {
     // for topic in topics.iter().filter(|x| x.category.eq(&Some(CATEGORY_BOOKS.to_string()))) {
         for (attr_name, attr_values) in topic.attributes.iter() {
-            let mut attribute = attributes.entry(attr_name.to_string()).or_insert_with(|| { AttributeForCatalog::new(attr_name) } );
+            let attribute = attributes.entry(attr_name.to_string()).or_insert_with(|| { AttributeForCatalog::new(attr_name) } );
             attribute.count += 1;
             attribute.max_values = std::cmp::max(attribute.max_values, attr_values.len());
             for one_value in attr_values.iter() {
@@ -105,7 +667,12 @@ fn catalog_attributes() {
             }
         }
     }
-    dbg!(&attributes);
+    for attribute in attributes.values() {
+        println!("\n{}: {} topics, up to {} values per topic", attribute.name, attribute.count, attribute.max_values);
+        for (value, count) in attribute.values.iter() {
+            println!("    {}: {}", value, count);
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -127,13 +694,12 @@ impl AttributeForCatalog {
     }
 
     pub fn register_value(&mut self, value: &str) {
-        let mut entry = self.values.entry(value.to_string()).or_insert_with(|| { 0 } );
+        let entry = self.values.entry(value.to_string()).or_insert_with(|| { 0 } );
         *entry += 1;
     }
 }
 
-fn catalog_categories() {
-    let wiki = import_topics();
+fn catalog_categories(wiki: &Wiki) {
     let mut g = Grouper::new("Categories");
     for topic in wiki.topics.values() {
         if let Some(category) = &topic.category {
@@ -142,4 +708,3 @@ fn catalog_categories() {
     }
     g.list_by_key();
 }
-
@@ -0,0 +1,223 @@
+use std::{fs, io, path};
+use std::collections::{BTreeMap, BTreeSet};
+use util::parse;
+use super::cache;
+use super::file_audit::{self, FileAudit};
+use super::model::Wiki;
+use super::paths;
+use super::plan::{ChangePlan, FileOperation};
+
+// Files other than images that topics link to with [[$FILE:Attachments\Report.pdf|Label]], such
+// as PDFs and spreadsheets. A relative reference is relative to the project's topic folder, the
+// same as Images\, so the attachments can be checked and copied along with the topics. An
+// absolute one such as C:\Documents\Report.pdf, or one that leaves the topic folder with "..",
+// can't be, so it's only reported.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttachmentReference {
+    // As written in the topic.
+    pub reference: String,
+    pub label: Option<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AttachmentAudit {
+    // The folders that the references point into, such as Attachments, which are checked for
+    // files that nothing refers to.
+    pub folders: Vec<path::PathBuf>,
+    // Relative to the topic folder.
+    pub audit: FileAudit,
+}
+
+impl AttachmentReference {
+    pub fn parse(entry: &str) -> Self {
+        // The text between "[[$FILE:" and "]]".
+        let (reference, label) = match entry.split_once("|") {
+            Some((reference, label)) => (reference, Some(label.trim().to_string()).filter(|x| !x.is_empty())),
+            None => (entry, None),
+        };
+        Self { reference: reference.trim().to_string(), label }
+    }
+
+    pub fn is_absolute(&self) -> bool {
+        paths::is_absolute_reference(&self.reference)
+    }
+
+    pub fn is_inside_folder(&self) -> bool {
+        paths::is_inside_folder(&self.reference)
+    }
+
+    pub fn relative_path(&self) -> Option<path::PathBuf> {
+        // None for anything outside the topic folder, so that copying it can't write outside the
+        // destination folder either.
+        if self.is_inside_folder() { Some(paths::reference_to_relative_path(&self.reference)) } else { None }
+    }
+
+    pub fn label(&self) -> String {
+        // Without a label ConnectedText shows the file name.
+        match &self.label {
+            Some(label) => label.clone(),
+            None => paths::reference_parts(&self.reference).last().map(|x| x.to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+pub fn attachment_references(content: &str) -> Vec<AttachmentReference> {
+    parse::delimited_entries(content, "[[$FILE:", "]]").iter()
+        .map(|x| AttachmentReference::parse(x))
+        .collect()
+}
+
+pub fn audit_attachments(wiki: &Wiki, path_folder: &path::Path) -> io::Result<AttachmentAudit> {
+    // path_folder is the project's topic folder.
+    assert!(path_folder.is_absolute());
+    let mut audit = FileAudit::new(path_folder);
+    let label = file_audit::topic_label;
+
+    let mut referenced: BTreeSet<path::PathBuf> = BTreeSet::new();
+    let mut folders: BTreeSet<path::PathBuf> = BTreeSet::new();
+    for topic in wiki.topics.values() {
+        let mut count = 0;
+        let mut size = 0;
+        for attachment in attachment_references(&topic.content) {
+            if !attachment.is_inside_folder() {
+                audit.outside_folder.push((label(topic), attachment.reference));
+                continue;
+            }
+            // Anything in a subfolder means that folder holds attachments. Files directly in the
+            // topic folder are only checked, since that's where the topic files are, and the
+            // Images folder has its own audit.
+            let parts = paths::reference_parts(&attachment.reference);
+            if parts.len() > 1 && !parts[0].eq_ignore_ascii_case(paths::FOLDER_IMAGES) {
+                folders.insert(paths::resolve_ci_or_join(path_folder, parts[0]));
+            }
+            match paths::resolve_ci(path_folder, &attachment.reference).filter(|x| x.is_file()) {
+                Some(path_file) => {
+                    let relative = paths::normalize_reference(path_file.strip_prefix(path_folder).unwrap().to_str().unwrap());
+                    if relative != paths::normalize_reference(&attachment.reference) {
+                        audit.case_mismatches.push((label(topic), attachment.reference.clone(), path_file.clone()));
+                    }
+                    count += 1;
+                    size += fs::metadata(&path_file)?.len();
+                    referenced.insert(path_file);
+                },
+                None => audit.missing.push((label(topic), attachment.reference)),
+            }
+        }
+        if count > 0 {
+            audit.size_per_topic.insert(label(topic), (count, size));
+        }
+    }
+
+    let mut files = vec![];
+    for path_folder_attachments in folders.iter().filter(|x| x.is_dir()) {
+        for path_file in paths::files_in_folder(path_folder_attachments)? {
+            if !referenced.contains(&path_file) {
+                audit.orphans.push(path_file.clone());
+            }
+            let bytes = fs::read(&path_file)?;
+            files.push((bytes.len() as u64, cache::hash_bytes(&bytes), path_file));
+        }
+    }
+    audit.set_duplicates(files.into_iter());
+    Ok(AttachmentAudit { folders: folders.into_iter().collect(), audit })
+}
+
+impl AttachmentAudit {
+    pub fn is_empty(&self) -> bool {
+        self.audit.is_empty()
+    }
+
+    pub fn print(&self) {
+        self.audit.print("attachment", "the topic folder");
+        println!("\n{}", self.audit.summary("the topic folder"));
+    }
+}
+
+pub fn plan_copy_attachments(wiki: &Wiki, paths_folder: &BTreeMap<String, path::PathBuf>, path_dest: &path::Path) -> ChangePlan {
+    // Copy the attachments of every project whose topic folder is in paths_folder, keyed by the
+    // lowercase project name, into the project's folder in an export. They keep their path
+    // relative to the topic folder so that the links in the exported topics work.
+    assert!(path_dest.is_absolute());
+    let mut plan = ChangePlan::new("Copy attachments");
+    let mut paths_dest: BTreeSet<path::PathBuf> = BTreeSet::new();
+    let mut paths_folder_dest: BTreeSet<path::PathBuf> = BTreeSet::new();
+    for topic in wiki.topics.values() {
+        let path_folder = match paths_folder.get(&topic.project_name.to_lowercase()) {
+            Some(path_folder) => path_folder,
+            None => continue,
+        };
+        for attachment in attachment_references(&topic.content) {
+            let path_relative = match attachment.relative_path() {
+                Some(path_relative) => path_relative,
+                None => continue,
+            };
+            let path_file_source = match paths::resolve_ci(path_folder, &attachment.reference).filter(|x| x.is_file()) {
+                Some(path_file_source) => path_file_source,
+                None => {
+                    plan.add_note(&format!("No attachment \"{}\" for {}: {}.", attachment.reference, topic.project_name, topic.name));
+                    continue;
+                },
+            };
+            let path_file_dest = path_dest.join(&topic.project_name).join(&path_relative);
+            if !paths_dest.insert(path_file_dest.clone()) {
+                continue;
+            }
+            let path_folder_dest = path_file_dest.parent().unwrap().to_path_buf();
+            if !path_folder_dest.is_dir() && paths_folder_dest.insert(path_folder_dest.clone()) {
+                plan.push(FileOperation::CreateDir { path: path_folder_dest });
+            }
+            plan.push(FileOperation::Copy { path_source: path_file_source, path_dest: path_file_dest });
+        }
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::model::Topic;
+
+    #[test]
+    fn outside_folder() {
+        for reference in ["Attachments\\Report.pdf", "Report.pdf", ".\\Attachments/Report.pdf", "Attachments\\..dots.pdf"].iter() {
+            let attachment = AttachmentReference::parse(reference);
+            assert!(attachment.is_inside_folder(), "{}", reference);
+            assert!(attachment.relative_path().is_some());
+        }
+        for reference in ["..\\Report.pdf", "Attachments\\..\\..\\Report.pdf", "Attachments/../Report.pdf", "C:\\Documents\\Report.pdf",
+                "c:Report.pdf", "\\\\server\\share\\Report.pdf", "/home/me/Report.pdf"].iter() {
+            let attachment = AttachmentReference::parse(reference);
+            assert!(!attachment.is_inside_folder(), "{}", reference);
+            assert_eq!(attachment.relative_path(), None);
+        }
+    }
+
+    #[test]
+    fn audit_and_copy() {
+        let path_folder = std::env::temp_dir().join(format!("connectedtext-attachments-{}", std::process::id()));
+        fs::create_dir_all(path_folder.join("Attachments")).unwrap();
+        fs::write(path_folder.join("Attachments").join("Report.pdf"), "report").unwrap();
+        fs::write(path_folder.join("Attachments").join("Copy.pdf"), "report").unwrap();
+        let mut wiki = Wiki::new();
+        wiki.add_topic(Topic::new("P", "One", "[[$FILE:Attachments\\Report.pdf|The report]] [[$FILE:..\\Secret.pdf]] [[$FILE:C:\\Secret.pdf]]\n"));
+        let audit = audit_attachments(&wiki, &path_folder).unwrap().audit;
+        assert_eq!(audit.outside_folder, vec![("P: One".to_string(), "..\\Secret.pdf".to_string()), ("P: One".to_string(), "C:\\Secret.pdf".to_string())]);
+        assert!(audit.missing.is_empty());
+        assert_eq!(audit.orphans.iter().map(|x| audit.relative(x)).collect::<Vec<_>>(), vec!["Attachments/Copy.pdf"]);
+        assert_eq!(audit.duplicates.len(), 1);
+        assert_eq!(audit.size_per_topic.get("P: One"), Some(&(1, 6)));
+
+        // Only the attachment inside the topic folder is copied.
+        let mut paths_folder = BTreeMap::new();
+        paths_folder.insert("p".to_string(), path_folder.clone());
+        let path_dest = path_folder.join("Export");
+        let plan = plan_copy_attachments(&wiki, &paths_folder, &path_dest);
+        let copied = plan.operations.iter().filter_map(|operation| match operation {
+            FileOperation::Copy { path_dest, .. } => Some(path_dest.clone()),
+            _ => None,
+        }).collect::<Vec<_>>();
+        assert_eq!(copied, vec![path_dest.join("P").join("Attachments").join("Report.pdf")]);
+        fs::remove_dir_all(&path_folder).unwrap();
+    }
+}
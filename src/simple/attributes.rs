@@ -0,0 +1,345 @@
+use std::collections::BTreeMap;
+use chrono::NaiveDate;
+use crate::*;
+use super::change::{Change, ChangeSet};
+use super::model::Wiki;
+use super::writer;
+
+// Bulk changes to attributes across every topic in the wiki. Each operation edits the attribute
+// table rows in place through writer::edit_attribute_rows() and returns a change set to be
+// previewed and then applied.
+
+// What retype_attribute() turns the values of an attribute into, written the way Topic reads them
+// back: whole numbers, dates as YYYYMMDD, and Yes or No.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AttributeValueType {
+    Text,
+    Int,
+    Date,
+    Bool,
+    TopicReference,
+}
+
+impl AttributeValueType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_ref() {
+            "text" | "string" => Some(AttributeValueType::Text),
+            "int" | "number" => Some(AttributeValueType::Int),
+            "date" => Some(AttributeValueType::Date),
+            "bool" | "yes/no" => Some(AttributeValueType::Bool),
+            "topic" | "reference" => Some(AttributeValueType::TopicReference),
+            _ => None,
+        }
+    }
+
+    pub fn convert(&self, value: &str) -> Option<String> {
+        // The value written as this type, or None if it can't be read as one.
+        let value = value.trim();
+        match self {
+            AttributeValueType::Text | AttributeValueType::TopicReference => Some(value.to_string()),
+            AttributeValueType::Int => value.replace(",", "").replace("_", "").parse::<i64>().ok().map(|x| x.to_string()),
+            AttributeValueType::Date => ["%Y%m%d", "%Y-%m-%d", "%Y/%m/%d", "%Y.%m.%d"].iter()
+                .find_map(|format| NaiveDate::parse_from_str(value, format).ok())
+                .map(|date| date.format("%Y%m%d").to_string()),
+            AttributeValueType::Bool => match value.to_lowercase().as_ref() {
+                "yes" | "y" | "true" | "1" => Some("Yes".to_string()),
+                "no" | "n" | "false" | "0" => Some("No".to_string()),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl Wiki {
+    pub fn rename_attribute(&self, old_name: &str, new_name: &str) -> ChangeSet {
+        // If a topic already has the new attribute, the values are combined into one row.
+        self.edit_attributes(&format!("Rename attribute {:?} to {:?}", old_name, new_name), |attr_name, values| {
+            if attr_name == old_name {
+                vec![(new_name.to_string(), values.to_vec())]
+            } else {
+                vec![(attr_name.to_string(), values.to_vec())]
+            }
+        })
+    }
+
+    pub fn merge_attributes(&self, attr_names: &[&str], new_name: &str) -> ChangeSet {
+        self.edit_attributes(&format!("Merge attributes {:?} into {:?}", attr_names, new_name), |attr_name, values| {
+            if attr_names.contains(&attr_name) {
+                vec![(new_name.to_string(), values.to_vec())]
+            } else {
+                vec![(attr_name.to_string(), values.to_vec())]
+            }
+        })
+    }
+
+    pub fn map_attribute_values(&self, attr_name: &str, value_map: &BTreeMap<String, String>) -> ChangeSet {
+        // For example, map "Audible personal" to "Audible (personal)". Values not in the map are
+        // left alone, and values that end up the same after mapping are only kept once.
+        self.edit_attributes(&format!("Map values of attribute {:?}", attr_name), |one_attr_name, values| {
+            if one_attr_name != attr_name {
+                return vec![(one_attr_name.to_string(), values.to_vec())];
+            }
+            let mut new_values: Vec<String> = vec![];
+            for value in values.iter() {
+                let new_value = value_map.get(value).unwrap_or(value);
+                if !new_values.contains(new_value) {
+                    new_values.push(new_value.to_string());
+                }
+            }
+            vec![(one_attr_name.to_string(), new_values)]
+        })
+    }
+
+    pub fn split_attribute<F>(&self, attr_name: &str, first_name: &str, second_name: &str, f: F) -> ChangeSet
+        where F: Fn(&str) -> (Option<String>, Option<String>)
+    {
+        // The closure splits each value into its parts for the two new attributes. For example,
+        // a Location of "Audible (personal)" could become a Location of "Audible" and an Account of
+        // "personal". A new attribute that gets no values is left out unless both are empty.
+        self.edit_attributes(&format!("Split attribute {:?} into {:?} and {:?}", attr_name, first_name, second_name), |one_attr_name, values| {
+            if one_attr_name != attr_name {
+                return vec![(one_attr_name.to_string(), values.to_vec())];
+            }
+            let mut first_values: Vec<String> = vec![];
+            let mut second_values: Vec<String> = vec![];
+            for value in values.iter() {
+                let (first_value, second_value) = f(value);
+                if let Some(first_value) = first_value {
+                    if !first_values.contains(&first_value) {
+                        first_values.push(first_value);
+                    }
+                }
+                if let Some(second_value) = second_value {
+                    if !second_values.contains(&second_value) {
+                        second_values.push(second_value);
+                    }
+                }
+            }
+            let mut rows = vec![];
+            if !first_values.is_empty() || second_values.is_empty() {
+                rows.push((first_name.to_string(), first_values));
+            }
+            if !second_values.is_empty() {
+                rows.push((second_name.to_string(), second_values));
+            }
+            rows
+        })
+    }
+
+    pub fn convert_attribute_to_topic_references(&self, attr_name: &str, category: Option<&str>) -> ChangeSet {
+        // ConnectedText treats the value in [[Author:=Jason Fried]] as a link to the topic "Jason
+        // Fried". To make an attribute a proper reference, each value is changed to match the case
+        // of the existing topic it names, and a stub topic is created for any value that doesn't
+        // name a topic in the same project yet.
+        let mut change_set = ChangeSet::new(&format!("Convert attribute {:?} to topic references", attr_name));
+        let mut new_topics: BTreeMap<String, (String, String)> = BTreeMap::new();
+        for topic in self.topics.values() {
+            let new_content = writer::edit_attribute_rows(&topic.content, |one_attr_name, values| {
+                if one_attr_name != attr_name {
+                    return vec![(one_attr_name.to_string(), values.to_vec())];
+                }
+                let mut new_values = vec![];
+                for value in values.iter() {
+                    match self.topics.get(&Wiki::link_key(&topic.project_name, value)) {
+                        Some(ref_topic) => new_values.push(ref_topic.name.clone()),
+                        None => {
+                            new_topics.entry(Wiki::link_key(&topic.project_name, value))
+                                .or_insert_with(|| (topic.project_name.clone(), value.trim().to_string()));
+                            new_values.push(value.to_string());
+                        },
+                    }
+                }
+                vec![(one_attr_name.to_string(), new_values)]
+            });
+            if new_content != topic.content {
+                change_set.push(Change::EditContent {
+                    project_name: topic.project_name.clone(),
+                    topic_name: topic.name.clone(),
+                    old_content: topic.content.clone(),
+                    new_content,
+                });
+            }
+        }
+        for (_, (project_name, topic_name)) in new_topics.into_iter() {
+            let content = match category {
+                Some(category) => format!("{}\n", gen::gen_category(category)),
+                None => "".to_string(),
+            };
+            change_set.push(Change::CreateTopic {
+                project_name,
+                topic_name,
+                content,
+            });
+        }
+        change_set
+    }
+
+    pub fn retype_attribute(&self, attr_name: &str, value_type: AttributeValueType) -> ChangeSet {
+        // Rewrite every value of the attribute in the form the new type expects, so that "2,000"
+        // becomes the Int 2000 and "2020-10-18" the Date 20201018. A value that can't be read as
+        // the new type is left as it is with a warning. Topic references also need their stub
+        // topics, which convert_attribute_to_topic_references() takes care of.
+        if value_type == AttributeValueType::TopicReference {
+            return self.convert_attribute_to_topic_references(attr_name, None);
+        }
+        let mut change_set = ChangeSet::new(&format!("Retype attribute {:?} as {:?}", attr_name, value_type));
+        for topic in self.topics.values() {
+            let mut warnings = vec![];
+            let new_content = writer::edit_attribute_rows(&topic.content, |one_attr_name, values| {
+                if one_attr_name != attr_name {
+                    return vec![(one_attr_name.to_string(), values.to_vec())];
+                }
+                let mut new_values: Vec<String> = vec![];
+                for value in values.iter() {
+                    let new_value = value_type.convert(value).unwrap_or_else(|| {
+                        warnings.push(format!("{}: {}: {:?} isn't a {:?}.", topic.project_name, topic.name, value, value_type));
+                        value.to_string()
+                    });
+                    if !new_values.contains(&new_value) {
+                        new_values.push(new_value);
+                    }
+                }
+                vec![(one_attr_name.to_string(), new_values)]
+            });
+            for warning in warnings.iter() {
+                change_set.add_warning(warning);
+            }
+            if new_content != topic.content {
+                change_set.push(Change::EditContent {
+                    project_name: topic.project_name.clone(),
+                    topic_name: topic.name.clone(),
+                    old_content: topic.content.clone(),
+                    new_content,
+                });
+            }
+        }
+        change_set
+    }
+
+    fn edit_attributes<F>(&self, label: &str, mut f: F) -> ChangeSet
+        where F: FnMut(&str, &[String]) -> Vec<(String, Vec<String>)>
+    {
+        let mut change_set = ChangeSet::new(label);
+        for topic in self.topics.values() {
+            let new_content = writer::edit_attribute_rows(&topic.content, &mut f);
+            if new_content != topic.content {
+                change_set.push(Change::EditContent {
+                    project_name: topic.project_name.clone(),
+                    topic_name: topic.name.clone(),
+                    old_content: topic.content.clone(),
+                    new_content,
+                });
+            }
+        }
+        change_set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::model::Topic;
+
+    fn wiki() -> Wiki {
+        let mut wiki = Wiki::new();
+        wiki.add_topic(Topic::new("P", "One", "{|\n||Author||[[Author:=A]]||\n||Authors||[[Authors:=B]], [[Authors:=A]]||\n||Location||[[Location:=Audible personal]]||\n|}\nText\n"));
+        wiki.add_topic(Topic::new("P", "Two", "{|\n||Location||[[Location:=Audible (work)]], [[Location:=Shelf]]||\n||Pages||[[Pages:=1,200]]||\n||Read||[[Read:=y]]||\n||Added||[[Added:=2020-10-18]]||\n|}\n"));
+        wiki.add_topic(Topic::new("P", "Jane Doe", "Text\n"));
+        wiki.add_topic(Topic::new("Q", "Three", "{|\n||Author||[[Author:=jane doe]], [[Author:=John Roe]], [[Author:=JOHN ROE]]||\n||Pages||[[Pages:=many]]||\n|}\n"));
+        wiki
+    }
+
+    fn content<'a>(change_set: &'a ChangeSet, topic_name: &str) -> Option<&'a str> {
+        change_set.changes.iter().find_map(|change| match change {
+            Change::EditContent { topic_name: edited, new_content, .. } if edited == topic_name => Some(new_content.as_str()),
+            Change::CreateTopic { topic_name: created, content, .. } if created == topic_name => Some(content.as_str()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn rename() {
+        let change_set = wiki().rename_attribute("Authors", "Author");
+        assert_eq!(change_set.changes.len(), 1);
+        // Combined with the Author row that's already there.
+        assert_eq!(content(&change_set, "One"), Some("{|\n||Author||[[Author:=A]], [[Author:=B]]||\n||Location||[[Location:=Audible personal]]||\n|}\nText\n"));
+        assert!(wiki().rename_attribute("Missing", "Other").is_empty());
+    }
+
+    #[test]
+    fn merge() {
+        let change_set = wiki().merge_attributes(&["Author", "Authors"], "Writer");
+        assert_eq!(content(&change_set, "One"), Some("{|\n||Writer||[[Writer:=A]], [[Writer:=B]]||\n||Location||[[Location:=Audible personal]]||\n|}\nText\n"));
+        assert_eq!(content(&change_set, "Three"), Some("{|\n||Writer||[[Writer:=jane doe]], [[Writer:=John Roe]], [[Writer:=JOHN ROE]]||\n||Pages||[[Pages:=many]]||\n|}\n"));
+    }
+
+    #[test]
+    fn map_values() {
+        let mut value_map = BTreeMap::new();
+        value_map.insert("Audible personal".to_string(), "Audible (personal)".to_string());
+        value_map.insert("Shelf".to_string(), "Audible (work)".to_string());
+        let change_set = wiki().map_attribute_values("Location", &value_map);
+        assert_eq!(content(&change_set, "One"), Some("{|\n||Author||[[Author:=A]]||\n||Authors||[[Authors:=B]], [[Authors:=A]]||\n||Location||[[Location:=Audible (personal)]]||\n|}\nText\n"));
+        // Values that map to the same value are only kept once.
+        assert_eq!(content(&change_set, "Two"), Some("{|\n||Location||[[Location:=Audible (work)]]||\n||Pages||[[Pages:=1,200]]||\n||Read||[[Read:=y]]||\n||Added||[[Added:=2020-10-18]]||\n|}\n"));
+    }
+
+    #[test]
+    fn split() {
+        let change_set = wiki().split_attribute("Location", "Location", "Account", |value| {
+            let value = value.replace("(", "").replace(")", "");
+            match value.split_once(" ") {
+                Some((location, account)) => (Some(location.to_string()), Some(account.to_string())),
+                None => (Some(value.to_string()), None),
+            }
+        });
+        assert_eq!(content(&change_set, "Two"), Some("{|\n||Location||[[Location:=Audible]], [[Location:=Shelf]]||\n||Account||[[Account:=work]]||\n||Pages||[[Pages:=1,200]]||\n||Read||[[Read:=y]]||\n||Added||[[Added:=2020-10-18]]||\n|}\n"));
+        // An empty first attribute keeps its placeholder.
+        let change_set = wiki().split_attribute("Read", "Read", "Other", |_| (None, None));
+        assert_eq!(content(&change_set, "Two"), Some("{|\n||Location||[[Location:=Audible (work)]], [[Location:=Shelf]]||\n||Pages||[[Pages:=1,200]]||\n||Read||[[Read:=***]]||\n||Added||[[Added:=2020-10-18]]||\n|}\n"));
+    }
+
+    #[test]
+    fn convert_to_topic_references() {
+        let change_set = wiki().convert_attribute_to_topic_references("Author", Some("People"));
+        // "jane doe" is in project Q, where there's no such topic, so it and John Roe get stubs
+        // there, with one for both spellings of John Roe.
+        assert_eq!(content(&change_set, "Three"), None);
+        let created = change_set.changes.iter().filter_map(|change| match change {
+            Change::CreateTopic { project_name, topic_name, content } => Some((project_name.as_str(), topic_name.as_str(), content.as_str())),
+            _ => None,
+        }).collect::<Vec<_>>();
+        assert_eq!(created, vec![("P", "A", "[[$CATEGORY:People]]\n"), ("Q", "jane doe", "[[$CATEGORY:People]]\n"), ("Q", "John Roe", "[[$CATEGORY:People]]\n")]);
+
+        let mut wiki = wiki();
+        wiki.add_topic(Topic::new("Q", "Jane Doe", "Text\n"));
+        let change_set = wiki.convert_attribute_to_topic_references("Author", None);
+        assert_eq!(content(&change_set, "Three"), Some("{|\n||Author||[[Author:=Jane Doe]], [[Author:=John Roe]], [[Author:=JOHN ROE]]||\n||Pages||[[Pages:=many]]||\n|}\n"));
+    }
+
+    #[test]
+    fn retype() {
+        let change_set = wiki().retype_attribute("Pages", AttributeValueType::Int);
+        assert_eq!(content(&change_set, "Two"), Some("{|\n||Location||[[Location:=Audible (work)]], [[Location:=Shelf]]||\n||Pages||[[Pages:=1200]]||\n||Read||[[Read:=y]]||\n||Added||[[Added:=2020-10-18]]||\n|}\n"));
+        assert_eq!(content(&change_set, "Three"), None);
+        assert_eq!(change_set.warnings, vec!["Q: Three: \"many\" isn't a Int."]);
+
+        let change_set = wiki().retype_attribute("Added", AttributeValueType::Date);
+        assert!(content(&change_set, "Two").unwrap().contains("||Added||[[Added:=20201018]]||"));
+        let change_set = wiki().retype_attribute("Read", AttributeValueType::Bool);
+        assert!(content(&change_set, "Two").unwrap().contains("||Read||[[Read:=Yes]]||"));
+        assert_eq!(wiki().retype_attribute("Author", AttributeValueType::TopicReference).changes.len(), 3);
+    }
+
+    #[test]
+    fn convert_values() {
+        assert_eq!(AttributeValueType::Int.convert(" -1_000 "), Some("-1000".to_string()));
+        assert_eq!(AttributeValueType::Int.convert("1.5"), None);
+        assert_eq!(AttributeValueType::Date.convert("2020/1/2"), Some("20200102".to_string()));
+        assert_eq!(AttributeValueType::Date.convert("20201018"), Some("20201018".to_string()));
+        assert_eq!(AttributeValueType::Date.convert("2020-02-30"), None);
+        assert_eq!(AttributeValueType::Bool.convert("FALSE"), Some("No".to_string()));
+        assert_eq!(AttributeValueType::Bool.convert("maybe"), None);
+        assert_eq!(AttributeValueType::from_name("Yes/No"), Some(AttributeValueType::Bool));
+    }
+}
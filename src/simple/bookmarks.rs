@@ -0,0 +1,836 @@
+use std::{fs, io, path};
+use std::collections::{BTreeMap, BTreeSet};
+use chrono::{NaiveDate, NaiveDateTime};
+use serde_json::Value;
+use crate::gen;
+use super::change::{Change, ChangeSet};
+use super::dates;
+use super::import;
+use super::model::{Link, Wiki};
+use super::urls;
+use super::writer;
+
+// Reading bookmarks exported from a browser into one tree of folders, whatever the format:
+//   - Chrome's Bookmarks file, the JSON it keeps in the profile folder.
+//   - A Firefox backup from Bookmarks > Manage Bookmarks > Backup, also JSON.
+//   - The Netscape bookmark HTML that every browser can export, including Chrome and Firefox.
+// Each format stores the date a bookmark was added in its own way, so they're all turned into a
+// UTC date and time here.
+
+// The topic for the bookmarks that aren't in any folder, which links to the topics for the
+// browser's top-level folders.
+pub const ROOT_TOPIC_NAME: &str = "Bookmarks";
+
+// The category of the topics made from bookmarks unless another one is given.
+pub const DEFAULT_CATEGORY: &str = "Bookmarks";
+
+// Chrome counts microseconds from 1601-01-01, which is this many seconds before 1970-01-01.
+const CHROME_EPOCH_OFFSET_SECONDS: i64 = 11_644_473_600;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BookmarkFormat {
+    ChromeJson,
+    FirefoxJson,
+    NetscapeHtml,
+}
+
+#[derive(Clone, Debug)]
+pub struct Bookmark {
+    pub url: String,
+    pub title: String,
+    pub added: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BookmarkFolder {
+    pub name: String,
+    // The names of the folders from the top of the tree down to and including this one, as in
+    // ["Bookmarks bar", "Programming", "Rust"]. Empty for the root, which only holds the
+    // browser's own top-level folders.
+    pub path: Vec<String>,
+    pub added: Option<NaiveDateTime>,
+    pub bookmarks: Vec<Bookmark>,
+    pub folders: Vec<BookmarkFolder>,
+}
+
+impl BookmarkFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_ref() {
+            "chrome" => Some(BookmarkFormat::ChromeJson),
+            "firefox" => Some(BookmarkFormat::FirefoxJson),
+            "html" | "netscape" => Some(BookmarkFormat::NetscapeHtml),
+            _ => None,
+        }
+    }
+
+    pub fn detect(text: &str) -> Option<Self> {
+        let text = text.trim_start_matches('\u{feff}').trim_start();
+        if text.starts_with('{') {
+            if text.contains("\"roots\"") {
+                Some(BookmarkFormat::ChromeJson)
+            } else if text.contains("text/x-moz-place") {
+                Some(BookmarkFormat::FirefoxJson)
+            } else {
+                None
+            }
+        } else if text.to_uppercase().contains("<DL") {
+            Some(BookmarkFormat::NetscapeHtml)
+        } else {
+            None
+        }
+    }
+}
+
+impl BookmarkFolder {
+    fn new(name: &str, parent_path: &[String], added: Option<NaiveDateTime>) -> Self {
+        let mut path = parent_path.to_vec();
+        path.push(name.to_string());
+        Self { name: name.to_string(), path, added, ..Self::default() }
+    }
+
+    pub fn path_string(&self) -> String {
+        self.path.join(" / ")
+    }
+
+    pub fn all_folders(&self) -> Vec<&BookmarkFolder> {
+        // This folder and every folder below it, parents before children.
+        let mut folders = vec![self];
+        for folder in self.folders.iter() {
+            folders.append(&mut folder.all_folders());
+        }
+        folders
+    }
+
+    pub fn all_bookmarks(&self) -> Vec<(&BookmarkFolder, &Bookmark)> {
+        self.all_folders().into_iter()
+            .flat_map(|folder| folder.bookmarks.iter().map(move |bookmark| (folder, bookmark)))
+            .collect()
+    }
+}
+
+// How the bookmarks in an export compare to the URL links already in the wiki. Each bookmark is
+// new, already there with the same normalized URL, or a near duplicate of a link to the same page
+// with a different scheme, query string or fragment. Topic labels are "project: topic".
+#[derive(Clone, Debug, Default)]
+pub struct BookmarkComparison {
+    // With the path of the folder the bookmark is in.
+    pub new: Vec<(String, Bookmark)>,
+    pub present: Vec<(Bookmark, Vec<String>)>,
+    // With the URL as written in the wiki and the topic label of each near duplicate.
+    pub near_duplicates: Vec<(Bookmark, Vec<(String, String)>)>,
+}
+
+// What goes into the topic for one folder, or for several folders that end up with the same
+// topic name.
+#[derive(Clone, Debug)]
+struct BookmarkTopic<'a> {
+    name: String,
+    parent: Option<String>,
+    children: Vec<String>,
+    bookmarks: Vec<&'a Bookmark>,
+    added: Option<NaiveDate>,
+}
+
+pub fn import_bookmarks(path_file: &path::Path, format: Option<BookmarkFormat>) -> io::Result<BookmarkFolder> {
+    // With no format given it's worked out from the content.
+    let bytes = fs::read(path_file)?;
+    if bytes.starts_with(b"mozLz40") {
+        return Err(invalid_data(path_file, "compressed Firefox backups (.jsonlz4) aren't supported, so use Backup to save a .json file instead"));
+    }
+    let text = String::from_utf8_lossy(&bytes);
+    let format = match format.or_else(|| BookmarkFormat::detect(&text)) {
+        Some(format) => format,
+        None => return Err(invalid_data(path_file, "not a Chrome, Firefox or HTML bookmarks file")),
+    };
+    match format {
+        BookmarkFormat::ChromeJson => parse_chrome_json(&text).map_err(|e| invalid_data(path_file, &e)),
+        BookmarkFormat::FirefoxJson => parse_firefox_json(&text).map_err(|e| invalid_data(path_file, &e)),
+        BookmarkFormat::NetscapeHtml => Ok(parse_netscape_html(&text)),
+    }
+}
+
+pub fn parse_chrome_json(text: &str) -> Result<BookmarkFolder, String> {
+    // {"roots": {"bookmark_bar": {"type": "folder", "name": "Bookmarks bar", "children": [...]},
+    // "other": {...}, "synced": {...}}, ...}
+    let value: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let roots = value.get("roots").and_then(|x| x.as_object()).ok_or_else(|| "no \"roots\" object".to_string())?;
+    let mut root = BookmarkFolder::default();
+    for key in ["bookmark_bar", "other", "synced"].iter() {
+        if let Some(node) = roots.get(*key) {
+            let folder = chrome_folder(node, &root.path);
+            if !folder.bookmarks.is_empty() || !folder.folders.is_empty() {
+                root.folders.push(folder);
+            }
+        }
+    }
+    Ok(root)
+}
+
+fn chrome_folder(node: &Value, parent_path: &[String]) -> BookmarkFolder {
+    let mut folder = BookmarkFolder::new(json_str(node, "name"), parent_path, json_str(node, "date_added").parse::<i64>().ok().and_then(chrome_date_time));
+    for child in node.get("children").and_then(|x| x.as_array()).into_iter().flatten() {
+        match json_str(child, "type") {
+            "folder" => folder.folders.push(chrome_folder(child, &folder.path)),
+            "url" => folder.bookmarks.push(Bookmark {
+                url: json_str(child, "url").to_string(),
+                title: json_str(child, "name").to_string(),
+                added: json_str(child, "date_added").parse::<i64>().ok().and_then(chrome_date_time),
+            }),
+            _ => {},
+        }
+    }
+    folder
+}
+
+pub fn parse_firefox_json(text: &str) -> Result<BookmarkFolder, String> {
+    // Every node has a "type": "text/x-moz-place-container" for a folder, "text/x-moz-place" for
+    // a bookmark and "text/x-moz-place-separator" for a separator. The root's children are the
+    // menu, toolbar, other and mobile folders, whose titles are internal names.
+    let value: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    if json_str(&value, "type") != "text/x-moz-place-container" {
+        return Err("the top level isn't a folder".to_string());
+    }
+    let mut root = BookmarkFolder::default();
+    for child in value.get("children").and_then(|x| x.as_array()).into_iter().flatten() {
+        if json_str(child, "type") != "text/x-moz-place-container" {
+            continue;
+        }
+        let name = match json_str(child, "guid") {
+            "menu________" => "Bookmarks Menu",
+            "toolbar_____" => "Bookmarks Toolbar",
+            "unfiled_____" => "Other Bookmarks",
+            "mobile______" => "Mobile Bookmarks",
+            _ => json_str(child, "title"),
+        };
+        let folder = firefox_folder(child, name, &root.path);
+        if !folder.bookmarks.is_empty() || !folder.folders.is_empty() {
+            root.folders.push(folder);
+        }
+    }
+    Ok(root)
+}
+
+fn firefox_folder(node: &Value, name: &str, parent_path: &[String]) -> BookmarkFolder {
+    let mut folder = BookmarkFolder::new(name, parent_path, node.get("dateAdded").and_then(|x| x.as_i64()).and_then(unix_micros_date_time));
+    for child in node.get("children").and_then(|x| x.as_array()).into_iter().flatten() {
+        match json_str(child, "type") {
+            "text/x-moz-place-container" => folder.folders.push(firefox_folder(child, json_str(child, "title"), &folder.path)),
+            // Smart bookmarks are saved searches with "place:" URIs, not web pages.
+            "text/x-moz-place" if !json_str(child, "uri").starts_with("place:") => folder.bookmarks.push(Bookmark {
+                url: json_str(child, "uri").to_string(),
+                title: json_str(child, "title").to_string(),
+                added: child.get("dateAdded").and_then(|x| x.as_i64()).and_then(unix_micros_date_time),
+            }),
+            _ => {},
+        }
+    }
+    folder
+}
+
+pub fn parse_netscape_html(text: &str) -> BookmarkFolder {
+    // <DT><H3 ADD_DATE="1580000000">Folder</H3>
+    // <DL><p>
+    //     <DT><A HREF="https://example.com/" ADD_DATE="1580000000">Title</A>
+    // </DL><p>
+    // A folder's heading comes just before the <DL> holding its contents, and ADD_DATE is in
+    // seconds. It isn't well-formed HTML, so it's read tag by tag rather than parsed.
+    // Uppercasing only ASCII keeps the positions the same as in text.
+    let upper = text.to_ascii_uppercase();
+    let mut stack: Vec<BookmarkFolder> = vec![];
+    let mut heading: Option<(String, Option<NaiveDateTime>)> = None;
+    let mut position = 0;
+    while let Some(start) = text[position..].find('<') {
+        let start = position + start;
+        let end = match text[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let tag = &text[start + 1..end];
+        let tag_name = tag.split_whitespace().next().unwrap_or("").to_uppercase();
+        position = end + 1;
+        match tag_name.as_ref() {
+            "H3" => {
+                let (inner, next) = inner_text(text, &upper, position, "</H3>");
+                heading = Some((inner, tag_attribute(tag, "ADD_DATE").and_then(|x| x.parse::<i64>().ok()).and_then(unix_seconds_date_time)));
+                position = next;
+            },
+            "DL" => {
+                // The first list is the root. Any other without a heading has no name.
+                let folder = match (stack.last(), heading.take()) {
+                    (None, _) => BookmarkFolder::default(),
+                    (Some(parent), Some((name, added))) => BookmarkFolder::new(&name, &parent.path, added),
+                    (Some(parent), None) => BookmarkFolder::new("", &parent.path, None),
+                };
+                stack.push(folder);
+            },
+            "/DL" => {
+                if stack.len() > 1 {
+                    let folder = stack.pop().unwrap();
+                    stack.last_mut().unwrap().folders.push(folder);
+                }
+            },
+            "A" => {
+                let (inner, next) = inner_text(text, &upper, position, "</A>");
+                position = next;
+                if let (Some(parent), Some(url)) = (stack.last_mut(), tag_attribute(tag, "HREF")) {
+                    parent.bookmarks.push(Bookmark {
+                        url,
+                        title: inner,
+                        added: tag_attribute(tag, "ADD_DATE").and_then(|x| x.parse::<i64>().ok()).and_then(unix_seconds_date_time),
+                    });
+                }
+            },
+            _ => {},
+        }
+    }
+    // A file cut off before its closing tags still gives what was read.
+    while stack.len() > 1 {
+        let folder = stack.pop().unwrap();
+        stack.last_mut().unwrap().folders.push(folder);
+    }
+    stack.pop().unwrap_or_default()
+}
+
+fn inner_text(text: &str, upper: &str, position: usize, end_tag: &str) -> (String, usize) {
+    // The text up to the end tag, ignoring case, and the position after it. upper is the whole
+    // text in uppercase so that it isn't copied again for every tag.
+    match upper[position..].find(end_tag) {
+        Some(end) => (decode_entities(text[position..position + end].trim()), position + end + end_tag.len()),
+        None => (String::new(), position),
+    }
+}
+
+fn tag_attribute(tag: &str, name: &str) -> Option<String> {
+    // The value of NAME="value" in a tag, ignoring the case of the name.
+    let upper = tag.to_ascii_uppercase();
+    let mut search_from = 0;
+    while let Some(found) = upper[search_from..].find(&format!("{}=", name)) {
+        let start = search_from + found;
+        search_from = start + name.len() + 1;
+        if start > 0 && !upper[..start].ends_with(char::is_whitespace) {
+            continue;
+        }
+        let rest = &tag[start + name.len() + 1..];
+        let value = match rest.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => rest[1..].split(quote).next().unwrap(),
+            _ => rest.split_whitespace().next().unwrap_or(""),
+        };
+        return Some(decode_entities(value));
+    }
+    None
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut s = String::new();
+    let mut remaining = text;
+    while let Some(start) = remaining.find('&') {
+        s.push_str(&remaining[..start]);
+        let after = &remaining[start + 1..];
+        let decoded = after.find(';').filter(|end| *end <= 8).and_then(|end| {
+            let c = match &after[..end] {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                code if code.starts_with("#x") || code.starts_with("#X") => u32::from_str_radix(&code[2..], 16).ok().and_then(std::char::from_u32),
+                code if code.starts_with('#') => code[1..].parse::<u32>().ok().and_then(std::char::from_u32),
+                _ => None,
+            };
+            c.map(|c| (c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                s.push(c);
+                remaining = &after[end + 1..];
+            },
+            None => {
+                s.push('&');
+                remaining = after;
+            },
+        }
+    }
+    s.push_str(remaining);
+    s
+}
+
+fn chrome_date_time(micros: i64) -> Option<NaiveDateTime> {
+    if micros <= 0 {
+        return None;
+    }
+    NaiveDateTime::from_timestamp_opt(micros / 1_000_000 - CHROME_EPOCH_OFFSET_SECONDS, 0)
+}
+
+fn unix_micros_date_time(micros: i64) -> Option<NaiveDateTime> {
+    if micros <= 0 { None } else { NaiveDateTime::from_timestamp_opt(micros / 1_000_000, 0) }
+}
+
+fn unix_seconds_date_time(seconds: i64) -> Option<NaiveDateTime> {
+    if seconds <= 0 { None } else { NaiveDateTime::from_timestamp_opt(seconds, 0) }
+}
+
+fn json_str<'a>(value: &'a Value, name: &str) -> &'a str {
+    value.get(name).and_then(|x| x.as_str()).unwrap_or("")
+}
+
+fn invalid_data(path_file: &path::Path, message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path_file.display(), message))
+}
+
+impl BookmarkComparison {
+    pub fn print(&self) {
+        for (folder_path, bookmark) in self.new.iter() {
+            let folder_path = if folder_path.is_empty() { "(no folder)" } else { folder_path.as_str() };
+            println!("New: {}: {}", folder_path, bookmark.url);
+        }
+        for (bookmark, topic_labels) in self.present.iter() {
+            println!("Already in {}: {}", topic_labels.join(", "), bookmark.url);
+        }
+        for (bookmark, matches) in self.near_duplicates.iter() {
+            let matches: Vec<String> = matches.iter().map(|(url, topic_label)| format!("{} in {}", url, topic_label)).collect();
+            println!("Near duplicate: {} is like {}", bookmark.url, matches.join(", "));
+        }
+        println!("\n{} new, {} already in the wiki, {} near duplicate(s)", self.new.len(), self.present.len(), self.near_duplicates.len());
+    }
+}
+
+impl Wiki {
+    fn topic_labels_by_url(&self) -> BTreeMap<String, Vec<String>> {
+        // The topics in every project that link to each normalized URL.
+        let mut labels_by_url: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for topic in self.topics.values() {
+            let topic_label = format!("{}: {}", topic.project_name, topic.name);
+            for (url, _) in urls::url_links(topic) {
+                let labels = labels_by_url.entry(urls::normalize_url(&url)).or_insert_with(|| { vec![] });
+                if !labels.contains(&topic_label) {
+                    labels.push(topic_label.clone());
+                }
+            }
+        }
+        labels_by_url
+    }
+
+    pub fn compare_bookmarks(&self, root: &BookmarkFolder) -> BookmarkComparison {
+        // A bookmark that's in the export more than once is only counted the first time.
+        let labels_by_url = self.topic_labels_by_url();
+        let mut urls_by_page: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+        for topic in self.topics.values() {
+            let topic_label = format!("{}: {}", topic.project_name, topic.name);
+            for (url, _) in urls::url_links(topic) {
+                urls_by_page.entry(urls::page_url(&url)).or_insert_with(|| { vec![] }).push((url, topic_label.clone()));
+            }
+        }
+        let mut comparison = BookmarkComparison::default();
+        let mut seen: BTreeSet<String> = BTreeSet::new();
+        for (folder, bookmark) in root.all_bookmarks() {
+            let url = urls::normalize_url(&bookmark.url);
+            if !seen.insert(url.clone()) {
+                continue;
+            }
+            if let Some(topic_labels) = labels_by_url.get(&url) {
+                comparison.present.push((bookmark.clone(), topic_labels.clone()));
+            } else if let Some(matches) = urls_by_page.get(&urls::page_url(&bookmark.url)) {
+                comparison.near_duplicates.push((bookmark.clone(), matches.clone()));
+            } else {
+                comparison.new.push((folder.path_string(), bookmark.clone()));
+            }
+        }
+        comparison
+    }
+
+    pub fn plan_staging_topic(&self, project_name: &str, topic_name: &str, category: &str, comparison: &BookmarkComparison) -> ChangeSet {
+        // Put the new bookmarks in one topic, under a heading for each folder, to be sorted into
+        // the right topics by hand. If the staging topic is already there from an earlier import
+        // the headings and bookmarks are added to the end of it. The comparison was made with the
+        // staging topic in the wiki, so none of the bookmarks are in it yet.
+        let mut change_set = ChangeSet::new(&format!("Stage {} new bookmark(s) in {:?}", comparison.new.len(), topic_name));
+        if comparison.new.is_empty() {
+            return change_set;
+        }
+        let mut sections = String::new();
+        let mut folder_path_last: Option<&str> = None;
+        for (folder_path, bookmark) in comparison.new.iter() {
+            if folder_path_last != Some(folder_path.as_str()) {
+                let heading = if folder_path.is_empty() { ROOT_TOPIC_NAME } else { folder_path.as_str() };
+                gen::gen_header(&mut sections, 1, heading);
+                folder_path_last = Some(folder_path);
+            }
+            gen_bookmark_line(&mut sections, bookmark);
+        }
+        match self.find_topic(project_name, topic_name) {
+            Some(existing) => {
+                let mut new_content = existing.content.trim_end().to_string();
+                new_content.push_str(&sections);
+                new_content.push('\n');
+                change_set.push(Change::EditContent {
+                    project_name: existing.project_name.clone(),
+                    topic_name: existing.name.clone(),
+                    old_content: existing.content.clone(),
+                    new_content,
+                });
+            },
+            None => {
+                let added = comparison.new.iter().filter_map(|(_, bookmark)| bookmark.added).map(|x| x.date()).min();
+                let mut s = gen_bookmark_topic_start(category, added);
+                s.push_str(&sections);
+                s.push('\n');
+                change_set.push(Change::CreateTopic {
+                    project_name: project_name.to_string(),
+                    topic_name: topic_name.to_string(),
+                    content: s,
+                });
+            },
+        }
+        change_set
+    }
+
+    pub fn plan_bookmark_topics(&self, project_name: &str, root: &BookmarkFolder, category: &str) -> ChangeSet {
+        // One topic per folder with links up to the parent folder's topic and down to the
+        // children's, and an Added date from the earliest date on the folder or its bookmarks.
+        // Bookmarks that any topic in the wiki already links to are left out, so importing a
+        // newer export of the same bookmarks adds just what's new, and a topic that already
+        // exists gets only the bookmarks and links it doesn't have yet.
+        let mut change_set = ChangeSet::new(&format!("Create topics for {} bookmark folder(s)", root.all_folders().len()));
+        let labels_by_url = self.topic_labels_by_url();
+        let mut present: BTreeSet<String> = BTreeSet::new();
+        for (_, bookmark) in root.all_bookmarks() {
+            let url = urls::normalize_url(&bookmark.url);
+            if let Some(topic_labels) = labels_by_url.get(&url) {
+                if present.insert(url) {
+                    change_set.add_warning(&format!("Already in {}: {}", topic_labels.join(", "), bookmark.url));
+                }
+            }
+        }
+        for topic in bookmark_topics(root, &present) {
+            match self.find_topic(project_name, &topic.name) {
+                Some(existing) => {
+                    let new_content = merge_bookmark_topic(&existing.content, existing.added_date.is_some(), &topic);
+                    if new_content != existing.content {
+                        change_set.push(Change::EditContent {
+                            project_name: existing.project_name.clone(),
+                            topic_name: existing.name.clone(),
+                            old_content: existing.content.clone(),
+                            new_content,
+                        });
+                    }
+                },
+                None => change_set.push(Change::CreateTopic {
+                    project_name: project_name.to_string(),
+                    topic_name: topic.name.clone(),
+                    content: gen_bookmark_topic(&topic, category),
+                }),
+            }
+        }
+        change_set
+    }
+}
+
+fn bookmark_topics<'a>(root: &'a BookmarkFolder, present: &BTreeSet<String>) -> Vec<BookmarkTopic<'a>> {
+    // present holds the normalized URLs that are already in the wiki. Folders with no other
+    // bookmarks anywhere below them are left out.
+    let is_new = |bookmark: &Bookmark| !present.contains(&urls::normalize_url(&bookmark.url));
+    let folders: Vec<&BookmarkFolder> = root.all_folders().into_iter()
+        .filter(|folder| folder.all_bookmarks().iter().any(|(_, bookmark)| is_new(bookmark)))
+        .collect();
+    let topic_names = bookmark_topic_names(&folders);
+    let mut topics: Vec<BookmarkTopic> = vec![];
+    // The index in topics for each lowercase topic name, the same matching as Wiki::topic_key().
+    let mut topic_indexes: BTreeMap<String, usize> = BTreeMap::new();
+    for folder in folders.iter() {
+        let name = topic_names[&folder.path].clone();
+        let parent = if folder.path.is_empty() { None } else { Some(topic_names[&folder.path[..folder.path.len() - 1].to_vec()].clone()) };
+        let index = *topic_indexes.entry(name.to_lowercase()).or_insert_with(|| {
+            topics.push(BookmarkTopic { name, parent, children: vec![], bookmarks: vec![], added: None });
+            topics.len() - 1
+        });
+        let topic = &mut topics[index];
+        for child in folder.folders.iter().filter(|child| topic_names.contains_key(&child.path)) {
+            let child_name = &topic_names[&child.path];
+            if !topic.children.contains(child_name) {
+                topic.children.push(child_name.clone());
+            }
+        }
+        for bookmark in folder.bookmarks.iter().filter(|bookmark| is_new(bookmark)) {
+            let url = urls::normalize_url(&bookmark.url);
+            if !topic.bookmarks.iter().any(|x| urls::normalize_url(&x.url) == url) {
+                topic.bookmarks.push(bookmark);
+            }
+        }
+        let dates = folder.added.iter().chain(folder.bookmarks.iter().filter_map(|x| x.added.as_ref())).map(|x| x.date());
+        topic.added = topic.added.into_iter().chain(dates).min();
+    }
+    topics
+}
+
+fn bookmark_topic_names(folders: &[&BookmarkFolder]) -> BTreeMap<Vec<String>, String> {
+    // Keyed by the folder path. A folder's topic is named for the folder unless another folder
+    // somewhere else has the same name, in which case it's the whole path, as in
+    // "Programming - Rust - Tools", so that they don't end up in one topic. The root's topic
+    // counts as one of them, so a top-level folder called Bookmarks becomes "Bookmarks -
+    // Bookmarks" rather than linking up to itself.
+    let mut counts: BTreeMap<String, BTreeSet<&Vec<String>>> = BTreeMap::new();
+    for folder in folders.iter() {
+        let name = if folder.path.is_empty() { ROOT_TOPIC_NAME.to_string() } else { clean_topic_name(&folder.name) };
+        counts.entry(name.to_lowercase()).or_insert_with(BTreeSet::new).insert(&folder.path);
+    }
+    folders.iter()
+        .map(|folder| {
+            let name = if folder.path.is_empty() {
+                ROOT_TOPIC_NAME.to_string()
+            } else if counts[&clean_topic_name(&folder.name).to_lowercase()].len() > 1 {
+                let path_name = clean_topic_name(&folder.path.join(" - "));
+                if Wiki::same_name(&path_name, ROOT_TOPIC_NAME) { format!("{} - {}", ROOT_TOPIC_NAME, path_name) } else { path_name }
+            } else {
+                clean_topic_name(&folder.name)
+            };
+            (folder.path.clone(), name)
+        })
+        .collect()
+}
+
+fn clean_topic_name(name: &str) -> String {
+    // Characters that have a meaning inside a link can't be in a topic name.
+    let name: String = name.chars().map(|c| if "[]|#".contains(c) || c.is_control() { '-' } else { c }).collect();
+    let name = name.trim();
+    if name.is_empty() { "Unnamed Folder".to_string() } else { name.to_string() }
+}
+
+fn gen_bookmark_line(s: &mut String, bookmark: &Bookmark) {
+    let label = if bookmark.title.trim().is_empty() { &bookmark.url } else { bookmark.title.trim() };
+    gen::gen_url_line_label_first(s, &bookmark.url, label);
+}
+
+fn gen_bookmark_topic_start(category: &str, added: Option<NaiveDate>) -> String {
+    // The category and a table with the Added date that every topic made from bookmarks starts with.
+    let mut s = gen::gen_category(category);
+    s.push_str(&format!("\n\n{{|\n{}\n|}}", gen::gen_table_field_date_single(dates::ATTRIBUTE_ADDED, added)));
+    s
+}
+
+fn gen_bookmark_topic(topic: &BookmarkTopic, category: &str) -> String {
+    let mut s = gen_bookmark_topic_start(category, topic.added);
+    if let Some(parent) = &topic.parent {
+        s.push_str(&format!("\n\nUp: [[{}]]", parent));
+    }
+    if !topic.children.is_empty() {
+        gen::gen_header(&mut s, 1, "Folders");
+        for child in topic.children.iter() {
+            s.push_str(&format!("\n * [[{}]]", child));
+        }
+    }
+    if !topic.bookmarks.is_empty() {
+        gen::gen_header(&mut s, 1, "Bookmarks");
+        for bookmark in topic.bookmarks.iter() {
+            gen_bookmark_line(&mut s, bookmark);
+        }
+    }
+    s.push('\n');
+    s
+}
+
+fn merge_bookmark_topic(content: &str, has_added_date: bool, topic: &BookmarkTopic) -> String {
+    // Add the links and bookmarks the topic doesn't have to the end of it, leaving the rest of it
+    // as it was. New child links go under a Folders heading of their own, and the bookmarks after
+    // them under a Bookmarks heading, so that neither ends up in whatever section was last.
+    let links = import::parse_links(content);
+    let linked_topics: BTreeSet<String> = links.iter()
+        .filter_map(|link| match link {
+            Link::Internal { topic_name, .. } => Some(topic_name.trim().to_lowercase()),
+            _ => None,
+        })
+        .collect();
+    let present_urls: BTreeSet<String> = links.iter()
+        .filter_map(|link| match link {
+            Link::Url { url, .. } => Some(urls::normalize_url(url)),
+            _ => None,
+        })
+        .collect();
+    let mut s = String::new();
+    if let Some(parent) = topic.parent.as_ref().filter(|x| !linked_topics.contains(&x.to_lowercase())) {
+        s.push_str(&format!("\nUp: [[{}]]", parent));
+    }
+    let children: Vec<&String> = topic.children.iter().filter(|x| !linked_topics.contains(&x.to_lowercase())).collect();
+    let adds_children = !children.is_empty();
+    if adds_children {
+        gen::gen_header(&mut s, 1, "Folders");
+        for child in children {
+            s.push_str(&format!("\n * [[{}]]", child));
+        }
+    }
+    let bookmarks: Vec<&&Bookmark> = topic.bookmarks.iter().filter(|x| !present_urls.contains(&urls::normalize_url(&x.url))).collect();
+    if !bookmarks.is_empty() {
+        let ends_in_bookmarks = content.lines().filter_map(writer::heading_depth).last().map_or(false, |(_, heading)| heading == "Bookmarks");
+        if adds_children || !ends_in_bookmarks {
+            gen::gen_header(&mut s, 1, "Bookmarks");
+        }
+        for bookmark in bookmarks {
+            gen_bookmark_line(&mut s, bookmark);
+        }
+    }
+    let mut new_content = content.to_string();
+    if !s.is_empty() {
+        if !new_content.is_empty() && !new_content.ends_with('\n') {
+            new_content.push('\n');
+        }
+        new_content.push_str(&s[1..]);
+        new_content.push('\n');
+    }
+    if let (false, Some(added)) = (has_added_date, topic.added) {
+        new_content = writer::set_attribute_values(&new_content, dates::ATTRIBUTE_ADDED, &[added.format("%Y%m%d").to_string()]);
+    }
+    new_content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date_time(text: &str) -> Option<NaiveDateTime> {
+        Some(NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S").unwrap())
+    }
+
+    #[test]
+    fn chrome_json() {
+        // 13222323022000000 microseconds from 1601 is 2020-01-01 03:30:22 UTC.
+        let text = r#"{"roots": {
+            "bookmark_bar": {"type": "folder", "name": "Bookmarks bar", "date_added": "13222323022000000", "children": [
+                {"type": "url", "name": "Example", "url": "https://example.com/", "date_added": "13222323022000000"},
+                {"type": "folder", "name": "Rust", "children": [{"type": "url", "name": "Rust", "url": "https://www.rust-lang.org/"}]}
+            ]},
+            "other": {"type": "folder", "name": "Other bookmarks", "children": []}
+        }}"#;
+        assert_eq!(BookmarkFormat::detect(text), Some(BookmarkFormat::ChromeJson));
+        let root = parse_chrome_json(text).unwrap();
+        // The empty Other bookmarks folder is left out.
+        assert_eq!(root.folders.len(), 1);
+        let bar = &root.folders[0];
+        assert_eq!((bar.name.as_str(), bar.added), ("Bookmarks bar", date_time("2020-01-01 03:30:22")));
+        assert_eq!(bar.bookmarks[0].added, date_time("2020-01-01 03:30:22"));
+        assert_eq!(bar.folders[0].path, vec!["Bookmarks bar", "Rust"]);
+        assert_eq!(bar.folders[0].bookmarks[0].added, None);
+        assert!(parse_chrome_json("{}").is_err());
+        assert!(parse_chrome_json("{\"roots\": ").is_err());
+    }
+
+    #[test]
+    fn firefox_json() {
+        let text = r#"{"type": "text/x-moz-place-container", "children": [
+            {"type": "text/x-moz-place-container", "guid": "toolbar_____", "title": "toolbar", "dateAdded": 1577849422000000, "children": [
+                {"type": "text/x-moz-place", "title": "Example", "uri": "https://example.com/", "dateAdded": 1577849422000000},
+                {"type": "text/x-moz-place", "title": "Recent", "uri": "place:sort=8"},
+                {"type": "text/x-moz-place-separator"},
+                {"type": "text/x-moz-place-container", "title": "Rust", "children": [
+                    {"type": "text/x-moz-place", "title": "Rust", "uri": "https://www.rust-lang.org/"}
+                ]}
+            ]},
+            {"type": "text/x-moz-place-container", "guid": "mobile______", "title": "mobile", "children": []}
+        ]}"#;
+        assert_eq!(BookmarkFormat::detect(text), Some(BookmarkFormat::FirefoxJson));
+        let root = parse_firefox_json(text).unwrap();
+        assert_eq!(root.folders.len(), 1);
+        let toolbar = &root.folders[0];
+        assert_eq!((toolbar.name.as_str(), toolbar.added), ("Bookmarks Toolbar", date_time("2020-01-01 03:30:22")));
+        // The smart bookmark and the separator are skipped.
+        assert_eq!(toolbar.bookmarks.iter().map(|x| x.url.as_str()).collect::<Vec<_>>(), vec!["https://example.com/"]);
+        assert_eq!(toolbar.folders[0].path, vec!["Bookmarks Toolbar", "Rust"]);
+        assert!(parse_firefox_json(r#"{"type": "text/x-moz-place"}"#).is_err());
+    }
+
+    #[test]
+    fn netscape_html() {
+        let text = "<!DOCTYPE NETSCAPE-Bookmark-file-1>\n<TITLE>Bookmarks</TITLE>\n<DL><p>\n\
+            <DT><A HREF=\"https://example.com/?a=1&amp;b=2\" ADD_DATE=\"1577849422\">Top &amp; level</A>\n\
+            <DT><H3 ADD_DATE=\"1577849422\">Programming</H3>\n<DL><p>\n\
+                <DT><h3>Rust</h3>\n<dl><p>\n<DT><a href='https://www.rust-lang.org/'>Rust</a>\n</dl><p>\n\
+                <DL><p>\n<DT><A HREF=https://unnamed.example/>Unnamed</A>\n</DL><p>\n\
+            </DL><p>\n</DL><p>\n";
+        assert_eq!(BookmarkFormat::detect(text), Some(BookmarkFormat::NetscapeHtml));
+        let root = parse_netscape_html(text);
+        assert_eq!(root.bookmarks[0].url, "https://example.com/?a=1&b=2");
+        assert_eq!(root.bookmarks[0].title, "Top & level");
+        assert_eq!(root.bookmarks[0].added, date_time("2020-01-01 03:30:22"));
+        let programming = &root.folders[0];
+        assert_eq!((programming.name.as_str(), programming.added), ("Programming", date_time("2020-01-01 03:30:22")));
+        assert_eq!(programming.folders[0].path, vec!["Programming", "Rust"]);
+        assert_eq!(programming.folders[0].bookmarks[0].url, "https://www.rust-lang.org/");
+        // A list with no heading before it is a folder with no name.
+        assert_eq!(programming.folders[1].path, vec!["Programming", ""]);
+        assert_eq!(programming.folders[1].bookmarks[0].url, "https://unnamed.example/");
+
+        // Cut off partway through a tag, it keeps what was read and the folders it was in.
+        let cut = &text[..text.find("<DT><A HREF=https").unwrap() + 10];
+        let root = parse_netscape_html(cut);
+        assert_eq!(root.folders[0].folders.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(), vec!["Rust", ""]);
+        assert!(root.folders[0].folders[1].bookmarks.is_empty());
+        assert_eq!(parse_netscape_html("").folders.len(), 0);
+    }
+
+    #[test]
+    fn attributes() {
+        assert_eq!(tag_attribute("A HREF=\"https://example.com/\" ADD_DATE=\"1\"", "HREF").as_deref(), Some("https://example.com/"));
+        assert_eq!(tag_attribute("a href='https://example.com/?q=\"x\"'", "HREF").as_deref(), Some("https://example.com/?q=\"x\""));
+        assert_eq!(tag_attribute("A HREF=https://example.com/ ADD_DATE=1", "ADD_DATE").as_deref(), Some("1"));
+        // DATA-HREF isn't HREF.
+        assert_eq!(tag_attribute("A DATA-HREF=\"x\"", "HREF"), None);
+        assert_eq!(tag_attribute("A DATA-HREF=\"x\" HREF=\"y\"", "HREF").as_deref(), Some("y"));
+    }
+
+    fn folder(name: &str, parent_path: &[String], urls: &[&str], folders: Vec<BookmarkFolder>) -> BookmarkFolder {
+        let mut folder = BookmarkFolder::new(name, parent_path, None);
+        folder.bookmarks = urls.iter().map(|url| Bookmark { url: url.to_string(), title: String::new(), added: None }).collect();
+        folder.folders = folders;
+        folder
+    }
+
+    #[test]
+    fn topics_merge_ignoring_case() {
+        // "ärger - Büro" is the path name of the Büro folder in "ärger", since there's another
+        // Büro, and it's the same topic as the "Ärger - büro" folder.
+        let root = BookmarkFolder {
+            folders: vec![
+                folder("Ärger - büro", &[], &["https://a.example/"], vec![]),
+                folder("ärger", &[], &[], vec![folder("Büro", &["ärger".to_string()], &["https://b.example/"], vec![])]),
+                folder("Other", &[], &[], vec![folder("Büro", &["Other".to_string()], &["https://c.example/"], vec![])]),
+            ],
+            ..BookmarkFolder::default()
+        };
+        let topics = bookmark_topics(&root, &BTreeSet::new());
+        let names = topics.iter().map(|topic| topic.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec![ROOT_TOPIC_NAME, "Ärger - büro", "ärger", "Other", "Other - Büro"]);
+        assert_eq!(topics[1].bookmarks.len(), 2);
+        assert_eq!(topics[2].children, vec!["ärger - Büro"]);
+    }
+
+    #[test]
+    fn merge_into_existing_topic() {
+        let mut wiki = Wiki::new();
+        wiki.add_topic(super::super::model::Topic::new("P", "Programming", "[[$CATEGORY:Bookmarks]]\n\nUp: [[Bookmarks]]\n\n=Bookmarks=\n\n * Old: [[$URL:https://old.example/]]\n"));
+        let programming = folder("Programming", &[], &["https://old.example/", "https://new.example/"], vec![]);
+        let rust = folder("Rust", &programming.path, &["https://www.rust-lang.org/"], vec![]);
+        let root = BookmarkFolder { folders: vec![BookmarkFolder { folders: vec![rust], ..programming.clone() }], ..BookmarkFolder::default() };
+        let change_set = wiki.plan_bookmark_topics("P", &root, DEFAULT_CATEGORY);
+        let edited = change_set.changes.iter().find_map(|change| match change {
+            Change::EditContent { topic_name, new_content, .. } if topic_name == "Programming" => Some(new_content.as_str()),
+            _ => None,
+        });
+        assert_eq!(edited, Some("[[$CATEGORY:Bookmarks]]\n\nUp: [[Bookmarks]]\n\n=Bookmarks=\n\n * Old: [[$URL:https://old.example/]]\n\n=Folders=\n\n * [[Rust]]\n\n=Bookmarks=\n\n * https://new.example/: [[$URL:https://new.example/]]\n"));
+
+        // With no new child folders, new bookmarks go on the end of the Bookmarks section.
+        let root = BookmarkFolder { folders: vec![programming], ..BookmarkFolder::default() };
+        let change_set = wiki.plan_bookmark_topics("P", &root, DEFAULT_CATEGORY);
+        let edited = change_set.changes.iter().find_map(|change| match change {
+            Change::EditContent { new_content, .. } => Some(new_content.as_str()),
+            _ => None,
+        });
+        assert_eq!(edited, Some("[[$CATEGORY:Bookmarks]]\n\nUp: [[Bookmarks]]\n\n=Bookmarks=\n\n * Old: [[$URL:https://old.example/]]\n * https://new.example/: [[$URL:https://new.example/]]\n"));
+    }
+
+    #[test]
+    fn entities() {
+        assert_eq!(decode_entities("a &lt;b&gt; &quot;c&quot; &apos;d&apos; &amp;amp;"), "a <b> \"c\" 'd' &amp;");
+        assert_eq!(decode_entities("&#233;&#xE9;&#XE9;&#x1F600;"), "\u{e9}\u{e9}\u{e9}\u{1F600}");
+        // Anything that isn't an entity is left as it was.
+        assert_eq!(decode_entities("AT&T; a & b &unknown; &#xZZ; &"), "AT&T; a & b &unknown; &#xZZ; &");
+    }
+}
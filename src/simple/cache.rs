@@ -0,0 +1,272 @@
+use std::{fs, io, path};
+use std::collections::{BTreeMap, BTreeSet};
+use serde::{Deserialize, Serialize};
+use crate::*;
+use super::input::{self, Encoding};
+use super::model::{Link, Topic, Wiki};
+
+// An on-disk cache of what was parsed from each topic of an export file, so that loading the
+// file again only parses the topics whose content changed. The cache is tied to the path of the
+// export file and each topic to a hash of its content. It also keeps the backlink index up to
+// date so that the topics affected by a change can be found without rebuilding it.
+
+// Changed whenever what's parsed from a topic changes, such as when URL links were added to the
+// links, so that a cache from an older version isn't used.
+const CACHE_VERSION: u32 = 2;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ImportCache {
+    // Caches written before there was a version read as 0.
+    #[serde(default)]
+    pub version: u32,
+    pub path_file: path::PathBuf,
+    pub project_name: String,
+    // Keyed by Wiki::topic_key().
+    pub topics: BTreeMap<String, CachedTopic>,
+    // The key of the topic being linked to and the keys of the topics that link to it, as in
+    // Wiki::link_index().
+    pub inbound: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CachedTopic {
+    pub name: String,
+    pub content_hash: u64,
+    pub category: Option<String>,
+    pub attributes: BTreeMap<String, Vec<String>>,
+    pub links: Vec<Link>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CacheUpdate {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+    pub reused: usize,
+}
+
+impl ImportCache {
+    pub fn new(path_file: &path::Path, project_name: &str) -> Self {
+        Self {
+            version: CACHE_VERSION,
+            path_file: path_file.to_path_buf(),
+            project_name: project_name.to_string(),
+            ..Self::default()
+        }
+    }
+
+    pub fn load(path_cache_file: &path::Path, path_file: &path::Path, project_name: &str) -> Self {
+        // A missing or unreadable cache, or one made by another version or for a different export
+        // file or project, just means starting over.
+        let cache: Option<ImportCache> = fs::read_to_string(path_cache_file).ok()
+            .and_then(|json| serde_json::from_str(&json).ok());
+        match cache {
+            Some(cache) if cache.version == CACHE_VERSION && cache.path_file == path_file && cache.project_name == project_name => cache,
+            _ => Self::new(path_file, project_name),
+        }
+    }
+
+    pub fn save(&self, path_cache_file: &path::Path) -> io::Result<()> {
+        let json = serde_json::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        fs::write(path_cache_file, json)
+    }
+
+    pub fn record(&mut self, wiki: &Wiki, update: &CacheUpdate, content_hashes: &BTreeMap<String, u64>) {
+        // Bring the cache in line with the wiki after the topics in update were added, changed or
+        // removed. The wiki's topics must already be resolved.
+        for key in update.added.iter().chain(update.changed.iter()) {
+            self.update_topic(key, wiki.topics.get(key), content_hashes[key]);
+        }
+        for key in update.removed.iter() {
+            self.update_topic(key, None, 0);
+        }
+    }
+
+    fn update_topic(&mut self, key: &str, topic: Option<&Topic>, content_hash: u64) {
+        // Take the topic's old links out of the backlink index and put its new ones in.
+        if let Some(cached) = self.topics.remove(key) {
+            for target in link_targets(&self.project_name, &cached.links) {
+                if let Some(keys) = self.inbound.get_mut(&target) {
+                    keys.retain(|x| x != key);
+                    if keys.is_empty() {
+                        self.inbound.remove(&target);
+                    }
+                }
+            }
+        }
+        if let Some(topic) = topic {
+            for target in link_targets(&self.project_name, &topic.links) {
+                let keys = self.inbound.entry(target).or_insert_with(|| { vec![] });
+                if !keys.iter().any(|x| x == key) {
+                    keys.push(key.to_string());
+                }
+            }
+            self.topics.insert(key.to_string(), CachedTopic {
+                name: topic.name.clone(),
+                content_hash,
+                category: topic.category.clone(),
+                attributes: topic.attributes.clone(),
+                links: topic.links.clone(),
+            });
+        }
+    }
+}
+
+impl CacheUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    pub fn affected_topics(&self, cache: &ImportCache) -> BTreeSet<String> {
+        // The topics whose own content changed, plus the topics that link to a topic that
+        // appeared or disappeared, since whether those links resolve has changed.
+        let mut keys: BTreeSet<String> = self.added.iter().chain(self.changed.iter()).cloned().collect();
+        for key in self.added.iter().chain(self.removed.iter()) {
+            if let Some(linking_keys) = cache.inbound.get(key) {
+                keys.extend(linking_keys.iter().cloned());
+            }
+        }
+        for key in self.removed.iter() {
+            keys.remove(key);
+        }
+        keys
+    }
+
+    pub fn print(&self) {
+        println!("{} topic(s) reused from the cache, {} added, {} changed, {} removed",
+            self.reused, self.added.len(), self.changed.len(), self.removed.len());
+    }
+}
+
+pub fn import_topics_cached(path_file_import: &path::Path, project_name: &str, encoding: Option<Encoding>, path_cache_file: &path::Path) -> io::Result<(Wiki, CacheUpdate)> {
+    let decoded = input::read_file(path_file_import, encoding)?;
+    decoded.print_problems(path_file_import.to_str().unwrap());
+    let mut cache = ImportCache::load(path_cache_file, path_file_import, project_name);
+    let mut update = CacheUpdate::default();
+    let mut wiki = Wiki::new();
+    wiki.text_formats.insert(project_name.to_lowercase(), decoded.format);
+    let mut content_hashes: BTreeMap<String, u64> = BTreeMap::new();
+    for (name, content) in input::split_into_sections(&decoded.text, DELIMITER_TOPIC) {
+        let key = Wiki::topic_key(project_name, &name);
+        let content_hash = hash_text(&content);
+        let mut topic = Topic::new(project_name, &name, &content);
+        match cache.topics.get(&key) {
+            Some(cached) if cached.content_hash == content_hash && cached.name == name => {
+                topic.category = cached.category.clone();
+                topic.attributes = cached.attributes.clone();
+                topic.links = cached.links.clone();
+                topic.is_resolved = true;
+                update.reused += 1;
+            },
+            Some(_) => update.changed.push(key.clone()),
+            None => update.added.push(key.clone()),
+        }
+        content_hashes.insert(key, content_hash);
+        wiki.add_topic(topic);
+    }
+    update.removed = cache.topics.keys().filter(|key| !wiki.topics.contains_key(*key)).cloned().collect();
+
+    // Only the topics that weren't in the cache get parsed here.
+    wiki.resolve_attributes();
+
+    cache.record(&wiki, &update, &content_hashes);
+    if !update.is_empty() {
+        cache.save(path_cache_file)?;
+    }
+    Ok((wiki, update))
+}
+
+fn link_targets(project_name: &str, links: &[Link]) -> Vec<String> {
+    // Internal links resolve within the linking topic's project.
+    links.iter()
+        .filter_map(|link| match link {
+            Link::Internal { topic_name, .. } if !topic_name.trim().is_empty() => Some(Wiki::link_key(project_name, topic_name)),
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn hash_text(text: &str) -> u64 {
+    hash_bytes(text.as_bytes())
+}
+
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    // FNV-1a, which unlike the standard library's hasher gives the same result on every run and
+    // every version of Rust, so it's safe to store.
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_folder(name: &str) -> path::PathBuf {
+        let path_folder = std::env::temp_dir().join(format!("connectedtext-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&path_folder).unwrap();
+        path_folder
+    }
+
+    #[test]
+    fn hash() {
+        // The published FNV-1a test values, which a stored cache relies on staying the same.
+        assert_eq!(hash_text(""), 0xcbf29ce484222325);
+        assert_eq!(hash_text("a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(hash_text("foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn version_and_source() {
+        let path_folder = temp_folder("cache-version");
+        let path_cache_file = path_folder.join("cache.json");
+        let path_file = path_folder.join("export.txt");
+        let mut cache = ImportCache::new(&path_file, "P");
+        cache.topics.insert("p:a".to_string(), CachedTopic { name: "A".to_string(), content_hash: 1, category: None, attributes: BTreeMap::new(), links: vec![] });
+        cache.save(&path_cache_file).unwrap();
+        assert_eq!(ImportCache::load(&path_cache_file, &path_file, "P").topics.len(), 1);
+        assert!(ImportCache::load(&path_cache_file, &path_folder.join("other.txt"), "P").topics.is_empty());
+        assert!(ImportCache::load(&path_cache_file, &path_file, "Q").topics.is_empty());
+        cache.version = CACHE_VERSION - 1;
+        cache.save(&path_cache_file).unwrap();
+        assert!(ImportCache::load(&path_cache_file, &path_file, "P").topics.is_empty());
+        fs::remove_dir_all(&path_folder).unwrap();
+    }
+
+    #[test]
+    fn reload_after_changes() {
+        let path_folder = temp_folder("cache-reload");
+        let path_cache_file = path_folder.join("cache.json");
+        let path_file = path_folder.join("export.txt");
+        let key = |topic_name: &str| Wiki::topic_key("P", topic_name);
+
+        fs::write(&path_file, "{{Topic}}A\n[[B]]\n{{Topic}}B\nText\n{{Topic}}C\n[[B]]\n").unwrap();
+        let (_, update) = import_topics_cached(&path_file, "P", None, &path_cache_file).unwrap();
+        assert_eq!((update.added.len(), update.reused), (3, 0));
+        let cache = ImportCache::load(&path_cache_file, &path_file, "P");
+        assert_eq!(cache.inbound.get(&key("B")), Some(&vec![key("A"), key("C")]));
+
+        // Loading it again unchanged parses nothing.
+        let (wiki, update) = import_topics_cached(&path_file, "P", None, &path_cache_file).unwrap();
+        assert!(update.is_empty());
+        assert_eq!(update.reused, 3);
+        assert!(matches!(&wiki.topics[&key("A")].links[..], [Link::Internal { topic_name, .. }] if topic_name == "B"));
+
+        // A now links to D instead of B, B is gone and D is new.
+        fs::write(&path_file, "{{Topic}}A\n[[D]]\n{{Topic}}C\n[[B]]\n{{Topic}}D\nText\n").unwrap();
+        let (wiki, update) = import_topics_cached(&path_file, "P", None, &path_cache_file).unwrap();
+        assert_eq!((update.added.clone(), update.changed.clone(), update.removed.clone(), update.reused), (vec![key("D")], vec![key("A")], vec![key("B")], 1));
+        assert!(matches!(&wiki.topics[&key("A")].links[..], [Link::Internal { topic_name, .. }] if topic_name == "D"));
+        let cache = ImportCache::load(&path_cache_file, &path_file, "P");
+        assert_eq!(cache.inbound.get(&key("B")), Some(&vec![key("C")]));
+        assert_eq!(cache.inbound.get(&key("D")), Some(&vec![key("A")]));
+        assert!(!cache.topics.contains_key(&key("B")));
+        // C links to B, which is gone, so it's affected too.
+        let affected = update.affected_topics(&cache).into_iter().collect::<Vec<_>>();
+        assert_eq!(affected, vec![key("A"), key("C"), key("D")]);
+        fs::remove_dir_all(&path_folder).unwrap();
+    }
+}
@@ -0,0 +1,381 @@
+use std::{io, path};
+use std::collections::{BTreeMap, BTreeSet};
+use crate::*;
+use super::import;
+use super::model::{Topic, Wiki};
+use super::plan::{ChangePlan, FileOperation};
+
+#[derive(Clone, Debug)]
+pub struct ChangeSet {
+    pub label: String,
+    pub changes: Vec<Change>,
+    pub problems: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Change {
+    RenameTopic {
+        project_name: String,
+        old_name: String,
+        new_name: String,
+    },
+    RenameFile {
+        project_name: String,
+        old_file_name: String,
+        new_file_name: String,
+    },
+    MoveTopic {
+        old_project_name: String,
+        new_project_name: String,
+        topic_name: String,
+    },
+    EditContent {
+        project_name: String,
+        topic_name: String,
+        old_content: String,
+        new_content: String,
+    },
+    CreateTopic {
+        project_name: String,
+        topic_name: String,
+        content: String,
+    },
+    DeleteTopic {
+        project_name: String,
+        topic_name: String,
+    },
+}
+
+impl ChangeSet {
+    pub fn new(label: &str) -> Self {
+        Self {
+            label: label.to_string(),
+            changes: vec![],
+            problems: vec![],
+            warnings: vec![],
+        }
+    }
+
+    pub fn push(&mut self, change: Change) {
+        self.changes.push(change);
+    }
+
+    pub fn add_problem(&mut self, problem: &str) {
+        self.problems.push(problem.to_string());
+    }
+
+    pub fn add_warning(&mut self, warning: &str) {
+        self.warnings.push(warning.to_string());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn print_preview(&self) {
+        println!("\n{}: {} change(s), {} problem(s), {} warning(s)\n", self.label, self.changes.len(), self.problems.len(), self.warnings.len());
+        for problem in self.problems.iter() {
+            println!("  PROBLEM: {}", problem);
+        }
+        for warning in self.warnings.iter() {
+            println!("  WARNING: {}", warning);
+        }
+        for change in self.changes.iter() {
+            match change {
+                Change::RenameTopic { project_name, old_name, new_name } => {
+                    println!("  {}: rename topic {:?} to {:?}", project_name, old_name, new_name);
+                },
+                Change::RenameFile { project_name, old_file_name, new_file_name } => {
+                    println!("  {}: rename file {:?} to {:?}", project_name, old_file_name, new_file_name);
+                },
+                Change::MoveTopic { old_project_name, new_project_name, topic_name } => {
+                    println!("  {}: move {:?} to {}", old_project_name, topic_name, new_project_name);
+                },
+                Change::EditContent { project_name, topic_name, old_content, new_content } => {
+                    println!("  {}: edit {:?}", project_name, topic_name);
+                    print_changed_lines(old_content, new_content);
+                },
+                Change::CreateTopic { project_name, topic_name, content } => {
+                    println!("  {}: create {:?} ({} lines)", project_name, topic_name, content.lines().count());
+                },
+                Change::DeleteTopic { project_name, topic_name } => {
+                    println!("  {}: delete {:?}", project_name, topic_name);
+                },
+            }
+        }
+    }
+
+    pub fn apply(&self, wiki: &mut Wiki) -> io::Result<()> {
+        // A change set made from an older state of the wiki may no longer fit, for instance if a
+        // topic it edits has since been deleted. The changes are worked out on the side, keyed the
+        // same way as the wiki's topics with None for a topic that's gone, and the wiki is only
+        // touched once every change fits, so a change set that doesn't fit leaves it as it was.
+        if !self.problems.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Change set \"{}\" has problems: {:?}", self.label, self.problems)));
+        }
+        let not_found = |project_name: &str, topic_name: &str| io::Error::new(io::ErrorKind::NotFound, format!("No topic {:?} in project {:?}.", topic_name, project_name));
+        let already_exists = |project_name: &str, topic_name: &str| io::Error::new(io::ErrorKind::AlreadyExists, format!("Topic {:?} already exists in project {:?}.", topic_name, project_name));
+        let mut staged: BTreeMap<String, Option<Topic>> = BTreeMap::new();
+        let current = |staged: &BTreeMap<String, Option<Topic>>, key: &str| -> Option<Topic> {
+            match staged.get(key) {
+                Some(topic) => topic.clone(),
+                None => wiki.topics.get(key).cloned(),
+            }
+        };
+        for change in self.changes.iter() {
+            match change {
+                Change::RenameTopic { project_name, old_name, new_name } => {
+                    let old_key = Wiki::topic_key(project_name, old_name);
+                    let new_key = Wiki::topic_key(project_name, new_name);
+                    let mut topic = current(&staged, &old_key).ok_or_else(|| not_found(project_name, old_name))?;
+                    if new_key != old_key && current(&staged, &new_key).is_some() {
+                        return Err(already_exists(project_name, new_name));
+                    }
+                    topic.name = new_name.to_string();
+                    staged.insert(old_key, None);
+                    staged.insert(new_key, Some(topic));
+                },
+                Change::RenameFile { .. } => {},
+                Change::MoveTopic { old_project_name, new_project_name, topic_name } => {
+                    let old_key = Wiki::topic_key(old_project_name, topic_name);
+                    let new_key = Wiki::topic_key(new_project_name, topic_name);
+                    let mut topic = current(&staged, &old_key).ok_or_else(|| not_found(old_project_name, topic_name))?;
+                    if new_key != old_key && current(&staged, &new_key).is_some() {
+                        return Err(already_exists(new_project_name, topic_name));
+                    }
+                    topic.project_name = new_project_name.to_string();
+                    staged.insert(old_key, None);
+                    staged.insert(new_key, Some(topic));
+                },
+                Change::EditContent { project_name, topic_name, old_content, new_content } => {
+                    let key = Wiki::topic_key(project_name, topic_name);
+                    let mut topic = current(&staged, &key).ok_or_else(|| not_found(project_name, topic_name))?;
+                    if &topic.content != old_content {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Content of {:?} changed since the change set was made.", topic_name)));
+                    }
+                    topic.set_content(new_content);
+                    staged.insert(key, Some(topic));
+                },
+                Change::CreateTopic { project_name, topic_name, content } => {
+                    let key = Wiki::topic_key(project_name, topic_name);
+                    if current(&staged, &key).is_some() {
+                        return Err(already_exists(project_name, topic_name));
+                    }
+                    let mut topic = Topic::new(project_name, topic_name, content);
+                    topic.links = import::parse_links(&topic.content);
+                    staged.insert(key, Some(topic));
+                },
+                Change::DeleteTopic { project_name, topic_name } => {
+                    let key = Wiki::topic_key(project_name, topic_name);
+                    current(&staged, &key).ok_or_else(|| not_found(project_name, topic_name))?;
+                    staged.insert(key, None);
+                },
+            }
+        }
+        for (key, topic) in staged {
+            match topic {
+                Some(topic) => { wiki.topics.insert(key, topic); },
+                None => { wiki.topics.remove(&key); },
+            }
+        }
+        wiki.resolve_attributes();
+        Ok(())
+    }
+
+    pub fn project_names(&self) -> BTreeSet<String> {
+        // Every project whose topic folder the change set touches, including both ends of a move.
+        let mut project_names = BTreeSet::new();
+        for change in self.changes.iter() {
+            match change {
+                Change::RenameTopic { project_name, .. }
+                | Change::RenameFile { project_name, .. }
+                | Change::EditContent { project_name, .. }
+                | Change::CreateTopic { project_name, .. }
+                | Change::DeleteTopic { project_name, .. } => {
+                    project_names.insert(project_name.clone());
+                },
+                Change::MoveTopic { old_project_name, new_project_name, .. } => {
+                    project_names.insert(old_project_name.clone());
+                    project_names.insert(new_project_name.clone());
+                },
+            }
+        }
+        project_names
+    }
+
+    pub fn plan_files(&self, wiki: &Wiki, path_project: &path::Path) -> io::Result<ChangePlan> {
+        // Use this for change sets that only touch one project.
+        self.plan_files_to(wiki, |_| Ok(path_project.to_path_buf()))
+    }
+
+    pub fn plan_files_by_project(&self, wiki: &Wiki, paths_project: &BTreeMap<String, path::PathBuf>) -> io::Result<ChangePlan> {
+        self.plan_files_to(wiki, |project_name| {
+            paths_project.get(project_name).cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No folder given for project {:?}.", project_name)))
+        })
+    }
+
+    fn plan_files_to<F>(&self, wiki: &Wiki, path_for_project: F) -> io::Result<ChangePlan>
+        where F: Fn(&str) -> io::Result<path::PathBuf>
+    {
+        // The wiki is the one the change set was made from, and gives the encoding and line
+        // endings each project's files were read with so they're written back the same way.
+        if !self.problems.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Change set \"{}\" has problems: {:?}", self.label, self.problems)));
+        }
+        let path_project = |project_name: &str| -> io::Result<path::PathBuf> {
+            let path_project = path_for_project(project_name)?;
+            if !path_project.is_absolute() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("The folder for project {:?} isn't an absolute path: {}", project_name, path_project.display())));
+            }
+            Ok(path_project)
+        };
+        let mut plan = ChangePlan::new(&self.label);
+        for change in self.changes.iter() {
+            match change {
+                Change::RenameTopic { project_name, old_name, new_name } => {
+                    // The topic's file is named for the topic, so it's renamed along with it.
+                    // RenameFile is for any other file in the project folder.
+                    let old_file_name = topic_name_to_file_name(old_name);
+                    let new_file_name = topic_name_to_file_name(new_name);
+                    if old_file_name != new_file_name {
+                        plan.push(FileOperation::Rename {
+                            path_source: path_project(project_name)?.join(old_file_name),
+                            path_dest: path_project(project_name)?.join(new_file_name),
+                        });
+                    }
+                },
+                Change::RenameFile { project_name, old_file_name, new_file_name } => {
+                    plan.push(FileOperation::Rename {
+                        path_source: path_project(project_name)?.join(old_file_name),
+                        path_dest: path_project(project_name)?.join(new_file_name),
+                    });
+                },
+                Change::MoveTopic { old_project_name, new_project_name, topic_name } => {
+                    let file_name = topic_name_to_file_name(topic_name);
+                    plan.push(FileOperation::Rename {
+                        path_source: path_project(old_project_name)?.join(&file_name),
+                        path_dest: path_project(new_project_name)?.join(&file_name),
+                    });
+                },
+                Change::EditContent { project_name, topic_name, new_content, .. } => {
+                    plan.folder_text_formats.insert(path_project(project_name)?, wiki.text_format(project_name));
+                    plan.push(FileOperation::Overwrite {
+                        path_dest: path_project(project_name)?.join(topic_name_to_file_name(topic_name)),
+                        content: new_content.to_string(),
+                    });
+                },
+                Change::CreateTopic { project_name, topic_name, content } => {
+                    plan.folder_text_formats.insert(path_project(project_name)?, wiki.text_format(project_name));
+                    plan.push(FileOperation::Create {
+                        path_dest: path_project(project_name)?.join(topic_name_to_file_name(topic_name)),
+                        content: content.to_string(),
+                    });
+                },
+                Change::DeleteTopic { project_name, topic_name } => {
+                    plan.push(FileOperation::Delete {
+                        path: path_project(project_name)?.join(topic_name_to_file_name(topic_name)),
+                    });
+                },
+            }
+        }
+        Ok(plan)
+    }
+}
+
+fn print_changed_lines(old_content: &str, new_content: &str) {
+    // This is only meant for previews of small in-place edits, so lines are compared by position
+    // rather than with a real diff.
+    let old_lines = old_content.lines().collect::<Vec<_>>();
+    let new_lines = new_content.lines().collect::<Vec<_>>();
+    for line_index in 0..std::cmp::max(old_lines.len(), new_lines.len()) {
+        let old_line = old_lines.get(line_index);
+        let new_line = new_lines.get(line_index);
+        if old_line != new_line {
+            if let Some(old_line) = old_line {
+                println!("      - {}", old_line);
+            }
+            if let Some(new_line) = new_line {
+                println!("      + {}", new_line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wiki() -> Wiki {
+        let mut wiki = Wiki::new();
+        wiki.add_topic(Topic::new("P", "Existing", "Text\n"));
+        wiki
+    }
+
+    #[test]
+    fn apply_all_or_nothing() {
+        let mut wiki = wiki();
+        let mut change_set = ChangeSet::new("Test");
+        change_set.push(Change::EditContent { project_name: "P".to_string(), topic_name: "Existing".to_string(),
+            old_content: "Text\n".to_string(), new_content: "New text\n".to_string() });
+        change_set.push(Change::CreateTopic { project_name: "P".to_string(), topic_name: "Ärger".to_string(), content: "A\n".to_string() });
+        // The same key as the topic created just before.
+        change_set.push(Change::CreateTopic { project_name: "P".to_string(), topic_name: "ärger".to_string(), content: "B\n".to_string() });
+        let error = change_set.apply(&mut wiki).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(wiki.topics.len(), 1);
+        assert_eq!(wiki.find_topic("P", "Existing").unwrap().content, "Text\n");
+
+        change_set.changes.pop();
+        change_set.apply(&mut wiki).unwrap();
+        assert_eq!(wiki.find_topic("P", "Existing").unwrap().content, "New text\n");
+        assert_eq!(wiki.find_topic("P", "ärger").unwrap().name, "Ärger");
+    }
+
+    #[test]
+    fn apply_follows_earlier_changes() {
+        // An edit after a rename is made under the new name, and a deleted topic can't be edited.
+        let mut wiki = wiki();
+        let mut change_set = ChangeSet::new("Test");
+        change_set.push(Change::RenameTopic { project_name: "P".to_string(), old_name: "Existing".to_string(), new_name: "Renamed".to_string() });
+        change_set.push(Change::EditContent { project_name: "P".to_string(), topic_name: "Renamed".to_string(),
+            old_content: "Text\n".to_string(), new_content: "New text\n".to_string() });
+        change_set.apply(&mut wiki).unwrap();
+        assert!(wiki.find_topic("P", "Existing").is_none());
+        assert_eq!(wiki.find_topic("P", "Renamed").unwrap().content, "New text\n");
+
+        let mut change_set = ChangeSet::new("Test");
+        change_set.push(Change::DeleteTopic { project_name: "P".to_string(), topic_name: "Renamed".to_string() });
+        change_set.push(Change::EditContent { project_name: "P".to_string(), topic_name: "Renamed".to_string(),
+            old_content: "New text\n".to_string(), new_content: "Newer text\n".to_string() });
+        assert_eq!(change_set.apply(&mut wiki).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(wiki.find_topic("P", "Renamed").is_some());
+    }
+
+    #[test]
+    fn rename_plans_file_rename() {
+        let wiki = wiki();
+        let change_set = wiki.rename_topic("P", "Existing", "Renamed");
+        let path_project = std::env::temp_dir().join("project");
+        let plan = change_set.plan_files(&wiki, &path_project).unwrap();
+        assert!(plan.operations.iter().any(|operation| match operation {
+            FileOperation::Rename { path_source, path_dest } => path_source == &path_project.join("Existing.TXT") && path_dest == &path_project.join("Renamed.TXT"),
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn plan_needs_every_project_folder() {
+        let mut change_set = ChangeSet::new("Test");
+        change_set.push(Change::MoveTopic { old_project_name: "P".to_string(), new_project_name: "Q".to_string(), topic_name: "Existing".to_string() });
+        assert_eq!(change_set.project_names().into_iter().collect::<Vec<_>>(), vec!["P", "Q"]);
+        let mut paths_project = BTreeMap::new();
+        paths_project.insert("P".to_string(), std::env::temp_dir().join("P"));
+        assert_eq!(change_set.plan_files_by_project(&wiki(), &paths_project).unwrap_err().kind(), io::ErrorKind::NotFound);
+        paths_project.insert("Q".to_string(), path::PathBuf::from("Q"));
+        assert_eq!(change_set.plan_files_by_project(&wiki(), &paths_project).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        paths_project.insert("Q".to_string(), std::env::temp_dir().join("Q"));
+        assert_eq!(change_set.plan_files_by_project(&wiki(), &paths_project).unwrap().operations.len(), 1);
+    }
+}
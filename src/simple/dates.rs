@@ -0,0 +1,243 @@
+use std::{fs, path};
+use std::collections::BTreeMap;
+use chrono::{DateTime, Local, NaiveDate};
+use crate::*;
+use super::change::{Change, ChangeSet};
+use super::model::{Topic, Wiki};
+use super::writer;
+
+pub const ATTRIBUTE_ADDED: &str = "Added";
+pub const ATTRIBUTE_ADDED_SOURCE: &str = "Added Source";
+
+pub enum AddedDateStrategy<'a> {
+    // The earliest Added date of any topic in the same project that links to this one.
+    EarliestInboundLink,
+    // The last-modified date of the topic's file, given the topic folder for each project.
+    FileModified(&'a BTreeMap<String, path::PathBuf>),
+    // The date of the earliest older export that has the topic. Each export is paired with the
+    // date it was made.
+    OlderExports(&'a [(NaiveDate, Wiki)]),
+}
+
+#[derive(Clone, Debug)]
+pub enum AddedDateSource {
+    InboundLink { topic_name: String },
+    FileModified { path_file: path::PathBuf },
+    OlderExport { export_date: NaiveDate },
+}
+
+#[derive(Clone, Debug)]
+pub struct DerivedAddedDate {
+    pub project_name: String,
+    pub topic_name: String,
+    pub added_date: NaiveDate,
+    pub source: AddedDateSource,
+}
+
+impl Wiki {
+    pub fn derive_added_dates(&self, strategies: &[AddedDateStrategy]) -> Vec<DerivedAddedDate> {
+        // For each topic with no Added date, try the strategies in order and take the first date
+        // found.
+        let earliest_inbound_links = self.earliest_inbound_links();
+        let mut derived = vec![];
+        for (key, topic) in self.topics.iter().filter(|(_, topic)| topic.added_date.is_none()) {
+            let found = strategies.iter().find_map(|strategy| {
+                match strategy {
+                    AddedDateStrategy::EarliestInboundLink => {
+                        earliest_inbound_links.get(key)
+                            .map(|(topic_name, added_date)| (*added_date, AddedDateSource::InboundLink { topic_name: topic_name.to_string() }))
+                    },
+                    AddedDateStrategy::FileModified(paths_project) => file_modified_date(paths_project, topic),
+                    AddedDateStrategy::OlderExports(exports) => {
+                        exports.iter()
+                            .filter(|(_, wiki)| wiki.find_topic(&topic.project_name, &topic.name).is_some())
+                            .map(|(export_date, _)| *export_date)
+                            .min()
+                            .map(|export_date| (export_date, AddedDateSource::OlderExport { export_date }))
+                    },
+                }
+            });
+            if let Some((added_date, source)) = found {
+                derived.push(DerivedAddedDate {
+                    project_name: topic.project_name.clone(),
+                    topic_name: topic.name.clone(),
+                    added_date,
+                    source,
+                });
+            }
+        }
+        derived
+    }
+
+    pub fn write_derived_added_dates(&self, derived: &[DerivedAddedDate]) -> ChangeSet {
+        // Set the Added attribute in each topic's attribute table, replacing a "***" placeholder
+        // row if there is one and creating the row or the whole table if not. An Added Source row
+        // next to it records where the date came from, so a derived date can be told apart from
+        // one that was entered by hand.
+        let mut change_set = ChangeSet::new(&format!("Write {} derived Added dates", derived.len()));
+        for one_derived in derived.iter() {
+            // The list may have been derived from an earlier load of the wiki.
+            let topic = match self.find_topic(&one_derived.project_name, &one_derived.topic_name) {
+                Some(topic) => topic,
+                None => {
+                    change_set.add_warning(&format!("No topic {:?} in project {:?}.", one_derived.topic_name, one_derived.project_name));
+                    continue;
+                }
+            };
+            if topic.added_date.is_some() {
+                change_set.add_warning(&format!("{:?} already has an Added date.", topic.name));
+                continue;
+            }
+            // Placeholder values are dropped when the attributes are parsed, so any value left
+            // here is one that isn't a valid date. Leave it for someone to fix by hand.
+            if let Some(values) = topic.attributes.get(ATTRIBUTE_ADDED).filter(|values| !values.is_empty()) {
+                change_set.add_warning(&format!("{:?} has an Added value that isn't a date: {:?}.", topic.name, values.join(", ")));
+                continue;
+            }
+            let value = one_derived.added_date.format("%Y%m%d").to_string();
+            let new_content = writer::set_attribute_values(&topic.content, ATTRIBUTE_ADDED, &[value]);
+            let new_content = writer::set_attribute_values(&new_content, ATTRIBUTE_ADDED_SOURCE, &[one_derived.source.description()]);
+            change_set.push(Change::EditContent {
+                project_name: topic.project_name.clone(),
+                topic_name: topic.name.clone(),
+                old_content: topic.content.clone(),
+                new_content,
+            });
+        }
+        change_set
+    }
+
+    fn earliest_inbound_links(&self) -> BTreeMap<String, (String, NaiveDate)> {
+        // The key is the key of the linked topic and the value is the name and Added date of the
+        // earliest dated topic that links to it.
+        let mut earliest_inbound_links: BTreeMap<String, (String, NaiveDate)> = BTreeMap::new();
+        for (key, linking_keys) in self.link_index() {
+            for linking_key in linking_keys.iter() {
+                let linking_topic = &self.topics[linking_key];
+                if let Some(added_date) = linking_topic.added_date {
+                    let is_earlier = earliest_inbound_links.get(&key).map_or(true, |(_, found_date)| added_date < *found_date);
+                    if is_earlier {
+                        earliest_inbound_links.insert(key.clone(), (linking_topic.name.clone(), added_date));
+                    }
+                }
+            }
+        }
+        earliest_inbound_links
+    }
+}
+
+impl AddedDateSource {
+    pub fn description(&self) -> String {
+        // This goes into the Added Source attribute value, so it avoids brackets and keeps only
+        // the file name rather than the full path.
+        match self {
+            AddedDateSource::InboundLink { topic_name } => format!("Inbound link from {}", topic_name),
+            AddedDateSource::FileModified { path_file } => format!("File date of {}", path_file.file_name().map_or(String::new(), |x| x.to_string_lossy().to_string())),
+            AddedDateSource::OlderExport { export_date } => format!("Export of {}", export_date),
+        }
+    }
+}
+
+impl DerivedAddedDate {
+    pub fn print_list(derived: &[DerivedAddedDate]) {
+        for one_derived in derived.iter() {
+            println!("{}: {}: {}: {}", one_derived.added_date, one_derived.project_name, one_derived.topic_name, one_derived.source.description());
+        }
+    }
+}
+
+fn file_modified_date(paths_project: &BTreeMap<String, path::PathBuf>, topic: &Topic) -> Option<(NaiveDate, AddedDateSource)> {
+    let path_file = paths_project.get(&topic.project_name)?.join(topic_name_to_file_name(&topic.name));
+    let modified = fs::metadata(&path_file).ok()?.modified().ok()?;
+    let added_date = DateTime::<Local>::from(modified).naive_local().date();
+    Some((added_date, AddedDateSource::FileModified { path_file }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn wiki(topics: &[(&str, &str, &str)]) -> Wiki {
+        let mut wiki = Wiki::new();
+        for (project_name, topic_name, content) in topics.iter() {
+            wiki.add_topic(Topic::new(project_name, topic_name, content));
+        }
+        wiki.resolve_attributes();
+        wiki
+    }
+
+    fn current_wiki() -> Wiki {
+        wiki(&[
+            ("P", "Early", "{|\n||Added||[[Added:=20200105]]||\n|}\n[[Linked]]\n"),
+            ("P", "Late", "{|\n||Added||[[Added:=20210105]]||\n|}\n[[Linked]] [[Exported]]\n"),
+            ("P", "Linked", "Text\n"),
+            ("P", "Exported", "Text\n"),
+            ("P", "Neither", "Text\n"),
+        ])
+    }
+
+    fn older_exports() -> Vec<(NaiveDate, Wiki)> {
+        vec![
+            (date(2019, 6, 1), wiki(&[("P", "Exported", ""), ("P", "Linked", "")])),
+            (date(2018, 6, 1), wiki(&[("P", "Exported", "")])),
+        ]
+    }
+
+    #[test]
+    fn strategy_order() {
+        let wiki = current_wiki();
+        let exports = older_exports();
+        let paths_project = BTreeMap::new();
+
+        let derived = wiki.derive_added_dates(&[AddedDateStrategy::FileModified(&paths_project), AddedDateStrategy::EarliestInboundLink, AddedDateStrategy::OlderExports(&exports)]);
+        let found = derived.iter().map(|one| (one.topic_name.as_str(), one.added_date, one.source.description())).collect::<Vec<_>>();
+        // There's no folder for the project so the file dates are skipped, and the inbound links
+        // come before the exports. "Exported" is only linked from "Late".
+        assert_eq!(found, vec![
+            ("Exported", date(2021, 1, 5), "Inbound link from Late".to_string()),
+            ("Linked", date(2020, 1, 5), "Inbound link from Early".to_string()),
+        ]);
+
+        // The earliest export that has the topic wins, whatever order the exports are in.
+        let derived = wiki.derive_added_dates(&[AddedDateStrategy::OlderExports(&exports), AddedDateStrategy::EarliestInboundLink]);
+        let found = derived.iter().map(|one| (one.topic_name.as_str(), one.added_date, one.source.description())).collect::<Vec<_>>();
+        assert_eq!(found, vec![
+            ("Exported", date(2018, 6, 1), "Export of 2018-06-01".to_string()),
+            ("Linked", date(2019, 6, 1), "Export of 2019-06-01".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn write_dates() {
+        let wiki = wiki(&[
+            ("P", "Placeholder", "{|\n||Added||[[Added:=***]]||\n|}\n"),
+            ("P", "No Row", "[[$CATEGORY:Books]]\n{|\n||Author||[[Author:=A]]||\n|}\n"),
+            ("P", "No Table", "[[$CATEGORY:Books]]\nText\n"),
+            ("P", "Dated", "{|\n||Added||[[Added:=20200105]]||\n|}\n"),
+            ("P", "Bad Date", "{|\n||Added||[[Added:=Sometime]]||\n|}\n"),
+        ]);
+        let derived = ["Placeholder", "No Row", "No Table", "Dated", "Bad Date", "Gone"].iter().map(|topic_name| DerivedAddedDate {
+            project_name: "P".to_string(),
+            topic_name: topic_name.to_string(),
+            added_date: date(2019, 6, 1),
+            source: AddedDateSource::OlderExport { export_date: date(2019, 6, 1) },
+        }).collect::<Vec<_>>();
+        let change_set = wiki.write_derived_added_dates(&derived);
+        let edited = change_set.changes.iter().filter_map(|change| match change {
+            Change::EditContent { topic_name, new_content, .. } => Some((topic_name.as_str(), new_content.as_str())),
+            _ => None,
+        }).collect::<Vec<_>>();
+        assert_eq!(edited, vec![
+            ("Placeholder", "{|\n||Added||[[Added:=20190601]]||\n||Added Source||[[Added Source:=Export of 2019-06-01]]||\n|}\n"),
+            ("No Row", "[[$CATEGORY:Books]]\n{|\n||Author||[[Author:=A]]||\n||Added||[[Added:=20190601]]||\n||Added Source||[[Added Source:=Export of 2019-06-01]]||\n|}\n"),
+            ("No Table", "[[$CATEGORY:Books]]\n\n{|\n||Added||[[Added:=20190601]]||\n||Added Source||[[Added Source:=Export of 2019-06-01]]||\n|}\nText\n"),
+        ]);
+        // The dated topic, the one with a bad value and the stale entry are each skipped with a
+        // warning.
+        assert_eq!(change_set.warnings.len(), 3);
+    }
+}
@@ -0,0 +1,431 @@
+use std::{io, path};
+use std::collections::{BTreeMap, BTreeSet};
+use crate::*;
+use super::attachments::AttachmentReference;
+use super::file_names::{self, FileNameMap, FileSystem};
+use super::images::{ImageAlignment, ImageReference, ImageSize};
+use super::model::{Topic, Wiki};
+use super::paths;
+use super::input::TextFormat;
+use super::plan::{ApplyReport, ChangePlan, ConflictHandling, FileOperation};
+use super::writer;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    // One ConnectedText .TXT file per topic, the same layout used by fix_file_names().
+    Text,
+    Markdown,
+    Html,
+}
+
+impl ExportFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_ref() {
+            "txt" | "text" => Some(ExportFormat::Text),
+            "md" | "markdown" => Some(ExportFormat::Markdown),
+            "html" => Some(ExportFormat::Html),
+            _ => None,
+        }
+    }
+
+    pub fn file_system(&self) -> Option<FileSystem> {
+        // Text files keep the names ConnectedText gives them so that it can import them again.
+        // Markdown is meant for an Obsidian vault, and HTML for any file system.
+        match self {
+            ExportFormat::Text => None,
+            ExportFormat::Markdown => Some(FileSystem::Obsidian),
+            ExportFormat::Html => Some(FileSystem::Windows),
+        }
+    }
+
+    pub fn extension(&self) -> &str {
+        match self {
+            ExportFormat::Text => ".TXT",
+            ExportFormat::Markdown => ".md",
+            ExportFormat::Html => ".html",
+        }
+    }
+
+    pub fn file_name(&self, topic_name: &str) -> String {
+        // The file name for a topic on its own. Use ExportNames to be sure that every topic in a
+        // project gets a different one.
+        match self.file_system() {
+            Some(file_system) => format!("{}{}", file_names::encode_topic_name(topic_name, file_system), self.extension()),
+            None => topic_name_to_file_name(topic_name),
+        }
+    }
+}
+
+pub struct ExportNames {
+    pub export_format: ExportFormat,
+    // Keyed by the lowercase project name.
+    projects: BTreeMap<String, FileNameMap>,
+}
+
+impl ExportNames {
+    pub fn new(export_format: ExportFormat) -> Self {
+        Self {
+            export_format,
+            projects: BTreeMap::new(),
+        }
+    }
+
+    pub fn from_wiki(wiki: &Wiki, export_format: ExportFormat) -> Self {
+        let mut names = Self::new(export_format);
+        let mut topic_names: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+        for topic in wiki.topics.values() {
+            topic_names.entry(topic.project_name.to_lowercase()).or_insert_with(|| { vec![] }).push(&topic.name);
+        }
+        for (project_key, topic_names) in topic_names {
+            names.add_project(&project_key, topic_names.into_iter());
+        }
+        names
+    }
+
+    pub fn add_project<'a, I>(&mut self, project_name: &str, topic_names: I)
+        where I: Iterator<Item = &'a str>
+    {
+        // Every topic name in the project, not just the ones being exported, so that each topic
+        // gets the same file name whichever way it's exported.
+        if let Some(file_system) = self.export_format.file_system() {
+            let map = FileNameMap::from_topic_names(file_system, self.export_format.extension(), topic_names);
+            self.projects.insert(project_name.to_lowercase(), map);
+        }
+    }
+
+    pub fn file_name(&self, project_name: &str, topic_name: &str) -> String {
+        match self.projects.get(&project_name.to_lowercase()) {
+            Some(map) => map.file_name(topic_name),
+            None => self.export_format.file_name(topic_name),
+        }
+    }
+}
+
+pub fn export_wiki(wiki: &Wiki, path_dest: &path::Path, export_format: ExportFormat) -> io::Result<usize> {
+    let plan = plan_export_wiki(wiki, path_dest, export_format);
+    plan.apply(ConflictHandling::Fail).map(|report| report.done)
+}
+
+pub fn plan_export_wiki(wiki: &Wiki, path_dest: &path::Path, export_format: ExportFormat) -> ChangePlan {
+    plan_export_where(wiki, path_dest, export_format, |_| true)
+}
+
+pub fn plan_export_topics(wiki: &Wiki, keys: &BTreeSet<String>, path_dest: &path::Path, export_format: ExportFormat) -> ChangePlan {
+    // Export only the topics with the given keys, such as the ones that changed since the last
+    // export.
+    plan_export_where(wiki, path_dest, export_format, |key| keys.contains(key))
+}
+
+fn plan_export_where<F>(wiki: &Wiki, path_dest: &path::Path, export_format: ExportFormat, include: F) -> ChangePlan
+    where F: Fn(&str) -> bool
+{
+    // Each project goes in its own subfolder of the destination folder. Files left from an earlier
+    // export, and two topics whose names give the same .TXT file name, are conflicts for the
+    // ConflictHandling the plan is applied with.
+    assert!(path_dest.is_absolute());
+    let mut plan = ChangePlan::new(&format!("Export to {:?}", export_format));
+    if export_format == ExportFormat::Text {
+        // Write the topic files the same way as the export file they came from, as long as all of
+        // the projects agree.
+        let mut text_formats = wiki.text_formats.values();
+        if let Some(text_format) = text_formats.next() {
+            if text_formats.all(|x| x == text_format) {
+                plan.text_format = *text_format;
+            }
+        }
+    }
+    // The file names come from every topic in the project, not just the ones being exported,
+    // so that a topic keeps the same file name and links to the others still work.
+    let names = ExportNames::from_wiki(wiki, export_format);
+    let mut paths_project: Vec<path::PathBuf> = vec![];
+    for (_, topic) in wiki.topics.iter().filter(|(key, _)| include(key)) {
+        plan_export_topic(&mut plan, &mut paths_project, topic, path_dest, &names);
+    }
+    plan
+}
+
+pub fn export_path(topic: &Topic, path_dest: &path::Path, names: &ExportNames) -> path::PathBuf {
+    path_dest.join(&topic.project_name).join(names.file_name(&topic.project_name, &topic.name))
+}
+
+pub fn export_topics<I>(topics: I, path_dest: &path::Path, names: &ExportNames, text_format: TextFormat, conflict_handling: ConflictHandling) -> io::Result<ApplyReport>
+    where I: Iterator<Item = io::Result<Topic>>
+{
+    // Export topics as they come from a stream::TopicStream without holding them all in memory.
+    // Each topic gets its own small plan, so with ConflictHandling::Fail the export stops at the
+    // first conflict rather than before anything is written. The names should already have every
+    // topic name from a first pass with stream::topic_names(), so the files are named the same as
+    // in a full export.
+    assert!(path_dest.is_absolute());
+    let export_format = names.export_format;
+    let mut report = ApplyReport::default();
+    let mut paths_project: Vec<path::PathBuf> = vec![];
+    for topic in topics {
+        let topic = topic?;
+        let mut plan = ChangePlan::new(&format!("Export {:?} to {:?}", topic.name, export_format));
+        if export_format == ExportFormat::Text {
+            plan.text_format = text_format;
+        }
+        plan_export_topic(&mut plan, &mut paths_project, &topic, path_dest, names);
+        report.append(plan.apply(conflict_handling)?);
+    }
+    Ok(report)
+}
+
+fn plan_export_topic(plan: &mut ChangePlan, paths_project: &mut Vec<path::PathBuf>, topic: &Topic, path_dest: &path::Path, names: &ExportNames) {
+    let path_project = path_dest.join(&topic.project_name);
+    if !path_project.is_dir() && !paths_project.contains(&path_project) {
+        plan.push(FileOperation::CreateDir { path: path_project.clone() });
+        paths_project.push(path_project.clone());
+    }
+    let path_file = export_path(topic, path_dest, names);
+    let content = render_topic(topic, names);
+    plan.push(FileOperation::Create { path_dest: path_file, content });
+}
+
+pub fn render_topic(topic: &Topic, names: &ExportNames) -> String {
+    match names.export_format {
+        ExportFormat::Text => topic.content.clone(),
+        ExportFormat::Markdown => render_markdown(topic, names),
+        ExportFormat::Html => render_html(topic, names),
+    }
+}
+
+fn render_markdown(topic: &Topic, names: &ExportNames) -> String {
+    let mut s = format!("# {}\n\n", topic.name);
+    for line in topic.content.lines() {
+        let trimmed = line.trim();
+        if trimmed == "{|" || trimmed == "|}" {
+            continue;
+        }
+        if writer::is_category_line(trimmed) {
+            s.push_str(&format!("Category: {}\n", topic.category.as_deref().unwrap_or("")));
+        } else if let Some((attr_name, values)) = writer::parse_attribute_row(trimmed) {
+            s.push_str(&format!("- **{}**: {}\n", attr_name, values.join(", ")));
+        } else if let Some((depth, heading)) = writer::heading_depth(trimmed) {
+            s.push_str(&format!("{} {}\n", "#".repeat(depth + 1), render_inline(topic, &heading, names)));
+        } else if trimmed.starts_with("*") {
+            s.push_str(&format!("- {}\n", render_inline(topic, trimmed.trim_start_matches('*').trim(), names)));
+        } else {
+            s.push_str(&format!("{}\n", render_inline(topic, line, names)));
+        }
+    }
+    s
+}
+
+fn render_html(topic: &Topic, names: &ExportNames) -> String {
+    let mut s = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n",
+        escape_html(&topic.name), escape_html(&topic.name));
+    let mut in_list = false;
+    let mut in_table = false;
+    for line in topic.content.lines() {
+        let trimmed = line.trim();
+        let is_list_item = trimmed.starts_with("*");
+        let attribute_row = writer::parse_attribute_row(trimmed);
+        if in_list && !is_list_item {
+            s.push_str("</ul>\n");
+            in_list = false;
+        }
+        if in_table && attribute_row.is_none() {
+            s.push_str("</table>\n");
+            in_table = false;
+        }
+        if trimmed == "{|" || trimmed == "|}" {
+            continue;
+        }
+        if writer::is_category_line(trimmed) {
+            s.push_str(&format!("<p class=\"category\">{}</p>\n", escape_html(topic.category.as_deref().unwrap_or(""))));
+        } else if let Some((attr_name, values)) = attribute_row {
+            if !in_table {
+                s.push_str("<table class=\"attributes\">\n");
+                in_table = true;
+            }
+            s.push_str(&format!("<tr><th>{}</th><td>{}</td></tr>\n", escape_html(&attr_name), escape_html(&values.join(", "))));
+        } else if let Some((depth, heading)) = writer::heading_depth(trimmed) {
+            let level = std::cmp::min(depth + 1, 6);
+            s.push_str(&format!("<h{}>{}</h{}>\n", level, render_inline(topic, &heading, names), level));
+        } else if is_list_item {
+            if !in_list {
+                s.push_str("<ul>\n");
+                in_list = true;
+            }
+            s.push_str(&format!("<li>{}</li>\n", render_inline(topic, trimmed.trim_start_matches('*').trim(), names)));
+        } else if !trimmed.is_empty() {
+            s.push_str(&format!("<p>{}</p>\n", render_inline(topic, line, names)));
+        }
+    }
+    if in_list {
+        s.push_str("</ul>\n");
+    }
+    if in_table {
+        s.push_str("</table>\n");
+    }
+    s.push_str("</body>\n</html>\n");
+    s
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;").replace("\"", "&quot;")
+}
+
+fn render_inline(topic: &Topic, text: &str, names: &ExportNames) -> String {
+    // Convert everything in double square brackets and escape the text around it.
+    let export_format = names.export_format;
+    let text_part = |x: &str| if export_format == ExportFormat::Html { escape_html(x) } else { x.to_string() };
+    let mut s = String::new();
+    let mut remaining = text;
+    while let Some(start) = remaining.find("[[") {
+        let after_start = &remaining[start + 2..];
+        let end = match after_start.find("]]") {
+            Some(end) => end,
+            None => break,
+        };
+        s.push_str(&text_part(&remaining[..start]));
+        s.push_str(&render_entry(topic, &after_start[..end], names));
+        remaining = &after_start[end + 2..];
+    }
+    s.push_str(&text_part(remaining));
+    s
+}
+
+fn render_entry(topic: &Topic, entry: &str, names: &ExportNames) -> String {
+    let html = names.export_format == ExportFormat::Html;
+    if let Some(url) = entry.strip_prefix("$URL:") {
+        let (url, label) = match url.split_once("|") {
+            Some((url, label)) => (url.trim(), label.trim()),
+            None => (url.trim(), url.trim()),
+        };
+        return if html {
+            format!("<a href=\"{}\">{}</a>", escape_html(url), escape_html(label))
+        } else {
+            format!("[{}]({})", label, url)
+        };
+    }
+    if let Some(image) = entry.strip_prefix("$IMG:") {
+        return render_image(topic, &ImageReference::parse(image), names);
+    }
+    if let Some(file) = entry.strip_prefix("$FILE:") {
+        // Relative to the project folder, where attachments::plan_copy_attachments() puts them.
+        let attachment = AttachmentReference::parse(file);
+        let href = if attachment.is_absolute() {
+            format!("file:///{}", attachment.reference.replace("\\", "/").trim_start_matches('/'))
+        } else {
+            paths::normalize_reference(&attachment.reference).replace("%", "%25").replace("#", "%23")
+        };
+        let label = attachment.label();
+        return if html {
+            format!("<a href=\"{}\">{}</a>", escape_html(&href), escape_html(&label))
+        } else {
+            format!("[{}]({})", label, href.replace(" ", "%20"))
+        };
+    }
+    if entry.starts_with("$") {
+        // Other ConnectedText commands such as $CATEGORY don't produce any text.
+        return "".to_string();
+    }
+    if let Some((_, value)) = entry.split_once(":=") {
+        return if html { escape_html(value) } else { value.to_string() };
+    }
+    let (link, label) = match entry.split_once("|") {
+        Some((link, label)) => (link.trim(), Some(label.trim())),
+        None => (entry.trim(), None),
+    };
+    let (topic_name, section_name) = match link.split_once("#") {
+        Some((topic_name, section_name)) => (topic_name.trim(), Some(section_name.trim())),
+        None => (link, None),
+    };
+    let label = label.unwrap_or(link);
+    let href = topic_href(topic, topic_name, section_name, names);
+    if html {
+        format!("<a href=\"{}\">{}</a>", escape_html(&href), escape_html(label))
+    } else {
+        format!("[{}]({})", label, href.replace(" ", "%20"))
+    }
+}
+
+fn topic_href(topic: &Topic, topic_name: &str, section_name: Option<&str>, names: &ExportNames) -> String {
+    // A file name may contain "%" or "#", which would otherwise be read as part of the URL.
+    let mut href = if topic_name.is_empty() {
+        "".to_string()
+    } else {
+        names.file_name(&topic.project_name, topic_name).replace("%", "%25").replace("#", "%23")
+    };
+    if let Some(section_name) = section_name {
+        href.push_str(&format!("#{}", section_name));
+    }
+    href
+}
+
+fn render_image(topic: &Topic, image_reference: &ImageReference, names: &ExportNames) -> String {
+    // Markdown has no way to size or align an image, so in that case it gets the same HTML tag,
+    // which Markdown allows and Obsidian shows.
+    let file = paths::normalize_reference(&image_reference.reference);
+    let caption = image_reference.caption.as_deref().unwrap_or("");
+    let href = image_reference.link.as_ref().map(|link| {
+        if link.contains("://") {
+            link.clone()
+        } else {
+            let (topic_name, section_name) = match link.split_once("#") {
+                Some((topic_name, section_name)) => (topic_name.trim(), Some(section_name.trim())),
+                None => (link.as_str(), None),
+            };
+            topic_href(topic, topic_name, section_name, names)
+        }
+    });
+    let is_plain = image_reference.size == ImageSize::Original && image_reference.alignment == ImageAlignment::None;
+    if names.export_format == ExportFormat::Markdown && is_plain {
+        let title = if caption.is_empty() { "".to_string() } else { format!(" \"{}\"", caption.replace("\"", "'")) };
+        let image = format!("![{}]({}{})", caption, file.replace(" ", "%20"), title);
+        return match href {
+            Some(href) => format!("[{}]({})", image, href.replace(" ", "%20")),
+            None => image,
+        };
+    }
+
+    let mut attributes = format!("src=\"{}\" alt=\"{}\"", escape_html(&file), escape_html(caption));
+    let mut style = vec![];
+    match image_reference.size {
+        ImageSize::Original => {},
+        // A percentage of the image's own size rather than of the page. The box around the image
+        // shrinks to fit the image's own width, so a percentage width is taken of that.
+        ImageSize::Scale(percent) => style.push(format!("width: {}%", percent)),
+        ImageSize::Pixels { width, height } => {
+            attributes.push_str(&format!(" width=\"{}\"", width));
+            if let Some(height) = height {
+                attributes.push_str(&format!(" height=\"{}\"", height));
+            }
+        },
+    }
+    let is_boxed = !caption.is_empty() || matches!(image_reference.size, ImageSize::Scale(_));
+    let alignment = match image_reference.alignment {
+        ImageAlignment::None => None,
+        ImageAlignment::Left => Some("float: left"),
+        ImageAlignment::Right => Some("float: right"),
+        ImageAlignment::Center => Some("display: block; margin: 0 auto"),
+    };
+    // With a box around the image the alignment goes on the box instead.
+    if let (Some(alignment), false) = (alignment, is_boxed) {
+        style.push(alignment.to_string());
+    }
+    if !style.is_empty() {
+        attributes.push_str(&format!(" style=\"{}\"", style.join("; ")));
+    }
+    let mut s = format!("<img {}>", attributes);
+    if let Some(href) = href {
+        s = format!("<a href=\"{}\">{}</a>", escape_html(&href), s);
+    }
+    if is_boxed {
+        // A <figure> can't go inside the <p> the line is in. A table shrinks to fit like an
+        // inline block but can still be centred with its margins.
+        let style = match image_reference.alignment {
+            ImageAlignment::Center => "display: table; margin: 0 auto".to_string(),
+            _ => match alignment {
+                Some(alignment) => format!("display: inline-block; {}", alignment),
+                None => "display: inline-block".to_string(),
+            },
+        };
+        let caption = if caption.is_empty() { "".to_string() } else { format!("<span class=\"caption\">{}</span>", escape_html(caption)) };
+        s = format!("<span class=\"image\" style=\"{}\">{}{}</span>", style, s, caption);
+    }
+    s
+}
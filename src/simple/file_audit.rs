@@ -0,0 +1,89 @@
+use std::path;
+use std::collections::BTreeMap;
+use super::model::Topic;
+use super::paths;
+
+// What the image and attachment audits have in common: the references that point at a file
+// that's missing, outside the folder or spelled with different case, the files that nothing
+// refers to or that have the same content, and how much each topic refers to.
+
+#[derive(Clone, Debug, Default)]
+pub struct FileAudit {
+    // The folder the references are relative to. Paths are printed relative to it.
+    pub path_folder: path::PathBuf,
+    // The topic label and the reference.
+    pub missing: Vec<(String, String)>,
+    pub outside_folder: Vec<(String, String)>,
+    pub case_mismatches: Vec<(String, String, path::PathBuf)>,
+    // Files that no topic refers to.
+    pub orphans: Vec<path::PathBuf>,
+    // Groups of files with the same content.
+    pub duplicates: Vec<Vec<path::PathBuf>>,
+    // The number of files each topic refers to that exist and their total size in bytes.
+    pub size_per_topic: BTreeMap<String, (usize, u64)>,
+}
+
+impl FileAudit {
+    pub fn new(path_folder: &path::Path) -> Self {
+        Self { path_folder: path_folder.to_path_buf(), ..Self::default() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.outside_folder.is_empty() && self.case_mismatches.is_empty()
+            && self.orphans.is_empty() && self.duplicates.is_empty()
+    }
+
+    pub fn set_duplicates<I>(&mut self, files: I)
+        where I: Iterator<Item = (u64, u64, path::PathBuf)>
+    {
+        // Each file's length, content hash and path.
+        let mut files_by_content: BTreeMap<(u64, u64), Vec<path::PathBuf>> = BTreeMap::new();
+        for (len, content_hash, path_file) in files {
+            files_by_content.entry((len, content_hash)).or_insert_with(|| { vec![] }).push(path_file);
+        }
+        self.duplicates = files_by_content.into_iter()
+            .map(|(_, paths)| paths)
+            .filter(|paths| paths.len() > 1)
+            .collect();
+    }
+
+    pub fn print(&self, kind: &str, folder_description: &str) {
+        // kind is what's being audited, such as "image", and folder_description is where the
+        // references should point, such as "the Images folder".
+        for (topic_label, reference) in self.missing.iter() {
+            println!("Missing: {}: {}", topic_label, reference);
+        }
+        for (topic_label, reference) in self.outside_folder.iter() {
+            println!("Outside {}: {}: {}", folder_description, topic_label, reference);
+        }
+        for (topic_label, reference, path_file) in self.case_mismatches.iter() {
+            println!("Different case: {}: {} is {}", topic_label, reference, self.relative(path_file));
+        }
+        for path_file in self.orphans.iter() {
+            println!("Not referenced: {}", self.relative(path_file));
+        }
+        for paths_file in self.duplicates.iter() {
+            let names: Vec<String> = paths_file.iter().map(|x| self.relative(x)).collect();
+            println!("Same content: {}", names.join(", "));
+        }
+        let mut sizes: Vec<(&String, &(usize, u64))> = self.size_per_topic.iter().collect();
+        sizes.sort_by(|a, b| (b.1).1.cmp(&(a.1).1).then(a.0.cmp(b.0)));
+        println!("\nLargest topics by {} size:", kind);
+        for (topic_label, (count, size)) in sizes.iter().take(20) {
+            println!("{:>12} bytes in {:>3} {}(s): {}", size, count, kind, topic_label);
+        }
+    }
+
+    pub fn summary(&self, folder_description: &str) -> String {
+        format!("{} missing, {} outside {}, {} with different case, {} not referenced, {} group(s) with the same content",
+            self.missing.len(), self.outside_folder.len(), folder_description, self.case_mismatches.len(), self.orphans.len(), self.duplicates.len())
+    }
+
+    pub fn relative(&self, path_file: &path::Path) -> String {
+        paths::normalize_reference(&path_file.strip_prefix(&self.path_folder).unwrap_or(path_file).to_string_lossy())
+    }
+}
+
+pub fn topic_label(topic: &Topic) -> String {
+    format!("{}: {}", topic.project_name, topic.name)
+}
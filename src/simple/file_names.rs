@@ -0,0 +1,319 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+// Turning topic names into file names that can be turned back into the same topic names.
+// topic_name_to_file_name() replaces every character Windows doesn't allow with "_", so "A/B" and
+// "A:B" both become "A_B.TXT" and there's no telling which topic a file came from. Here each
+// character that can't be used is written as "~" and its two-digit hex code instead, as is "~"
+// itself, so "A/B" becomes "A~2FB" and "A:B" becomes "A~3AB". ConnectedText's own .TXT files still
+// use topic_name_to_file_name() since that's what it expects to read.
+
+const ESCAPE: char = '~';
+
+// Added to the end of a file name that had to be changed to keep it unique, as in "Name~~2". An
+// escaped character is always "~" followed by two hex digits, so "~~" can't come from a topic name.
+const SUFFIX_UNIQUE: &str = "~~";
+
+// The longest file name most file systems allow, in bytes.
+const MAX_FILE_NAME_LEN: usize = 255;
+
+const WINDOWS_RESERVED_NAMES: [&str; 22] = ["CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4",
+    "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7",
+    "LPT8", "LPT9"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileSystem {
+    Windows,
+    Posix,
+    // A vault that may be synced to Windows, with the characters that have a meaning in Obsidian's
+    // links escaped as well.
+    Obsidian,
+}
+
+impl FileSystem {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_ref() {
+            "windows" => Some(FileSystem::Windows),
+            "posix" | "linux" | "unix" => Some(FileSystem::Posix),
+            "obsidian" => Some(FileSystem::Obsidian),
+            _ => None,
+        }
+    }
+
+    pub fn is_case_sensitive(&self) -> bool {
+        *self == FileSystem::Posix
+    }
+
+    fn must_escape(&self, c: char, is_first: bool) -> bool {
+        // Only ASCII control characters, since decode_file_stem() only turns codes below 0x80
+        // back into characters.
+        if c == ESCAPE || c == '/' || c.is_ascii_control() {
+            return true;
+        }
+        // A name starting with "." is hidden on POSIX and in Obsidian.
+        if c == '.' && is_first && *self != FileSystem::Windows {
+            return true;
+        }
+        match self {
+            FileSystem::Windows => "<>:\"\\|?*".contains(c),
+            FileSystem::Posix => false,
+            FileSystem::Obsidian => "<>:\"\\|?*#^[]".contains(c),
+        }
+    }
+}
+
+pub fn encode_topic_name(topic_name: &str, file_system: FileSystem) -> String {
+    // The file name without an extension. This alone is always reversible, but two topic names
+    // can still end up with the same file name on a file system that ignores case, so use
+    // FileNameMap for a whole project.
+    let mut s = String::new();
+    for (index, c) in topic_name.chars().enumerate() {
+        if file_system.must_escape(c, index == 0) {
+            s.push_str(&escape_char(c));
+        } else {
+            s.push(c);
+        }
+    }
+    if file_system != FileSystem::Posix {
+        // Windows won't create a file called CON or CON.txt, so escape the last character of the
+        // reserved part to get CO~4E.
+        let base = s.split('.').next().unwrap();
+        if WINDOWS_RESERVED_NAMES.contains(&base.to_uppercase().as_str()) {
+            let last = base.chars().last().unwrap();
+            s = format!("{}{}{}", &base[..base.len() - last.len_utf8()], escape_char(last), &s[base.len()..]);
+        }
+    }
+    s
+}
+
+pub fn decode_file_stem(file_stem: &str) -> String {
+    // The reverse of encode_topic_name(). Anything after "~~" is the suffix added to keep the
+    // name unique. A "~" that isn't followed by two hex digits is kept as it is, so a file
+    // that wasn't named this way comes back unchanged.
+    let file_stem = match file_stem.find(SUFFIX_UNIQUE) {
+        Some(position) => &file_stem[..position],
+        None => file_stem,
+    };
+    let mut s = String::new();
+    let mut remaining = file_stem;
+    while let Some(position) = remaining.find(ESCAPE) {
+        s.push_str(&remaining[..position]);
+        let after = &remaining[position + 1..];
+        match after.get(..2).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(code) if code < 0x80 => {
+                s.push(code as char);
+                remaining = &after[2..];
+            },
+            _ => {
+                s.push(ESCAPE);
+                remaining = after;
+            },
+        }
+    }
+    s.push_str(remaining);
+    s
+}
+
+pub fn topic_name_from_file_name(file_name: &str) -> String {
+    // Exact unless the name was shortened to fit MAX_FILE_NAME_LEN, in which case only the
+    // FileNameMap that made it knows the full topic name.
+    let file_stem = match file_name.rfind('.') {
+        Some(position) if position > 0 => &file_name[..position],
+        _ => file_name,
+    };
+    decode_file_stem(file_stem)
+}
+
+fn escape_char(c: char) -> String {
+    // Only ASCII characters are ever escaped.
+    format!("{}{:02X}", ESCAPE, c as u32)
+}
+
+#[derive(Clone, Debug)]
+pub struct FileNameMap {
+    pub file_system: FileSystem,
+    // Including the dot, as in ".md".
+    pub extension: String,
+    // Keyed by the lowercase topic name, since links don't always match the topic name's case.
+    file_names: BTreeMap<String, String>,
+    topic_names: BTreeMap<String, String>,
+}
+
+impl FileNameMap {
+    pub fn new(file_system: FileSystem, extension: &str) -> Self {
+        Self {
+            file_system,
+            extension: extension.to_string(),
+            file_names: BTreeMap::new(),
+            topic_names: BTreeMap::new(),
+        }
+    }
+
+    pub fn from_topic_names<'a, I>(file_system: FileSystem, extension: &str, topic_names: I) -> Self
+        where I: Iterator<Item = &'a str>
+    {
+        // The names are for one project, since each project has its own folder. They're added in
+        // sorted order so that the same topics always get the same file names, whatever order
+        // they came in.
+        let mut map = Self::new(file_system, extension);
+        let topic_names: BTreeSet<&str> = topic_names.collect();
+        for topic_name in topic_names {
+            map.add(topic_name);
+        }
+        map
+    }
+
+    pub fn add(&mut self, topic_name: &str) -> String {
+        // Returns the file name for the topic, giving it one if it doesn't have one yet. When the
+        // plain file name is taken, or too long, "~~2", "~~3" and so on are added until it's
+        // unique.
+        if let Some(file_name) = self.file_names.get(&topic_name.trim().to_lowercase()) {
+            return file_name.clone();
+        }
+        let file_stem = encode_topic_name(topic_name, self.file_system);
+        let max_stem_len = MAX_FILE_NAME_LEN - self.extension.len() - SUFFIX_UNIQUE.len() - 4;
+        let (file_stem, is_shortened) = if file_stem.len() + self.extension.len() > MAX_FILE_NAME_LEN {
+            (shorten(&file_stem, max_stem_len), true)
+        } else {
+            (file_stem, false)
+        };
+        let mut number = if is_shortened { 2 } else { 1 };
+        let file_name = loop {
+            let file_name = if number == 1 {
+                format!("{}{}", file_stem, self.extension)
+            } else {
+                format!("{}{}{}{}", file_stem, SUFFIX_UNIQUE, number, self.extension)
+            };
+            if !self.topic_names.contains_key(&self.lookup_key(&file_name)) {
+                break file_name;
+            }
+            number += 1;
+        };
+        self.topic_names.insert(self.lookup_key(&file_name), topic_name.to_string());
+        self.file_names.insert(topic_name.trim().to_lowercase(), file_name.clone());
+        file_name
+    }
+
+    pub fn file_name(&self, topic_name: &str) -> String {
+        // A topic that isn't in the map, such as the target of a broken link, gets the plain file
+        // name.
+        match self.file_names.get(&topic_name.trim().to_lowercase()) {
+            Some(file_name) => file_name.clone(),
+            None => format!("{}{}", encode_topic_name(topic_name, self.file_system), self.extension),
+        }
+    }
+
+    pub fn topic_name(&self, file_name: &str) -> String {
+        match self.topic_names.get(&self.lookup_key(file_name)) {
+            Some(topic_name) => topic_name.clone(),
+            None => topic_name_from_file_name(file_name),
+        }
+    }
+
+    fn lookup_key(&self, file_name: &str) -> String {
+        if self.file_system.is_case_sensitive() { file_name.to_string() } else { file_name.to_lowercase() }
+    }
+}
+
+fn shorten(file_stem: &str, max_len: usize) -> String {
+    // Cut at a character boundary without splitting an escaped character.
+    let mut end = 0;
+    let mut chars = file_stem.char_indices().peekable();
+    while let Some((position, c)) = chars.next() {
+        let len = if c == ESCAPE { 3 } else { c.len_utf8() };
+        if position + len > max_len {
+            break;
+        }
+        if c == ESCAPE {
+            chars.next();
+            chars.next();
+        }
+        end = position + len;
+    }
+    file_stem[..end].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE_SYSTEMS: [FileSystem; 3] = [FileSystem::Windows, FileSystem::Posix, FileSystem::Obsidian];
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let topic_names = ["Plain", "A/B", "A:B", "100% ~ done", "~7E", ".hidden", "CON", "con.txt", "Lpt1",
+            "Tab\there", "Bell\u{7}", "Next\u{85}Line", "Café", "Links [[#^x]]", "Why? <because>"];
+        for file_system in FILE_SYSTEMS.iter() {
+            for topic_name in topic_names.iter() {
+                let file_stem = encode_topic_name(topic_name, *file_system);
+                assert_eq!(decode_file_stem(&file_stem), *topic_name, "{:?} {:?}", file_system, file_stem);
+            }
+        }
+    }
+
+    #[test]
+    fn encode_escapes() {
+        assert_eq!(encode_topic_name("A/B", FileSystem::Posix), "A~2FB");
+        assert_eq!(encode_topic_name("A:B", FileSystem::Windows), "A~3AB");
+        assert_eq!(encode_topic_name("A:B", FileSystem::Posix), "A:B");
+        assert_eq!(encode_topic_name("~", FileSystem::Posix), "~7E");
+        assert_eq!(encode_topic_name(".hidden", FileSystem::Posix), "~2Ehidden");
+        assert_eq!(encode_topic_name(".hidden", FileSystem::Windows), ".hidden");
+        assert_eq!(encode_topic_name("CON", FileSystem::Windows), "CO~4E");
+        assert_eq!(encode_topic_name("con.txt", FileSystem::Obsidian), "co~6E.txt");
+        assert_eq!(encode_topic_name("CON", FileSystem::Posix), "CON");
+        assert_eq!(encode_topic_name("A#B^C", FileSystem::Obsidian), "A~23B~5EC");
+        // C1 controls are left alone since only ASCII is escaped.
+        assert_eq!(encode_topic_name("A\u{85}B", FileSystem::Windows), "A\u{85}B");
+        assert_eq!(encode_topic_name("A\tB", FileSystem::Windows), "A~09B");
+    }
+
+    #[test]
+    fn decode_leaves_other_names_alone() {
+        assert_eq!(decode_file_stem("No escapes"), "No escapes");
+        assert_eq!(decode_file_stem("a~zz"), "a~zz");
+        assert_eq!(decode_file_stem("a~"), "a~");
+        assert_eq!(decode_file_stem("a~E9"), "a~E9");
+        assert_eq!(decode_file_stem("Name~~2"), "Name");
+        assert_eq!(topic_name_from_file_name("A~2FB.md"), "A/B");
+        assert_eq!(topic_name_from_file_name(".md"), ".md");
+    }
+
+    #[test]
+    fn file_name_map_round_trip() {
+        let topic_names = ["A/B", "A:B", "a/b", "Topic", "Other"];
+        for file_system in FILE_SYSTEMS.iter() {
+            let map = FileNameMap::from_topic_names(*file_system, ".md", topic_names.iter().copied());
+            // Topic names that differ only in case are the same topic.
+            assert_eq!(map.file_name("a/b"), map.file_name("A/B"));
+            assert_eq!(map.file_name(" topic "), map.file_name("Topic"));
+            for topic_name in ["A/B", "A:B", "Topic", "Other"].iter() {
+                let file_name = map.file_name(topic_name);
+                assert!(file_name.ends_with(".md"));
+                assert_eq!(map.topic_name(&file_name), *topic_name);
+            }
+            if !file_system.is_case_sensitive() {
+                assert_eq!(map.topic_name(&map.file_name("Topic").to_uppercase()), "Topic");
+            }
+        }
+    }
+
+    #[test]
+    fn file_name_map_long_names() {
+        let prefix = "x".repeat(300);
+        let topic_names = [format!("{}1", prefix), format!("{}2", prefix)];
+        let map = FileNameMap::from_topic_names(FileSystem::Windows, ".md", topic_names.iter().map(|x| x.as_str()));
+        let file_names = topic_names.iter().map(|x| map.file_name(x)).collect::<Vec<_>>();
+        assert_ne!(file_names[0], file_names[1]);
+        for (topic_name, file_name) in topic_names.iter().zip(file_names.iter()) {
+            assert!(file_name.len() <= MAX_FILE_NAME_LEN);
+            assert_eq!(map.topic_name(file_name), *topic_name);
+        }
+    }
+
+    #[test]
+    fn file_name_map_unknown_topic() {
+        let map = FileNameMap::from_topic_names(FileSystem::Windows, ".md", ["Known"].iter().copied());
+        assert_eq!(map.file_name("Not/Known"), "Not~2FKnown.md");
+        assert_eq!(map.topic_name("Not~2FKnown.md"), "Not/Known");
+    }
+}
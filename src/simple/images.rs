@@ -0,0 +1,302 @@
+use std::{fs, io, path};
+use std::collections::BTreeMap;
+use util::parse;
+use super::cache;
+use super::file_audit::{self, FileAudit};
+use super::model::Wiki;
+use super::paths;
+use super::plan::{ChangePlan, FileOperation};
+
+// Checking the $IMG references in the topics against the files in a project's Images folder.
+// References are matched to files ignoring case, since that's how they worked in ConnectedText on
+// Windows, but a difference in case is reported because it breaks on other file systems and in
+// exported HTML.
+
+// The options after the file name are separated by "|", can come in any order and can be left out:
+//   Size:      "50%" of the image's own size, "200px" wide, or "200x150" pixels.
+//   Alignment: NONE, LEFT, RIGHT or CENTER, as ConnectedText writes them in [[$IMG:file.png|100%|NONE]].
+//   Link:      "link:" followed by a topic name, a topic name and "#section", or a URL. This isn't
+//              part of ConnectedText, which shows it as the caption, but lets an exported image
+//              link somewhere, as in [[$IMG:Images\Cover.jpg|50%|RIGHT|The cover|link:Book Covers]].
+//   Caption:   the first option that isn't one of the above. Any later ones are ignored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageReference {
+    // As written in the topic, such as "Images\Cover.jpg".
+    pub reference: String,
+    pub size: ImageSize,
+    pub alignment: ImageAlignment,
+    pub caption: Option<String>,
+    // A topic name or a URL.
+    pub link: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageSize {
+    Original,
+    // A percentage of the image's own size, as in "50%".
+    Scale(u32),
+    // "200px" or "200x150".
+    Pixels { width: u32, height: Option<u32> },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageAlignment {
+    None,
+    Left,
+    Right,
+    Center,
+}
+
+#[derive(Clone, Debug)]
+pub struct ImageFile {
+    pub path: path::PathBuf,
+    // Relative to the Images folder with "/" between the parts, as in "Books/Cover.jpg".
+    pub file_name: String,
+    pub len: u64,
+    pub content_hash: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ImageAudit {
+    pub files: Vec<ImageFile>,
+    // Relative to the Images folder.
+    pub audit: FileAudit,
+}
+
+impl ImageReference {
+    pub fn parse(entry: &str) -> Self {
+        // The text between "[[$IMG:" and "]]". Anything that isn't a size, an alignment or a link
+        // is the caption.
+        let mut parts = entry.split("|").map(|x| x.trim());
+        let mut image_reference = Self {
+            reference: parts.next().unwrap().to_string(),
+            size: ImageSize::Original,
+            alignment: ImageAlignment::None,
+            caption: None,
+            link: None,
+        };
+        for part in parts.filter(|x| !x.is_empty()) {
+            if let Some(size) = ImageSize::parse(part) {
+                image_reference.size = size;
+            } else if let Some(alignment) = ImageAlignment::from_name(part) {
+                image_reference.alignment = alignment;
+            } else if part.to_lowercase().starts_with("link:") {
+                image_reference.link = Some(part["link:".len()..].trim().to_string());
+            } else if image_reference.caption.is_none() {
+                image_reference.caption = Some(part.to_string());
+            }
+        }
+        image_reference
+    }
+
+    pub fn file_name(&self) -> Option<String> {
+        paths::image_reference_to_file_name(&self.reference)
+    }
+}
+
+impl ImageSize {
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.to_lowercase();
+        if let Some(percent) = text.strip_suffix("%") {
+            return percent.trim().parse::<u32>().ok().map(|percent| if percent == 100 { ImageSize::Original } else { ImageSize::Scale(percent) });
+        }
+        // A number on its own could be a caption, so pixels need "px" or a height.
+        if let Some(width) = text.strip_suffix("px") {
+            return width.trim().parse::<u32>().ok().map(|width| ImageSize::Pixels { width, height: None });
+        }
+        match text.split_once("x") {
+            Some((width, height)) => match (width.trim().parse::<u32>(), height.trim().parse::<u32>()) {
+                (Ok(width), Ok(height)) => Some(ImageSize::Pixels { width, height: Some(height) }),
+                _ => None,
+            },
+            None => None,
+        }
+    }
+}
+
+impl ImageAlignment {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_ref() {
+            "none" => Some(ImageAlignment::None),
+            "left" => Some(ImageAlignment::Left),
+            "right" => Some(ImageAlignment::Right),
+            "center" | "centre" => Some(ImageAlignment::Center),
+            _ => None,
+        }
+    }
+}
+
+pub fn image_references(content: &str) -> Vec<ImageReference> {
+    parse::delimited_entries(content, "[[$IMG:", "]]").iter()
+        .map(|x| ImageReference::parse(x))
+        .collect()
+}
+
+pub fn image_files(path_images: &path::Path) -> io::Result<Vec<ImageFile>> {
+    // Every file in the Images folder and its subfolders, sorted by path.
+    let mut files = vec![];
+    for path in paths::files_in_folder(path_images)? {
+        let bytes = fs::read(&path)?;
+        let file_name = paths::normalize_reference(path.strip_prefix(path_images).unwrap().to_str().unwrap());
+        files.push(ImageFile { path, file_name, len: bytes.len() as u64, content_hash: cache::hash_bytes(&bytes) });
+    }
+    Ok(files)
+}
+
+pub fn audit_images(wiki: &Wiki, path_images: &path::Path) -> io::Result<ImageAudit> {
+    assert!(path_images.is_absolute());
+    let files = if path_images.is_dir() { image_files(path_images)? } else { vec![] };
+    let mut audit = FileAudit::new(path_images);
+    let mut files_by_name: BTreeMap<String, &ImageFile> = BTreeMap::new();
+    for file in files.iter() {
+        files_by_name.entry(file.file_name.to_lowercase()).or_insert(file);
+    }
+    let label = file_audit::topic_label;
+
+    let mut referenced: Vec<&path::Path> = vec![];
+    for topic in wiki.topics.values() {
+        let mut count = 0;
+        let mut size = 0;
+        for image_reference in image_references(&topic.content) {
+            let file_name = match image_reference.file_name() {
+                Some(file_name) => file_name,
+                None => {
+                    audit.outside_folder.push((label(topic), image_reference.reference));
+                    continue;
+                },
+            };
+            match files_by_name.get(&file_name.to_lowercase()) {
+                Some(file) => {
+                    if file.file_name != file_name {
+                        audit.case_mismatches.push((label(topic), image_reference.reference.clone(), file.path.clone()));
+                    }
+                    referenced.push(&file.path);
+                    count += 1;
+                    size += file.len;
+                },
+                None => audit.missing.push((label(topic), image_reference.reference)),
+            }
+        }
+        if count > 0 {
+            audit.size_per_topic.insert(label(topic), (count, size));
+        }
+    }
+
+    audit.orphans = files.iter()
+        .filter(|file| !referenced.contains(&file.path.as_path()))
+        .map(|file| file.path.clone())
+        .collect();
+    audit.set_duplicates(files.iter().map(|file| (file.len, file.content_hash, file.path.clone())));
+    Ok(ImageAudit { files, audit })
+}
+
+impl ImageAudit {
+    pub fn is_empty(&self) -> bool {
+        self.audit.is_empty()
+    }
+
+    pub fn print(&self) {
+        self.audit.print("image", "the Images folder");
+        let total: u64 = self.files.iter().map(|x| x.len).sum();
+        println!("\n{} image file(s), {} bytes. {}", self.files.len(), total, self.audit.summary("the Images folder"));
+    }
+
+    pub fn plan_cleanup(&self, delete_orphans: bool, fix_case: bool) -> ChangePlan {
+        // Renaming a file to match the case used in the topics only works when they all agree on
+        // the spelling. Duplicates that are still referenced are left alone since the topics
+        // would have to be changed first.
+        let mut plan = ChangePlan::new("Clean up images");
+        let audit = &self.audit;
+        if delete_orphans {
+            for path_file in audit.orphans.iter() {
+                plan.push(FileOperation::Delete { path: path_file.clone() });
+            }
+        }
+        if fix_case {
+            let mut spellings: BTreeMap<&path::PathBuf, Vec<String>> = BTreeMap::new();
+            for (_, reference, path_file) in audit.case_mismatches.iter() {
+                let file_name = paths::image_reference_to_file_name(reference).unwrap();
+                let entry = spellings.entry(path_file).or_insert_with(|| { vec![] });
+                if !entry.contains(&file_name) {
+                    entry.push(file_name);
+                }
+            }
+            for (path_file, file_names) in spellings {
+                let path_dest = audit.path_folder.join(paths::reference_to_relative_path(&file_names[0]));
+                if file_names.len() > 1 {
+                    plan.add_note(&format!("Topics spell \"{}\" as {}.", audit.relative(path_file), file_names.join(", ")));
+                } else if !path_dest.parent().unwrap().is_dir() {
+                    // The difference is in a folder name, which would have to be renamed instead.
+                    plan.add_note(&format!("Topics spell \"{}\" as {}.", audit.relative(path_file), file_names[0]));
+                } else {
+                    plan.push(FileOperation::Rename { path_source: path_file.clone(), path_dest });
+                }
+            }
+        }
+        for paths_file in audit.duplicates.iter() {
+            let referenced: Vec<String> = paths_file.iter().filter(|x| !audit.orphans.contains(x)).map(|x| audit.relative(x)).collect();
+            if referenced.len() > 1 {
+                plan.add_note(&format!("Change the topics to use one of {} to remove the duplicates.", referenced.join(", ")));
+            }
+        }
+        plan
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size() {
+        assert_eq!(ImageSize::parse("50%"), Some(ImageSize::Scale(50)));
+        assert_eq!(ImageSize::parse(" 25 %"), Some(ImageSize::Scale(25)));
+        assert_eq!(ImageSize::parse("100%"), Some(ImageSize::Original));
+        assert_eq!(ImageSize::parse("200px"), Some(ImageSize::Pixels { width: 200, height: None }));
+        assert_eq!(ImageSize::parse("200PX"), Some(ImageSize::Pixels { width: 200, height: None }));
+        assert_eq!(ImageSize::parse("200x150"), Some(ImageSize::Pixels { width: 200, height: Some(150) }));
+        assert_eq!(ImageSize::parse("200 X 150"), Some(ImageSize::Pixels { width: 200, height: Some(150) }));
+        assert_eq!(ImageSize::parse("200"), None);
+        assert_eq!(ImageSize::parse("half%"), None);
+        assert_eq!(ImageSize::parse("Box"), None);
+        assert_eq!(ImageSize::parse("-5%"), None);
+    }
+
+    #[test]
+    fn parse_reference() {
+        assert_eq!(ImageReference::parse("Images\\Cover.jpg"), ImageReference {
+            reference: "Images\\Cover.jpg".to_string(),
+            size: ImageSize::Original,
+            alignment: ImageAlignment::None,
+            caption: None,
+            link: None,
+        });
+        assert_eq!(ImageReference::parse("Images\\Cover.jpg|50%|RIGHT|The cover|link: Book Covers#Hardback"), ImageReference {
+            reference: "Images\\Cover.jpg".to_string(),
+            size: ImageSize::Scale(50),
+            alignment: ImageAlignment::Right,
+            caption: Some("The cover".to_string()),
+            link: Some("Book Covers#Hardback".to_string()),
+        });
+        // The options can come in any order, and only the first caption is kept.
+        assert_eq!(ImageReference::parse(" pic.png | Link:https://example.com | First | centre | 20x10 | Second "), ImageReference {
+            reference: "pic.png".to_string(),
+            size: ImageSize::Pixels { width: 20, height: Some(10) },
+            alignment: ImageAlignment::Center,
+            caption: Some("First".to_string()),
+            link: Some("https://example.com".to_string()),
+        });
+        let image_reference = ImageReference::parse("pic.png|100%|NONE||");
+        assert_eq!((image_reference.size, image_reference.alignment, image_reference.caption), (ImageSize::Original, ImageAlignment::None, None));
+    }
+
+    #[test]
+    fn references_in_content() {
+        let content = "Before [[$IMG:a.png|LEFT]] and [[Topic]] then [[$IMG:Images\\b.jpg]].";
+        let image_references = image_references(content);
+        assert_eq!(image_references.iter().map(|x| x.reference.as_str()).collect::<Vec<_>>(), vec!["a.png", "Images\\b.jpg"]);
+        assert_eq!(image_references[0].alignment, ImageAlignment::Left);
+        // Relative to the Images folder.
+        assert_eq!(image_references[1].file_name(), Some("b.jpg".to_string()));
+    }
+}
@@ -1,28 +1,40 @@
-use std::{fs, io, path};
-use std::collections::{HashMap, HashSet};
+use std::{io, path};
+use std::collections::HashSet;
 use crate::*;
 use super::model::{Link, LinkType, Topic, Wiki};
+use super::images;
+use super::input::{self, Encoding};
+use super::paths;
+use super::plan::{ChangePlan, ConflictHandling, FileOperation};
+use super::reconcile;
+use super::urls;
 use util::parse;
+use rayon::prelude::*;
 
-pub fn fix_file_names(path_full_export_file: &path::Path, path_source: &path::Path, path_dest: &path::Path) -> io::Result<()> {
+pub fn fix_file_names(path_full_export_file: &path::Path, path_source: &path::Path, path_dest: &path::Path, encoding: Option<Encoding>) -> io::Result<()> {
+    plan_fix_file_names(path_full_export_file, path_source, path_dest, encoding)?.apply(ConflictHandling::Fail).map(|_| ())
+}
+
+pub fn plan_fix_file_names(path_full_export_file: &path::Path, path_source: &path::Path, path_dest: &path::Path, encoding: Option<Encoding>) -> io::Result<ChangePlan> {
+    // The encoding is the one the topic files are read with to compare them with the export.
     assert!(path_source.is_absolute());
-    assert!(path_source.is_dir());
     assert!(path_dest.is_absolute());
-    assert!(path_dest.is_dir());
-    assert_ne!(path_source, path_dest);
+    paths::require_folder(path_source)?;
+    paths::require_folder(path_dest)?;
+    if paths::same_path_ci(path_source, path_dest) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "The source and destination folders are the same."));
+    }
 
-    let files_and_topics = reconcile_files_and_topics(path_full_export_file, path_source);
-    for path_file_source in parse::get_files_ci(path_source, "*.txt").unwrap() {
-        assert!(path_file_source.is_file());
-        dbg!(&path_file_source);
-        let topic_name = files_and_topics.get(&path_file_source).unwrap();
+    let mut plan = ChangePlan::new("Fix file names");
+    let report = reconcile::reconcile(path_full_export_file, path_source, encoding)?;
+    for (path_file_source, topic_name) in report.matched.iter() {
         let file_name_dest = topic_name_to_file_name(&topic_name);
-        let path_file_dest = path_dest.join(&file_name_dest);
-        assert!(!path_file_dest.exists());
-        println!("{}", &path_file_dest.to_str().unwrap());
-        fs::copy(&path_file_source, &path_file_dest).unwrap();
+        plan.push(FileOperation::Copy { path_source: path_file_source.clone(), path_dest: path_dest.join(&file_name_dest) });
     }
-    Ok(())
+    for issue in report.issues.iter() {
+        plan.add_note(&format!("{} {}", issue, issue.suggestion()));
+    }
+    Ok(plan)
 }
 
 /*
@@ -82,122 +94,181 @@ fn fix_file_name(file_name: &str) -> String {
 
 pub fn get_image_path(path: &path::Path) -> path::PathBuf {
     assert!(path.is_absolute());
-    assert!(path.is_dir());
-    let path_buf = path.join(r"Images\");
-    if !path_buf.exists() {
-        fs::create_dir(&path_buf).unwrap();
-    }
-    assert!(path_buf.is_dir());
-    path_buf
+    paths::resolve_ci_or_join(path, paths::FOLDER_IMAGES)
 }
 
 pub fn get_image_file_names(path: &path::Path) -> io::Result<HashSet<String>> {
+    // The names are relative to the Images folder and use forward slashes.
     assert!(path.is_dir());
-    Ok(parse::find_in_files_ci(path, "*.txt","[[$IMG:Images\\", "]]")?.iter()
-        // If there's a pipe character, take only the part before it.
-        .map(|x| parse::before(&x, "|").to_string())
-        .collect())
+    let mut file_names = HashSet::new();
+    for path_file in parse::get_files_ci(path, "*.txt")? {
+        let content = input::read_file(&path_file, None)?.text;
+        for reference in image_references(&content) {
+            if let Some(file_name) = paths::image_reference_to_file_name(&reference) {
+                file_names.insert(file_name);
+            }
+        }
+    }
+    Ok(file_names)
+}
+
+pub fn image_references(content: &str) -> Vec<String> {
+    // The part of each [[$IMG:...]] before any pipe character, as written in the topic.
+    images::image_references(content).into_iter().map(|x| x.reference).collect()
 }
 
 pub fn copy_image_files(path_source: &path::Path, path_dest: &path::Path) -> io::Result<()> {
-    assert_ne!(path_source, path_dest);
     assert!(path_source.is_absolute());
-    assert!(path_source.is_dir());
+    paths::require_folder(path_source)?;
+    let plan = plan_copy_image_files(&get_image_path(path_source), path_dest)?;
+    for note in plan.notes.iter() {
+        println!("\n{}\n", note);
+    }
+    plan.apply(ConflictHandling::Fail).map(|_| ())
+}
+
+pub fn plan_copy_image_files(path_image_source: &path::Path, path_dest: &path::Path) -> io::Result<ChangePlan> {
+    // Copy the images that the topics in path_dest refer to from path_image_source, which is
+    // the image folder itself rather than the topic folder it's usually inside, into the Images
+    // folder under path_dest.
+    assert!(path_image_source.is_absolute());
     assert!(path_dest.is_absolute());
-    assert!(path_dest.is_dir());
+    paths::require_folder(path_dest)?;
 
-    let path_image_source = get_image_path(path_source);
     let path_image_dest = get_image_path(path_dest);
-    assert_ne!(path_image_source, path_image_dest);
-    assert!(path_image_source.is_absolute());
-    assert!(path_image_source.is_dir());
-    assert!(path_image_dest.is_absolute());
-    assert!(path_image_dest.is_dir());
+    if paths::same_path_ci(path_image_source, &path_image_dest) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "The source and destination image folders are the same."));
+    }
 
+    let mut plan = ChangePlan::new("Copy image files");
+    if !path_image_dest.is_dir() {
+        plan.push(FileOperation::CreateDir { path: path_image_dest.clone() });
+    }
     for file_name in get_image_file_names(&path_dest)? {
-        let path_file_source = path_image_source.join(&file_name);
-        let path_file_dest = path_image_dest.join(&file_name);
-        assert_ne!(path_file_source, path_file_dest);
-        assert!(path_file_source.is_absolute());
-        assert!(path_file_dest.is_absolute());
-        assert!(!path_file_dest.exists(), "Destination file exists: \"{}\"", &path_file_dest.to_str().unwrap());
-        println!("{}", &path_file_source.to_str().unwrap());
-        if path_file_source.exists() {
-            println!("{}", &path_file_dest.to_str().unwrap());
-            fs::copy(&path_file_source, &path_file_dest).unwrap();
-        } else {
-            println!("\nNo source file.\n");
+        // The topic may spell the file name with a different case than the file on disk.
+        match paths::resolve_ci(path_image_source, &file_name) {
+            Some(path_file_source) => {
+                let path_file_dest = paths::resolve_ci_or_join(&path_image_dest, &file_name);
+                plan.push(FileOperation::Copy { path_source: path_file_source, path_dest: path_file_dest });
+            },
+            None => plan.add_note(&format!("No source file \"{}\".", path_image_source.join(paths::reference_to_relative_path(&file_name)).to_str().unwrap())),
         }
     }
-
-    Ok(())
+    Ok(plan)
 }
 
-pub fn get_all_topic_names(path_full_export_file: &path::Path) -> io::Result<Vec<String>> {
-    assert!(path_full_export_file.is_absolute());
-    assert!(path_full_export_file.is_file());
-    parse::find_in_file(path_full_export_file, "****************** ", "\r\n")
+pub fn get_all_topic_names(path_full_export_file: &path::Path, encoding: Option<Encoding>) -> io::Result<Vec<String>> {
+    Ok(get_all_topics(path_full_export_file, encoding)?.into_iter().map(|(topic_name, _)| topic_name).collect())
 }
 
-pub fn reconcile_files_and_topics(path_full_export_file: &path::Path, path_source: &path::Path) -> HashMap<path::PathBuf, String> {
+pub fn get_all_topics(path_full_export_file: &path::Path, encoding: Option<Encoding>) -> io::Result<Vec<(String, String)>> {
+    // The name and content of each topic in the order they appear. Each topic in the full export
+    // starts with a line like "****************** Topic Name". A {{Topic}}-delimited export file
+    // works too. With no encoding the file's encoding is detected.
     assert!(path_full_export_file.is_absolute());
-    assert!(path_full_export_file.is_file());
-    assert!(path_source.is_absolute());
-    assert!(path_source.is_dir());
-
-    let mut topics: Vec<(String, String)> = get_all_topic_names(path_full_export_file)
-        .unwrap()
-        .iter()
-        .map(|topic_name| (topic_name.to_string(), topic_name_to_file_name(topic_name).to_lowercase()))
-        .collect();
-    dbg!(&topics);
-    let mut map = HashMap::new();
-    for (path_file, file_name_from_file) in parse::get_files_ci(&path_source, "*.txt")
-        .unwrap()
-        .iter()
-        .map(|path_file| (path_file, path_file.file_name().unwrap().to_str().unwrap().to_lowercase())) {
-        let mut file_name_matches: Vec<String> = topics
-            .drain_filter(|(_, file_name_from_topic)| file_name_from_topic == &file_name_from_file)
-            .map(|(topic_name, _)| topic_name)
-            .collect();
-        if file_name_matches.len() > 0 {
-            assert!(file_name_matches.len() == 1, "Too many matches for {}", &file_name_from_file);
-            map.insert(path_file.to_owned(), file_name_matches.remove(0));
+    paths::require_file(path_full_export_file)?;
+    let decoded = input::read_file(path_full_export_file, encoding)?;
+    decoded.print_problems(&path_full_export_file.display().to_string());
+    let mut topics: Vec<(String, String)> = vec![];
+    for line in decoded.text.split_inclusive('\n') {
+        match line.strip_prefix("****************** ").or_else(|| line.strip_prefix(DELIMITER_TOPIC)) {
+            Some(topic_name) => topics.push((topic_name.trim().to_string(), String::new())),
+            None => {
+                if let Some((_, content)) = topics.last_mut() {
+                    content.push_str(line);
+                }
+            },
         }
     }
-    dbg!(&map);
-    map
+    Ok(topics)
 }
 
 pub fn import_topics(file_import: &str, project_name: &str) -> Wiki {
-    let map = parse::read_file_into_sections(file_import, DELIMITER_TOPIC);
+    import_topics_with_encoding(path::Path::new(file_import), project_name, None).unwrap()
+}
+
+pub fn import_topics_with_encoding(path_file_import: &path::Path, project_name: &str, encoding: Option<Encoding>) -> io::Result<Wiki> {
+    // With no encoding the file's encoding is detected. Bytes that can't be decoded are reported
+    // and replaced rather than stopping the import.
+    let decoded = input::read_file(path_file_import, encoding)?;
+    decoded.print_problems(path_file_import.to_str().unwrap());
+    let map = input::split_into_sections(&decoded.text, DELIMITER_TOPIC);
     //bg!(map.keys().map(|x| format!("|{}|", x)).collect::<Vec<_>>());
     let mut wiki = Wiki::new();
-    for (name, content) in map.iter() {
-        let topic = Topic::new(project_name, name, content);
+    wiki.text_formats.insert(project_name.to_lowercase(), decoded.format);
+    let topics: Vec<Topic> = map.par_iter()
+        .map(|(name, content)| Topic::new(project_name, name, content))
+        .collect();
+    for mut topic in topics {
+        topic.log_problems();
+        wiki.add_topic(topic);
+    }
+    Ok(wiki)
+}
+
+pub fn import_topics_from_folder(path_folder: &path::Path, project_name: &str, encoding: Option<Encoding>, path_full_export_file: Option<&path::Path>) -> io::Result<Wiki> {
+    // Load a project from a folder of one .TXT file per topic instead of the single export file.
+    // The files don't say which topic they hold, so the names come from matching them against
+    // the topic names in the full export, the same way fix_file_names() does. Without a full
+    // export the file name is taken as the topic name.
+    assert!(path_folder.is_absolute());
+    paths::require_folder(path_folder)?;
+    let topic_names = match path_full_export_file {
+        Some(path_full_export_file) => Some(reconcile::reconcile(path_full_export_file, path_folder, encoding)?.matched),
+        None => None,
+    };
+    let mut wiki = Wiki::new();
+    let mut topics = vec![];
+    for path_file in parse::get_files_ci(path_folder, "*.txt")? {
+        let topic_name = match &topic_names {
+            Some(topic_names) => match topic_names.get(&path_file) {
+                Some(topic_name) => topic_name.clone(),
+                None => {
+                    println!("No topic for file \"{}\".", path_file.to_str().unwrap());
+                    continue;
+                },
+            },
+            None => path_file.file_stem().unwrap().to_str().unwrap().to_string(),
+        };
+        let decoded = input::read_file(&path_file, encoding)?;
+        decoded.print_problems(path_file.to_str().unwrap());
+        // The files were all written by ConnectedText, so the first one stands for the rest.
+        wiki.text_formats.entry(project_name.to_lowercase()).or_insert(decoded.format);
+        topics.push((topic_name, decoded.text));
+    }
+    let topics: Vec<Topic> = topics.par_iter()
+        .map(|(name, content)| Topic::new(project_name, name, content))
+        .collect();
+    for mut topic in topics {
+        topic.log_problems();
         wiki.add_topic(topic);
     }
-    wiki
+    Ok(wiki)
 }
 
 pub fn add_links(wiki: &mut Wiki) {
-    for topic in wiki.topics.values_mut() {
-        //bg!(&topic.name);
-        for entry in parse::delimited_entries(&topic.content, "[[", "]]").iter() {
-            if !entry.starts_with("$") && !entry.contains(":=") {
-                let (link, label) = parse::split_once_with_option(entry,"|");
-                let (topic_name, section_name) = parse::split_once_with_option(&link, "#");
-                topic.links.push(Link::Internal {
-                    topic_name,
-                    section_name,
-                    label,
-                    type_: LinkType::Normal
-                })
-            }
+    // The links are parsed along with the attributes, so this only does topics that haven't been
+    // resolved yet.
+    wiki.resolve_attributes();
+    wiki.report_link_groups();
+}
+
+pub fn parse_links(content: &str) -> Vec<Link> {
+    let mut links = vec![];
+    for entry in parse::delimited_entries(content, "[[", "]]").iter() {
+        if !entry.starts_with("$") && !entry.contains(":=") {
+            let (link, label) = parse::split_once_with_option(entry,"|");
+            let (topic_name, section_name) = parse::split_once_with_option(&link, "#");
+            links.push(Link::Internal {
+                topic_name,
+                section_name,
+                label,
+                type_: LinkType::Normal
+            })
         }
-        //bg!(&topic.links);
     }
-    wiki.report_link_groups();
+    links.append(&mut urls::parse_url_links(content));
+    links
 }
 
 pub fn add_tags(wiki: &mut Wiki) {
@@ -0,0 +1,382 @@
+use std::{fs, io, path};
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+
+// Reading ConnectedText files. Older exports are Windows-1252 with CRLF line endings, newer ones
+// are UTF-8, some start with a byte order mark, and a few mix line endings. Everything is decoded
+// into a String with "\n" line endings for parsing, and the original style is kept in a
+// TextFormat so that files written back out look the way ConnectedText wrote them.
+
+const BOM_UTF_8: [u8; 3] = [0xEF, 0xBB, 0xBF];
+
+// Windows-1252 characters for bytes 0x80 to 0x9F. The bytes with None aren't defined.
+const WINDOWS_1252_HIGH: [Option<char>; 32] = [
+    Some('\u{20AC}'), None, Some('\u{201A}'), Some('\u{0192}'), Some('\u{201E}'), Some('\u{2026}'), Some('\u{2020}'), Some('\u{2021}'),
+    Some('\u{02C6}'), Some('\u{2030}'), Some('\u{0160}'), Some('\u{2039}'), Some('\u{0152}'), None, Some('\u{017D}'), None,
+    None, Some('\u{2018}'), Some('\u{2019}'), Some('\u{201C}'), Some('\u{201D}'), Some('\u{2022}'), Some('\u{2013}'), Some('\u{2014}'),
+    Some('\u{02DC}'), Some('\u{2122}'), Some('\u{0161}'), Some('\u{203A}'), Some('\u{0153}'), None, Some('\u{017E}'), Some('\u{0178}'),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encoding {
+    Utf8,
+    Windows1252,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextFormat {
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
+    pub bom: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct DecodeProblem {
+    // Byte offset in the file, and the line and column (both starting at 1) in the decoded text.
+    pub position: usize,
+    pub line: usize,
+    pub column: usize,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DecodedText {
+    pub text: String,
+    pub format: TextFormat,
+    // True if the file had both CRLF and bare LF or CR line endings. They're all "\n" in text and
+    // will all be format.line_ending when written back.
+    pub mixed_line_endings: bool,
+    pub problems: Vec<DecodeProblem>,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().replace("_", "-").as_ref() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "windows-1252" | "cp1252" | "ansi" => Some(Encoding::Windows1252),
+            _ => None,
+        }
+    }
+
+    pub fn from_config_name(name: &str) -> Option<Option<Self>> {
+        // "auto" means the encoding should be detected from the file.
+        if name.eq_ignore_ascii_case("auto") {
+            Some(None)
+        } else {
+            Self::from_name(name).map(Some)
+        }
+    }
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+impl Default for TextFormat {
+    fn default() -> Self {
+        Self {
+            encoding: Encoding::Utf8,
+            line_ending: LineEnding::Lf,
+            bom: false,
+        }
+    }
+}
+
+impl TextFormat {
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        // The reverse of decode(). Characters that don't exist in Windows-1252 become "?".
+        let text = text.replace("\r\n", "\n");
+        let text = match self.line_ending {
+            LineEnding::Lf => text,
+            LineEnding::CrLf => text.replace("\n", "\r\n"),
+        };
+        let mut bytes = vec![];
+        if self.bom && self.encoding == Encoding::Utf8 {
+            bytes.extend_from_slice(&BOM_UTF_8);
+        }
+        match self.encoding {
+            Encoding::Utf8 => bytes.extend_from_slice(text.as_bytes()),
+            Encoding::Windows1252 => bytes.extend(text.chars().map(encode_windows_1252_char)),
+        }
+        bytes
+    }
+}
+
+impl std::fmt::Display for DecodeProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bytes = self.bytes.iter().map(|x| format!("{:02X}", x)).collect::<Vec<_>>().join(" ");
+        write!(f, "line {}, column {} (byte {}): can't decode {}", self.line, self.column, self.position, bytes)
+    }
+}
+
+impl DecodedText {
+    pub fn print_problems(&self, label: &str) {
+        if self.mixed_line_endings {
+            println!("{}: mixed line endings, will be written as {:?}", label, self.format.line_ending);
+        }
+        for problem in self.problems.iter() {
+            println!("{}: {}", label, problem);
+        }
+    }
+}
+
+pub fn read_file(path_file: &path::Path, encoding: Option<Encoding>) -> io::Result<DecodedText> {
+    Ok(decode(&fs::read(path_file)?, encoding))
+}
+
+pub fn write_file(path_file: &path::Path, text: &str, format: TextFormat) -> io::Result<()> {
+    fs::write(path_file, format.encode(text))
+}
+
+pub fn decode(bytes: &[u8], encoding: Option<Encoding>) -> DecodedText {
+    // With no encoding given, a file is UTF-8 if it has a BOM or is valid UTF-8, and otherwise
+    // Windows-1252, which is what ConnectedText used before it supported Unicode.
+    let (bytes_text, bom) = strip_bom(bytes);
+    let offset = bytes.len() - bytes_text.len();
+    // This is the whole file, so unlike in detect_encoding() a sequence cut off at the end means
+    // it isn't UTF-8.
+    let is_utf_8 = bom || std::str::from_utf8(bytes_text).is_ok();
+    let encoding = encoding.unwrap_or(if is_utf_8 { Encoding::Utf8 } else { Encoding::Windows1252 });
+    let (text, bad_positions) = decode_bytes(bytes_text, encoding);
+    let line_starts = if bad_positions.is_empty() { vec![] } else { line_starts(&text) };
+    let problems = bad_positions.into_iter()
+        .map(|(position_text, position_bytes, bad_bytes)| {
+            let (line, column) = line_and_column(&text, &line_starts, position_text);
+            DecodeProblem { position: offset + position_bytes, line, column, bytes: bad_bytes }
+        })
+        .collect();
+    let (text, line_ending, mixed_line_endings) = normalize_line_endings(&text);
+    DecodedText {
+        text,
+        format: TextFormat { encoding, line_ending, bom },
+        mixed_line_endings,
+        problems,
+    }
+}
+
+pub fn split_into_sections(text: &str, delimiter: &str) -> BTreeMap<String, String> {
+    // The same layout as parse::read_file_into_sections(): each section starts with a line that
+    // begins with the delimiter, the rest of that line is the section name, and the lines up to
+    // the next delimiter are its content.
+    let mut sections = BTreeMap::new();
+    let mut current: Option<(String, String)> = None;
+    for line in text.split_inclusive('\n') {
+        match line.strip_prefix(delimiter) {
+            Some(name) => {
+                if let Some((name, content)) = current.take() {
+                    sections.insert(name, content);
+                }
+                current = Some((name.trim().to_string(), String::new()));
+            },
+            None => {
+                if let Some((_, content)) = current.as_mut() {
+                    content.push_str(line);
+                }
+            },
+        }
+    }
+    if let Some((name, content)) = current {
+        sections.insert(name, content);
+    }
+    sections
+}
+
+pub fn strip_bom(bytes: &[u8]) -> (&[u8], bool) {
+    match bytes.strip_prefix(&BOM_UTF_8) {
+        Some(bytes) => (bytes, true),
+        None => (bytes, false),
+    }
+}
+
+pub fn detect_encoding(bytes: &[u8]) -> Encoding {
+    // The bytes may be only the start of a file, so a UTF-8 sequence cut off at the end doesn't
+    // count against it.
+    match std::str::from_utf8(bytes) {
+        Ok(_) => Encoding::Utf8,
+        Err(error) if error.error_len().is_none() => Encoding::Utf8,
+        Err(_) => Encoding::Windows1252,
+    }
+}
+
+pub fn decode_bytes(bytes: &[u8], encoding: Encoding) -> (String, Vec<(usize, usize, Vec<u8>)>) {
+    // Returns the text and, for each undecodable sequence, its byte position in the text and in
+    // the input and the bytes themselves. Line endings are left alone.
+    match encoding {
+        Encoding::Utf8 => decode_utf_8(bytes),
+        Encoding::Windows1252 => decode_windows_1252(bytes),
+    }
+}
+
+fn decode_utf_8(bytes: &[u8]) -> (String, Vec<(usize, usize, Vec<u8>)>) {
+    // Like String::from_utf8_lossy() but also returns where each bad sequence was, as a byte
+    // position in the decoded text and in the input.
+    let mut text = String::with_capacity(bytes.len());
+    let mut bad = vec![];
+    let mut position = 0;
+    while position < bytes.len() {
+        match std::str::from_utf8(&bytes[position..]) {
+            Ok(valid) => {
+                text.push_str(valid);
+                break;
+            },
+            Err(error) => {
+                let valid_up_to = position + error.valid_up_to();
+                text.push_str(std::str::from_utf8(&bytes[position..valid_up_to]).unwrap());
+                let bad_len = error.error_len().unwrap_or(bytes.len() - valid_up_to);
+                bad.push((text.len(), valid_up_to, bytes[valid_up_to..valid_up_to + bad_len].to_vec()));
+                text.push(char::REPLACEMENT_CHARACTER);
+                position = valid_up_to + bad_len;
+            },
+        }
+    }
+    (text, bad)
+}
+
+fn decode_windows_1252(bytes: &[u8]) -> (String, Vec<(usize, usize, Vec<u8>)>) {
+    let mut text = String::with_capacity(bytes.len());
+    let mut bad = vec![];
+    for (position, byte) in bytes.iter().enumerate() {
+        let c = match byte {
+            0x80..=0x9F => WINDOWS_1252_HIGH[(byte - 0x80) as usize],
+            _ => Some(*byte as char),
+        };
+        match c {
+            Some(c) => text.push(c),
+            None => {
+                bad.push((text.len(), position, vec![*byte]));
+                text.push(char::REPLACEMENT_CHARACTER);
+            },
+        }
+    }
+    (text, bad)
+}
+
+fn encode_windows_1252_char(c: char) -> u8 {
+    let code = c as u32;
+    if code < 0x80 || (0xA0..=0xFF).contains(&code) {
+        return code as u8;
+    }
+    match WINDOWS_1252_HIGH.iter().position(|x| *x == Some(c)) {
+        Some(index) => 0x80 + index as u8,
+        None => b'?',
+    }
+}
+
+pub fn normalize_line_endings(text: &str) -> (String, LineEnding, bool) {
+    // The line ending used most often is the one the file will be written back with.
+    let count_crlf = text.matches("\r\n").count();
+    let count_cr = text.matches('\r').count() - count_crlf;
+    let count_lf = text.matches('\n').count() - count_crlf;
+    let line_ending = if count_crlf > count_lf { LineEnding::CrLf } else { LineEnding::Lf };
+    let mixed = (count_crlf > 0 && count_lf + count_cr > 0) || (count_cr > 0 && count_lf > 0);
+    if count_crlf + count_cr == 0 {
+        return (text.to_string(), line_ending, mixed);
+    }
+    (text.replace("\r\n", "\n").replace('\r', "\n"), line_ending, mixed)
+}
+
+fn line_starts(text: &str) -> Vec<usize> {
+    // The byte position where each line starts. The text hasn't had its line endings normalised
+    // yet, so CRLF, CR and LF each end a line.
+    let bytes = text.as_bytes();
+    let mut line_starts = vec![0];
+    for (position, byte) in bytes.iter().enumerate() {
+        let is_line_end = *byte == b'\n' || (*byte == b'\r' && bytes.get(position + 1) != Some(&b'\n'));
+        if is_line_end {
+            line_starts.push(position + 1);
+        }
+    }
+    line_starts
+}
+
+fn line_and_column(text: &str, line_starts: &[usize], position: usize) -> (usize, usize) {
+    let line = line_starts.partition_point(|line_start| *line_start <= position);
+    let column = text[line_starts[line - 1]..position].chars().count() + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_1252_high_bytes() {
+        let bytes = (0x80..=0x9F).collect::<Vec<u8>>();
+        let decoded = decode(&bytes, Some(Encoding::Windows1252));
+        assert_eq!(decoded.text.chars().next(), Some('€'));
+        assert_eq!(decoded.text.chars().nth(0x99 - 0x80), Some('™'));
+        // 0x81, 0x8D, 0x8F, 0x90 and 0x9D aren't defined.
+        let undefined = decoded.problems.iter().map(|problem| problem.bytes[0]).collect::<Vec<_>>();
+        assert_eq!(undefined, vec![0x81, 0x8D, 0x8F, 0x90, 0x9D]);
+        // Everything else comes back as the same byte.
+        let format = TextFormat { encoding: Encoding::Windows1252, line_ending: LineEnding::Lf, bom: false };
+        let encoded = format.encode(&decoded.text);
+        for (byte, encoded_byte) in bytes.iter().zip(encoded.iter()) {
+            if !undefined.contains(byte) {
+                assert_eq!(byte, encoded_byte);
+            }
+        }
+        assert_eq!(format.encode("Ā"), b"?");
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(detect_encoding("Caf\u{e9}".as_bytes()), Encoding::Utf8);
+        assert_eq!(detect_encoding(b"Caf\xE9 au lait"), Encoding::Windows1252);
+        // A sequence cut off at the end of the bytes is still UTF-8.
+        assert_eq!(detect_encoding(&"Caf\u{e9}".as_bytes()[..4]), Encoding::Utf8);
+
+        let decoded = decode(b"\xEF\xBB\xBFCaf\xC3\xA9", None);
+        assert_eq!(decoded.text, "Caf\u{e9}");
+        assert_eq!(decoded.format, TextFormat { encoding: Encoding::Utf8, line_ending: LineEnding::Lf, bom: true });
+        let decoded = decode(b"Caf\xE9", None);
+        assert_eq!(decoded.text, "Caf\u{e9}");
+        assert_eq!(decoded.format.encoding, Encoding::Windows1252);
+    }
+
+    #[test]
+    fn line_endings() {
+        assert_eq!(normalize_line_endings("a\r\nb\r\nc\nd"), ("a\nb\nc\nd".to_string(), LineEnding::CrLf, true));
+        assert_eq!(normalize_line_endings("a\nb\r\nc\n"), ("a\nb\nc\n".to_string(), LineEnding::Lf, true));
+        assert_eq!(normalize_line_endings("a\rb\nc"), ("a\nb\nc".to_string(), LineEnding::Lf, true));
+        assert_eq!(normalize_line_endings("a\r\nb\r\n"), ("a\nb\n".to_string(), LineEnding::CrLf, false));
+    }
+
+    #[test]
+    fn problem_positions() {
+        // A bad UTF-8 byte on the third line, after a CRLF and a bare CR, with a BOM before it.
+        let decoded = decode(b"\xEF\xBB\xBFone\r\ntwo\r\xC3\xA9\xFFx\n", Some(Encoding::Utf8));
+        assert_eq!(decoded.problems.len(), 1);
+        let problem = &decoded.problems[0];
+        assert_eq!((problem.position, problem.line, problem.column, problem.bytes.clone()), (14, 3, 2, vec![0xFF]));
+        assert_eq!(decoded.text, "one\ntwo\n\u{e9}\u{FFFD}x\n");
+        assert_eq!(problem.to_string(), "line 3, column 2 (byte 14): can't decode FF");
+    }
+
+    #[test]
+    fn round_trip() {
+        for format in [
+            TextFormat { encoding: Encoding::Utf8, line_ending: LineEnding::CrLf, bom: true },
+            TextFormat { encoding: Encoding::Utf8, line_ending: LineEnding::Lf, bom: false },
+            TextFormat { encoding: Encoding::Windows1252, line_ending: LineEnding::CrLf, bom: false },
+        ].iter() {
+            let bytes = format.encode("Caf\u{e9} \u{2013} \u{20AC}5\nNext\n");
+            let decoded = decode(&bytes, None);
+            assert_eq!(decoded.text, "Caf\u{e9} \u{2013} \u{20AC}5\nNext\n");
+            assert_eq!(decoded.format, *format);
+            assert!(!decoded.mixed_line_endings);
+            assert_eq!(decoded.format.encode(&decoded.text), bytes);
+        }
+    }
+}
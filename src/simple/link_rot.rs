@@ -0,0 +1,462 @@
+use std::{fs, io, path};
+use std::collections::BTreeMap;
+use super::change::{Change, ChangeSet};
+use super::model::Wiki;
+use super::paths;
+use super::urls;
+use super::writer;
+
+// Finding the URL links that have probably gone dead without going out to the network. The
+// results of fetching each URL come from a snapshot made somewhere else, either a folder of WARC
+// files from a crawler such as wget --warc-file, or a CSV file with a row for each URL:
+//
+//   url,status,location,archive_url
+//   https://example.com/old,301,https://example.com/new,
+//   https://example.org/gone,404,,https://web.archive.org/web/20190101000000/https://example.org/gone
+//
+// Only the url and status columns are required. A status of 0 means there was no response at
+// all. Without a header row the columns are taken to be in that order.
+
+// How many redirects to follow within the snapshot before giving up.
+const MAX_REDIRECTS: usize = 10;
+
+#[derive(Clone, Debug)]
+pub struct UrlStatus {
+    pub status: u16,
+    pub location: Option<String>,
+    pub archive_url: Option<String>,
+    // When the URL was fetched, as in "20210304050607". Only known for WARC files.
+    pub date: Option<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LinkSnapshot {
+    // Keyed by the normalized URL.
+    pub results: BTreeMap<String, UrlStatus>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LinkState {
+    Ok,
+    // Where the redirects ended up, which is a page that loads.
+    Redirected { location: String },
+    Dead { status: u16 },
+    NotInSnapshot,
+}
+
+#[derive(Clone, Debug)]
+pub struct LinkCheck {
+    // As written in the topic.
+    pub url: String,
+    pub state: LinkState,
+    pub archive_url: Option<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LinkRotReport {
+    // Keyed by the project and topic name. Only topics with URL links are included.
+    pub checks: BTreeMap<(String, String), Vec<LinkCheck>>,
+}
+
+impl LinkSnapshot {
+    pub fn load(path: &path::Path, archive_prefix: Option<&str>) -> io::Result<Self> {
+        // A folder of WARC files, a single WARC file or a CSV file. archive_prefix is the start of
+        // the URL of an archived copy, such as "https://web.archive.org/web/", used for URLs that
+        // don't have an archive_url of their own.
+        let mut snapshot = Self::default();
+        if path.is_dir() {
+            for path_file in paths::files_in_folder(path)? {
+                let file_name = path_file.file_name().unwrap().to_str().unwrap().to_lowercase();
+                if file_name.ends_with(".warc.gz") {
+                    return Err(invalid_data(&path_file, "compressed WARC files aren't supported, so unzip them first"));
+                }
+                if file_name.ends_with(".warc") {
+                    snapshot.read_warc(&path_file, archive_prefix)?;
+                }
+            }
+        } else if path.extension().map_or(false, |x| x.eq_ignore_ascii_case("warc")) {
+            snapshot.read_warc(path, archive_prefix)?;
+        } else {
+            snapshot.read_csv(path, archive_prefix)?;
+        }
+        Ok(snapshot)
+    }
+
+    fn read_csv(&mut self, path_file: &path::Path, archive_prefix: Option<&str>) -> io::Result<()> {
+        let text = fs::read_to_string(path_file)?;
+        let mut lines = text.lines().filter(|line| !line.trim().is_empty()).peekable();
+        let mut columns = vec!["url".to_string(), "status".to_string(), "location".to_string(), "archive_url".to_string()];
+        if let Some(first_line) = lines.peek() {
+            let fields = split_csv_line(first_line);
+            if fields.get(1).map_or(true, |x| x.trim().parse::<u16>().is_err()) {
+                columns = fields.iter().map(|x| x.trim().to_lowercase()).collect();
+                lines.next();
+            }
+        }
+        let column = |name: &str| columns.iter().position(|x| x == name);
+        let (column_url, column_status) = match (column("url"), column("status")) {
+            (Some(column_url), Some(column_status)) => (column_url, column_status),
+            _ => return Err(invalid_data(path_file, "expected url and status columns")),
+        };
+        let (column_location, column_archive_url) = (column("location"), column("archive_url"));
+        for (line_index, line) in lines.enumerate() {
+            let fields = split_csv_line(line);
+            let field = |column: Option<usize>| column.and_then(|column| fields.get(column))
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty());
+            let url = match field(Some(column_url)) {
+                Some(url) => url,
+                None => continue,
+            };
+            let status = field(Some(column_status)).and_then(|x| x.parse::<u16>().ok())
+                .ok_or_else(|| invalid_data(path_file, &format!("no status for {} in data row {}", url, line_index + 1)))?;
+            let archive_url = field(column_archive_url).or_else(|| archive_prefix.map(|prefix| format!("{}{}", prefix, url)));
+            self.add(&url, UrlStatus { status, location: field(column_location), archive_url, date: None });
+        }
+        Ok(())
+    }
+
+    fn read_warc(&mut self, path_file: &path::Path, archive_prefix: Option<&str>) -> io::Result<()> {
+        // Each record is a block of "Name: value" lines, a blank line and then Content-Length
+        // bytes of content. For a response record the content is the HTTP response, whose status
+        // line and headers are all that's needed here.
+        let bytes = fs::read(path_file)?;
+        let mut position = 0;
+        loop {
+            while position < bytes.len() && (bytes[position] == b'\r' || bytes[position] == b'\n') {
+                position += 1;
+            }
+            if position >= bytes.len() {
+                break;
+            }
+            let (header_len, separator_len) = find_blank_line(&bytes[position..])
+                .ok_or_else(|| invalid_data(path_file, &format!("unfinished record header at byte {}", position)))?;
+            let header = String::from_utf8_lossy(&bytes[position..position + header_len]).to_string();
+            if !header.starts_with("WARC/") {
+                return Err(invalid_data(path_file, &format!("expected a WARC record at byte {}", position)));
+            }
+            let fields = header_fields(&header);
+            let content_len = fields.get("content-length").and_then(|x| x.parse::<usize>().ok())
+                .ok_or_else(|| invalid_data(path_file, &format!("no Content-Length in the record at byte {}", position)))?;
+            let start_content = position + header_len + separator_len;
+            let end_content = std::cmp::min(start_content + content_len, bytes.len());
+            let target_uri = fields.get("warc-target-uri").map(|x| x.trim_start_matches('<').trim_end_matches('>').to_string());
+            if let (Some("response"), Some(url)) = (fields.get("warc-type").map(|x| x.as_str()), target_uri) {
+                let content = &bytes[start_content..end_content];
+                let http_header_len = find_blank_line(content).map_or(content.len(), |(header_len, _)| header_len);
+                let http_header = String::from_utf8_lossy(&content[..http_header_len]).to_string();
+                let status = http_header.lines().next()
+                    .and_then(|status_line| status_line.split_whitespace().nth(1))
+                    .and_then(|x| x.parse::<u16>().ok());
+                if let Some(status) = status {
+                    let location = header_fields(&http_header).get("location").cloned();
+                    let date: Option<String> = fields.get("warc-date").map(|x| x.chars().filter(|c| c.is_ascii_digit()).collect());
+                    // There's only an archived copy worth linking to if the page was there.
+                    let archive_url = match (archive_prefix, &date) {
+                        (Some(prefix), Some(date)) if is_success(status) => Some(format!("{}{}/{}", prefix, date, url)),
+                        _ => None,
+                    };
+                    self.add(&url, UrlStatus { status, location, archive_url, date });
+                }
+            }
+            position = end_content;
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, url: &str, url_status: UrlStatus) {
+        // A URL fetched more than once keeps the latest result, but an archived copy from an
+        // earlier fetch is better than none.
+        let key = urls::normalize_url(url);
+        let url_status = match self.results.remove(&key) {
+            Some(existing) if existing.date > url_status.date => UrlStatus { archive_url: existing.archive_url.clone().or(url_status.archive_url), ..existing },
+            Some(existing) => UrlStatus { archive_url: url_status.archive_url.or(existing.archive_url), ..url_status },
+            None => url_status,
+        };
+        self.results.insert(key, url_status);
+    }
+
+    pub fn check_url(&self, url: &str) -> (LinkState, Option<String>) {
+        // The state after following any redirects, and an archived copy of the original URL.
+        let url_status = match self.results.get(&urls::normalize_url(url)) {
+            Some(url_status) => url_status,
+            None => return (LinkState::NotInSnapshot, None),
+        };
+        let archive_url = url_status.archive_url.clone();
+        let mut current_url = url.to_string();
+        let mut current = url_status;
+        for _ in 0..MAX_REDIRECTS {
+            if is_success(current.status) {
+                let state = if urls::normalize_url(&current_url) == urls::normalize_url(url) {
+                    LinkState::Ok
+                } else {
+                    LinkState::Redirected { location: current_url }
+                };
+                return (state, archive_url);
+            }
+            let location = match (is_redirect(current.status), &current.location) {
+                (true, Some(location)) => resolve_location(&current_url, location),
+                _ => return (LinkState::Dead { status: current.status }, archive_url),
+            };
+            match self.results.get(&urls::normalize_url(&location)) {
+                Some(next) => {
+                    current_url = location;
+                    current = next;
+                },
+                // The page the redirect goes to wasn't fetched, so assume it's there.
+                None => return (LinkState::Redirected { location }, archive_url),
+            }
+        }
+        (LinkState::Dead { status: current.status }, archive_url)
+    }
+}
+
+pub fn check_links(wiki: &Wiki, snapshot: &LinkSnapshot) -> LinkRotReport {
+    let mut report = LinkRotReport::default();
+    for topic in wiki.topics.values() {
+        let mut checks: Vec<LinkCheck> = vec![];
+        for (url, _) in urls::url_links(topic) {
+            if checks.iter().any(|check| check.url == url) {
+                continue;
+            }
+            let (state, archive_url) = snapshot.check_url(&url);
+            checks.push(LinkCheck { url, state, archive_url });
+        }
+        if !checks.is_empty() {
+            report.checks.insert((topic.project_name.clone(), topic.name.clone()), checks);
+        }
+    }
+    report
+}
+
+impl LinkRotReport {
+    pub fn count(&self, f: fn(&LinkState) -> bool) -> usize {
+        self.checks.values().flat_map(|checks| checks.iter()).filter(|check| f(&check.state)).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count(|state| matches!(state, LinkState::Dead { .. } | LinkState::Redirected { .. })) == 0
+    }
+
+    pub fn print(&self) {
+        for ((project_name, topic_name), checks) in self.checks.iter() {
+            let problems: Vec<&LinkCheck> = checks.iter()
+                .filter(|check| matches!(check.state, LinkState::Dead { .. } | LinkState::Redirected { .. }))
+                .collect();
+            if problems.is_empty() {
+                continue;
+            }
+            println!("{}: {}", project_name, topic_name);
+            for check in problems {
+                match &check.state {
+                    LinkState::Dead { status: 0 } => println!("    Dead (no response): {}", check.url),
+                    LinkState::Dead { status } => println!("    Dead ({}): {}", status, check.url),
+                    LinkState::Redirected { location } => println!("    Redirected: {} -> {}", check.url, location),
+                    _ => {},
+                }
+                if let (LinkState::Dead { .. }, Some(archive_url)) = (&check.state, &check.archive_url) {
+                    println!("        Archived: {}", archive_url);
+                }
+            }
+        }
+        println!("\n{} OK, {} redirected, {} dead, {} not in the snapshot",
+            self.count(|state| *state == LinkState::Ok),
+            self.count(|state| matches!(state, LinkState::Redirected { .. })),
+            self.count(|state| matches!(state, LinkState::Dead { .. })),
+            self.count(|state| *state == LinkState::NotInSnapshot));
+    }
+
+    pub fn plan_rewrite(&self, wiki: &Wiki, update_redirects: bool) -> ChangeSet {
+        // Point dead links at their archived copies and, if update_redirects is set, redirected
+        // links at where they end up. Only the URLs change; labels and the rest of the topic are
+        // left exactly as they were.
+        let mut change_set = ChangeSet::new("Rewrite dead links");
+        for ((project_name, topic_name), checks) in self.checks.iter() {
+            let mut replacements: BTreeMap<&str, String> = BTreeMap::new();
+            for check in checks.iter() {
+                match (&check.state, &check.archive_url) {
+                    (LinkState::Dead { .. }, Some(archive_url)) => {
+                        replacements.insert(&check.url, archive_url.clone());
+                    },
+                    (LinkState::Dead { .. }, None) => {
+                        change_set.add_warning(&format!("{}: {}: no archived copy of {}", project_name, topic_name, check.url));
+                    },
+                    (LinkState::Redirected { location }, _) if update_redirects => {
+                        replacements.insert(&check.url, location.clone());
+                    },
+                    _ => {},
+                }
+            }
+            if replacements.is_empty() {
+                continue;
+            }
+            // The report may come from an earlier load of the wiki.
+            let topic = match wiki.find_topic(project_name, topic_name) {
+                Some(topic) => topic,
+                None => {
+                    change_set.add_warning(&format!("{}: {}: the topic no longer exists.", project_name, topic_name));
+                    continue;
+                },
+            };
+            let new_content = writer::replace_urls(&topic.content, |url| replacements.get(url).cloned());
+            if new_content != topic.content {
+                change_set.push(Change::EditContent {
+                    project_name: project_name.clone(),
+                    topic_name: topic_name.clone(),
+                    old_content: topic.content.clone(),
+                    new_content,
+                });
+            }
+        }
+        change_set
+    }
+}
+
+fn is_success(status: u16) -> bool {
+    (200..300).contains(&status)
+}
+
+fn is_redirect(status: u16) -> bool {
+    (300..400).contains(&status)
+}
+
+fn resolve_location(url: &str, location: &str) -> String {
+    // A Location header may be relative to the URL that was fetched.
+    if location.contains("://") {
+        return location.to_string();
+    }
+    let (scheme, rest) = url.split_once("://").unwrap_or(("http", url));
+    let authority = rest.split(|c| c == '/' || c == '?' || c == '#').next().unwrap();
+    if let Some(location) = location.strip_prefix("//") {
+        format!("{}://{}", scheme, location)
+    } else if location.starts_with('/') {
+        format!("{}://{}{}", scheme, authority, location)
+    } else {
+        let path = rest.split(|c| c == '?' || c == '#').next().unwrap();
+        let base = match path.rfind('/') {
+            Some(position) => &path[..position + 1],
+            None => path,
+        };
+        let separator = if base.ends_with('/') { "" } else { "/" };
+        format!("{}://{}{}{}", scheme, base, separator, location)
+    }
+}
+
+fn find_blank_line(bytes: &[u8]) -> Option<(usize, usize)> {
+    // The length of the header before the blank line and the length of the line breaks that
+    // make it. WARC and HTTP both call for CRLF, but some tools write bare LF.
+    (0..bytes.len()).find_map(|position| {
+        if bytes[position..].starts_with(b"\r\n\r\n") {
+            Some((position, 4))
+        } else if bytes[position..].starts_with(b"\n\n") {
+            Some((position, 2))
+        } else {
+            None
+        }
+    })
+}
+
+fn header_fields(header: &str) -> BTreeMap<String, String> {
+    // Keyed by the lowercase field name. The first line is the WARC version or the HTTP status
+    // line, which has no colon before any space and so is skipped.
+    header.lines().skip(1)
+        .filter_map(|line| line.split_once(":"))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect()
+}
+
+fn split_csv_line(line: &str) -> Vec<String> {
+    // Fields may be in double quotes, with "" for a quote inside a field, since URLs can have
+    // commas in them.
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut is_quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if is_quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            },
+            '"' => is_quoted = !is_quoted,
+            ',' if !is_quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+fn invalid_data(path_file: &path::Path, message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path_file.display(), message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str, content: &str) -> path::PathBuf {
+        let path_file = std::env::temp_dir().join(format!("connectedtext-{}-{}", std::process::id(), name));
+        fs::write(&path_file, content).unwrap();
+        path_file
+    }
+
+    fn warc_record(line_break: &str, url: &str, date: &str, http_response: &str) -> String {
+        let http_response = http_response.replace("\n", line_break);
+        let header = ["WARC/1.0", "WARC-Type: response", &format!("WARC-Target-URI: <{}>", url), &format!("WARC-Date: {}", date),
+            &format!("Content-Length: {}", http_response.len())].join(line_break);
+        format!("{}{}{}{}{}{}", header, line_break, line_break, http_response, line_break, line_break)
+    }
+
+    #[test]
+    fn read_csv() {
+        let path_file = temp_file("snapshot.csv", "status,url,archive_url\n301,https://example.com/old,\n\n404,\"https://example.org/a,b\",https://archive.example/a\n");
+        let snapshot = LinkSnapshot::load(&path_file, Some("https://web.archive.org/web/")).unwrap();
+        let old = &snapshot.results[&urls::normalize_url("https://example.com/old")];
+        assert_eq!((old.status, old.location.clone()), (301, None));
+        assert_eq!(old.archive_url.as_deref(), Some("https://web.archive.org/web/https://example.com/old"));
+        assert_eq!(snapshot.results[&urls::normalize_url("https://example.org/a,b")].archive_url.as_deref(), Some("https://archive.example/a"));
+
+        // No header row, so the columns are in the usual order.
+        let path_file = temp_file("snapshot-no-header.csv", "https://example.com/old,301,/new\nhttps://example.com/new,200\n");
+        let snapshot = LinkSnapshot::load(&path_file, None).unwrap();
+        assert_eq!(snapshot.check_url("https://example.com/old"), (LinkState::Redirected { location: "https://example.com/new".to_string() }, None));
+
+        let path_file = temp_file("snapshot-bad.csv", "url,status\nhttps://example.com/old,moved\n");
+        assert_eq!(LinkSnapshot::load(&path_file, None).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let path_file = temp_file("snapshot-columns.csv", "address,code\nhttps://example.com/old,301\n");
+        assert_eq!(LinkSnapshot::load(&path_file, None).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_warc() {
+        for line_break in ["\r\n", "\n"].iter() {
+            let text = [
+                warc_record(line_break, "https://example.com/page", "2021-03-04T05:06:07Z", "HTTP/1.1 200 OK\nContent-Type: text/html\n\n<html>\n\n</html>"),
+                warc_record(line_break, "https://example.com/old", "2021-03-04T05:06:08Z", "HTTP/1.1 302 Found\nLocation: page\n\n"),
+                warc_record(line_break, "https://example.com/gone", "2021-03-04T05:06:09Z", "HTTP/1.1 404 Not Found\n\n"),
+            ].concat();
+            let path_file = temp_file("snapshot.warc", &text);
+            let snapshot = LinkSnapshot::load(&path_file, Some("https://web.archive.org/web/")).unwrap();
+            assert_eq!(snapshot.results.len(), 3);
+            let page = &snapshot.results[&urls::normalize_url("https://example.com/page")];
+            assert_eq!(page.date.as_deref(), Some("20210304050607"));
+            assert_eq!(page.archive_url.as_deref(), Some("https://web.archive.org/web/20210304050607/https://example.com/page"));
+            assert_eq!(snapshot.check_url("https://example.com/old").0, LinkState::Redirected { location: "https://example.com/page".to_string() });
+            assert_eq!(snapshot.check_url("https://example.com/gone"), (LinkState::Dead { status: 404 }, None));
+            assert_eq!(snapshot.check_url("https://example.com/other").0, LinkState::NotInSnapshot);
+        }
+        let path_file = temp_file("snapshot-bad.warc", "WARC/1.0\nWARC-Type: response\n");
+        assert_eq!(LinkSnapshot::load(&path_file, None).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn location() {
+        assert_eq!(resolve_location("https://example.com/a/b?x=1", "https://other.com/c"), "https://other.com/c");
+        assert_eq!(resolve_location("https://example.com/a/b?x=1", "//other.com/c"), "https://other.com/c");
+        assert_eq!(resolve_location("https://example.com/a/b?x=1", "/c"), "https://example.com/c");
+        assert_eq!(resolve_location("https://example.com/a/b?x=1", "c"), "https://example.com/a/c");
+        assert_eq!(resolve_location("https://example.com/a/", "c"), "https://example.com/a/c");
+        assert_eq!(resolve_location("https://example.com", "c"), "https://example.com/c");
+    }
+}
@@ -0,0 +1,119 @@
+use std::{io, path};
+use chrono::NaiveDate;
+use super::import;
+use super::input::Encoding;
+use super::model::{Link, Topic, Wiki};
+use super::stream::{self, TopicStream};
+use super::urls;
+use super::writer;
+
+const DATE_ATTRIBUTES: [&str; 5] = ["Added", "Acquired", "Started", "Completed", "Abandoned"];
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Diagnostic {
+    pub project_name: String,
+    pub topic_name: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(topic: &Topic, message: &str) -> Self {
+        Self {
+            project_name: topic.project_name.clone(),
+            topic_name: topic.name.clone(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn print_list(diagnostics: &[Diagnostic]) {
+        for diagnostic in diagnostics.iter() {
+            println!("{}", diagnostic);
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}: {}", self.project_name, self.topic_name, self.message)
+    }
+}
+
+pub fn lint_wiki(wiki: &Wiki) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    for topic in wiki.topics.values() {
+        diagnostics.append(&mut lint_topic(wiki, topic));
+    }
+    diagnostics.sort();
+    diagnostics
+}
+
+pub fn lint_export_file(path_file: &path::Path, project_name: &str, encoding: Option<Encoding>) -> io::Result<Vec<Diagnostic>> {
+    // Lint straight from the export file one topic at a time, for exports too big to load as a
+    // Wiki. The first pass only collects topic names so that links can be checked in the second.
+    let topic_keys = stream::topic_keys(path_file, project_name, encoding)?;
+    let topic_exists = |project_name: &str, topic_name: &str| topic_keys.contains(&Wiki::link_key(project_name, topic_name));
+    let mut topics = TopicStream::open(path_file, project_name, encoding)?;
+    let mut diagnostics = vec![];
+    for topic in topics.by_ref() {
+        diagnostics.append(&mut lint_topic_with(&topic?, &topic_exists));
+    }
+    for problem in topics.problems.iter() {
+        println!("{}: {}", path_file.to_str().unwrap(), problem);
+    }
+    diagnostics.sort();
+    Ok(diagnostics)
+}
+
+pub fn lint_topic(wiki: &Wiki, topic: &Topic) -> Vec<Diagnostic> {
+    lint_topic_with(topic, &|project_name: &str, topic_name: &str| wiki.topics.contains_key(&Wiki::link_key(project_name, topic_name)))
+}
+
+pub fn lint_topic_with(topic: &Topic, topic_exists: &dyn Fn(&str, &str) -> bool) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+
+    match topic.content.lines().filter(|line| writer::is_category_line(line)).count() {
+        0 => diagnostics.push(Diagnostic::new(topic, "No $CATEGORY line.")),
+        1 => {},
+        _ => diagnostics.push(Diagnostic::new(topic, "Multiple $CATEGORY lines.")),
+    }
+
+    let mut attr_names: Vec<String> = vec![];
+    for line in topic.content.lines().filter(|line| writer::is_attribute_row(line)) {
+        match writer::parse_attribute_row(line) {
+            Some((attr_name, values)) => {
+                if attr_names.contains(&attr_name) {
+                    diagnostics.push(Diagnostic::new(topic, &format!("Attribute {:?} appears more than once.", attr_name)));
+                }
+                for value in values.iter() {
+                    if value.trim().is_empty() {
+                        diagnostics.push(Diagnostic::new(topic, &format!("Blank value for attribute {:?}.", attr_name)));
+                    } else if DATE_ATTRIBUTES.contains(&attr_name.as_str()) && NaiveDate::parse_from_str(value, "%Y%m%d").is_err() {
+                        diagnostics.push(Diagnostic::new(topic, &format!("Bad date {:?} for attribute {:?}.", value, attr_name)));
+                    }
+                }
+                attr_names.push(attr_name);
+            },
+            None => diagnostics.push(Diagnostic::new(topic, &format!("Badly formed attribute line {:?}.", line.trim()))),
+        }
+    }
+
+    let mut dangling: Vec<String> = vec![];
+    for link in import::parse_links(&topic.content) {
+        match link {
+            Link::Internal { topic_name, .. } => {
+                // A link like [[#Section]] points into the same topic.
+                if !topic_name.trim().is_empty() && !topic_exists(&topic.project_name, &topic_name) && !dangling.contains(&topic_name) {
+                    diagnostics.push(Diagnostic::new(topic, &format!("Link to missing topic {:?}.", topic_name)));
+                    dangling.push(topic_name);
+                }
+            },
+            Link::Url { url, .. } => {
+                let problems = urls::validate_url(&url);
+                if !problems.is_empty() {
+                    diagnostics.push(Diagnostic::new(topic, &format!("Bad URL {:?}: {}", url, problems.join(" "))));
+                }
+            },
+        }
+    }
+    diagnostics
+}
@@ -1,2 +1,23 @@
+pub mod attachments;
+pub mod attributes;
+pub mod bookmarks;
+pub mod cache;
+pub mod change;
+pub mod dates;
+pub mod export;
+pub mod file_audit;
+pub mod file_names;
+pub mod images;
 pub mod import;
+pub mod input;
+pub mod link_rot;
+pub mod lint;
 pub mod model;
+pub mod paths;
+pub mod plan;
+pub mod reconcile;
+pub mod refactor;
+pub mod stream;
+pub mod urls;
+pub mod watch;
+pub mod writer;
@@ -1,13 +1,21 @@
 use std::collections::BTreeMap;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use chrono::{NaiveDate, Datelike};
 use crate::CT_DUMMY_VALUE;
 use util::group::Grouper;
 use util::{log, parse};
+use super::dates::{AddedDateStrategy, DerivedAddedDate};
+use super::import;
+use super::input::TextFormat;
 
 #[derive(Debug)]
 pub struct Wiki {
     pub topics: BTreeMap<String, Topic>,
     pub attribute_types: BTreeMap<String, AttributeType>,
+    // How each project's export file was encoded, keyed by the lowercase project name, so that
+    // files written for that project can match it.
+    pub text_formats: BTreeMap<String, TextFormat>,
 }
 
 #[derive(Debug)]
@@ -45,9 +53,25 @@ pub struct Topic {
     pub abandoned_date: Option<NaiveDate>,
     pub repeat_score: Option<u32>,
     pub links: Vec<Link>,
+    // Set once the attributes and links have been parsed from the content, so that appending a
+    // project doesn't parse every topic again. Cleared when the content changes.
+    pub is_resolved: bool,
+    // Problems found while parsing, held until log_problems() since the parsing may happen on
+    // other threads and their messages would come out interleaved.
+    pub problems: Vec<String>,
 }
 
-#[derive(Clone, Debug)]
+// The differences between two wikis that should hold the same topics, such as one loaded from the
+// single-file export and one from the per-topic folder. Each entry is "project: topic".
+#[derive(Clone, Debug, Default)]
+pub struct WikiComparison {
+    pub only_in_first: Vec<String>,
+    pub only_in_second: Vec<String>,
+    pub different_names: Vec<(String, String)>,
+    pub different_content: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Link {
     Internal {
         topic_name: String,
@@ -61,7 +85,7 @@ pub enum Link {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum LinkType {
     Normal,
     Parent,
@@ -75,25 +99,108 @@ impl Wiki {
         Self {
             topics: BTreeMap::new(),
             attribute_types: BTreeMap::new(),
+            text_formats: BTreeMap::new(),
         }
     }
 
+    pub fn text_format(&self, project_name: &str) -> TextFormat {
+        self.text_formats.get(&project_name.to_lowercase()).copied().unwrap_or_default()
+    }
+
     pub fn append(&mut self, mut other: Wiki) {
         self.topics.append(&mut other.topics);
+        self.text_formats.append(&mut other.text_formats);
         self.resolve_attributes();
     }
 
     pub fn add_topic(&mut self, topic: Topic) {
-        let key = format!("{:<20}{}", topic.project_name.to_lowercase(), topic.name.to_lowercase());
+        let key = Self::topic_key(&topic.project_name, &topic.name);
         //bg!(&key);
         self.topics.insert(key, topic);
     }
 
+    pub fn topic_key(project_name: &str, topic_name: &str) -> String {
+        format!("{:<20}{}", project_name.to_lowercase(), topic_name.to_lowercase())
+    }
+
+    pub fn same_name(name: &str, other_name: &str) -> bool {
+        // Whether two topic or project names are the same ignoring case, the same way topic_key()
+        // lowercases them, so that "Über" and "über" match.
+        name.to_lowercase() == other_name.to_lowercase()
+    }
+
+    pub fn link_key(project_name: &str, link_topic_name: &str) -> String {
+        // The key of the topic a link goes to. ConnectedText ignores spaces around the name, so
+        // [[ Topic ]] is a link to Topic.
+        Self::topic_key(project_name, link_topic_name.trim())
+    }
+
+    pub fn find_topic(&self, project_name: &str, topic_name: &str) -> Option<&Topic> {
+        self.topics.get(&Self::topic_key(project_name, topic_name))
+    }
+
+    pub fn find_topic_mut(&mut self, project_name: &str, topic_name: &str) -> Option<&mut Topic> {
+        self.topics.get_mut(&Self::topic_key(project_name, topic_name))
+    }
+
+    pub fn compare(&self, other: &Wiki) -> WikiComparison {
+        // Topics are matched by key, so a name that only differs in case counts as the same topic
+        // with a different name. Content is compared ignoring trailing whitespace, since the
+        // export file and the topic files don't end topics the same way.
+        let label = |topic: &Topic| format!("{}: {}", topic.project_name, topic.name);
+        let mut comparison = WikiComparison::default();
+        for (key, topic) in self.topics.iter() {
+            match other.topics.get(key) {
+                Some(other_topic) => {
+                    if topic.name != other_topic.name {
+                        comparison.different_names.push((label(topic), label(other_topic)));
+                    }
+                    if topic.content.trim_end() != other_topic.content.trim_end() {
+                        comparison.different_content.push(label(topic));
+                    }
+                },
+                None => comparison.only_in_first.push(label(topic)),
+            }
+        }
+        for (key, topic) in other.topics.iter() {
+            if !self.topics.contains_key(key) {
+                comparison.only_in_second.push(label(topic));
+            }
+        }
+        comparison
+    }
+
+    pub fn link_index(&self) -> BTreeMap<String, Vec<String>> {
+        // The key is the key of the topic being linked to and the value is the list of keys of the
+        // topics that link to it. Internal links are resolved within the linking topic's project.
+        // Topics that haven't been resolved yet have their links parsed from the content.
+        let mut index: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (key, topic) in self.topics.iter() {
+            let links = if topic.is_resolved { topic.links.clone() } else { import::parse_links(&topic.content) };
+            for link in links {
+                if let Link::Internal { topic_name, .. } = link {
+                    let entry = index.entry(Self::link_key(&topic.project_name, &topic_name)).or_insert_with(|| { vec![] });
+                    if !entry.contains(key) {
+                        entry.push(key.to_string());
+                    }
+                }
+            }
+        }
+        index
+    }
+
     pub fn resolve_attributes(&mut self) {
-        // self.attribute_types.clear();
+        // Parsing the attributes and links of each topic is the slow part and doesn't depend on
+        // any other topic, so do it in parallel, skipping topics that were already done. The
+        // attribute type statistics are shared, so they're gathered afterwards in one pass, which
+        // is also when any problems from the parsing are logged.
+        self.topics.par_iter_mut()
+            .map(|(_, topic)| topic)
+            .filter(|topic| !topic.is_resolved)
+            .for_each(|topic| topic.parse());
         let mut attribute_types = BTreeMap::new();
         for topic in self.topics.values_mut() {
-            topic.parse_attributes();
+            topic.log_problems();
             topic.set_attributes(&mut attribute_types);
         }
         self.attribute_types = attribute_types;
@@ -156,38 +263,8 @@ impl Wiki {
     }
 
     pub fn report_derived_added_dates(&self) {
-        let mut earliest_inbound_links: BTreeMap<String, (String, NaiveDate)> = BTreeMap::new();
-        for topic in self.topics.values() {
-            if let Some(added_date) = topic.added_date {
-                for link in topic.links.iter() {
-                    match link {
-                        Link::Internal{ topic_name: ref_topic_name, .. } => {
-                            match earliest_inbound_links.get(ref_topic_name) {
-                                Some((_, found_date)) => {
-                                    if added_date < *found_date {
-                                        //rintln!("Replacing {} with {}.", found_date, added_date);
-                                        earliest_inbound_links.insert(ref_topic_name.to_string(), (topic.name.clone(), added_date));
-                                    }
-                                },
-                                None => {
-                                    earliest_inbound_links.insert(ref_topic_name.to_string(), (topic.name.clone(), added_date));
-                                }
-                            }
-                        },
-                        _ => {},
-                    };
-                }
-            }
-        }
-        //bg!(&earliest_inbound_links);
-        let mut no_date = vec![];
-        for topic in self.topics.values().filter(|topic| topic.added_date.is_none()) {
-            match earliest_inbound_links.get(&topic.name) {
-                Some((other_topic_name, added_date)) => println!("{}: {}: from {}", added_date, topic.name, other_topic_name),
-                None => no_date.push(&topic.name),
-            }
-        }
-        //bg!(&no_date);
+        let derived = self.derive_added_dates(&[AddedDateStrategy::EarliestInboundLink]);
+        DerivedAddedDate::print_list(&derived);
     }
 }
 
@@ -214,6 +291,8 @@ impl Topic {
             abandoned_date: None,
             repeat_score: None,
             links: vec![],
+            is_resolved: false,
+            problems: vec![],
         };
         topic.parse_category();
         topic
@@ -223,31 +302,58 @@ impl Topic {
         log::log(&format!("{}: {}: {}", self.project_name, self.name, message));
     }
 
+    pub fn log_problems(&mut self) {
+        for message in std::mem::take(&mut self.problems) {
+            self.log(&message);
+        }
+    }
+
     fn parse_category(&mut self) {
         let category_lines = self.content.split("\n").filter(|x| x.trim().starts_with("[[$CATEGORY:")).collect::<Vec<_>>();
         self.category = match category_lines.len() {
             0 => None,
             1 => Some(parse::between(category_lines[0], "[[$CATEGORY:", "]]").trim().to_string()),
             _ => {
-                self.log("Multiple $CATEGORY lines.");
+                self.problems.push("Multiple $CATEGORY lines.".to_string());
                 None
             },
         }
     }
 
+    pub fn resolve(&mut self, attribute_types: &mut BTreeMap<String, AttributeType>) {
+        self.parse();
+        self.log_problems();
+        self.set_attributes(attribute_types);
+    }
+
+    fn parse(&mut self) {
+        // Topic::new() has already parsed the category, so start over rather than report its
+        // problems twice.
+        self.problems.clear();
+        self.parse_category();
+        self.parse_attributes();
+        self.links = import::parse_links(&self.content);
+        self.is_resolved = true;
+    }
+
+    pub fn set_content(&mut self, content: &str) {
+        self.content = content.to_string();
+        self.is_resolved = false;
+    }
+
     fn parse_attributes(&mut self) {
         self.attributes.clear();
         for line in self.content.split("\n") {
             if line.contains("||[[") && line.contains(":=") {
                 //bg!(&line);
                 if !line.trim().starts_with("||") || !line.trim().ends_with("||") {
-                    self.log("Attribute line is missing pipes at start or end.");
+                    self.problems.push("Attribute line is missing pipes at start or end.".to_string());
                     continue;
                 }
                 let line = parse::between(line, "||", "||");
                 let (attribute_name, values_part) = parse::split_2(line, "||");
                 if self.attributes.contains_key(attribute_name) {
-                    self.log(&format!("Attribute {:?} appears more than once.", attribute_name));
+                    self.problems.push(format!("Attribute {:?} appears more than once.", attribute_name));
                     continue;
                 }
                 let values_part = parse::between(values_part, "[[", "]]");
@@ -256,21 +362,21 @@ impl Topic {
                 for value_split in values_part.split("]],[[") {
                     //bg!(&value_split);
                     if value_split.contains("[[") || value_split.contains("]]") {
-                        self.log(&format!("Value split {:?}; contains extra delimiters.", value_split));
+                        self.problems.push(format!("Value split {:?}; contains extra delimiters.", value_split));
                         continue;
                     }
                     if !value_split.contains(":=") {
-                        self.log(&format!("Value split {:?}; does not contain \":=\".", value_split));
+                        self.problems.push(format!("Value split {:?}; does not contain \":=\".", value_split));
                         continue;
                     }
                     let (value_attribute_name, value_attribute_value) = parse::split_2(value_split, ":=");
                     if value_attribute_name != attribute_name {
-                        self.log(&format!("Attribute name = {:?} but value says {:?}.", attribute_name, value_attribute_name));
+                        self.problems.push(format!("Attribute name = {:?} but value says {:?}.", attribute_name, value_attribute_name));
                         continue;
                     }
                     if value_attribute_value != CT_DUMMY_VALUE {
                         if value_attribute_value.trim().is_empty() || value_attribute_value.contains("*") {
-                            self.log(&format!("Unexpected blank value in {:?}.", value_split));
+                            self.problems.push(format!("Unexpected blank value in {:?}.", value_split));
                         }
                         values.push(value_attribute_value.to_string());
                     }
@@ -475,4 +581,44 @@ impl AttributeType {
             int_values: Grouper::new("int_values"),
         }
     }
+
+    pub fn is_multiple(&self) -> bool {
+        self.is_multiple
+    }
+}
+
+impl WikiComparison {
+    pub fn is_empty(&self) -> bool {
+        self.only_in_first.is_empty() && self.only_in_second.is_empty() && self.different_names.is_empty()
+            && self.different_content.is_empty()
+    }
+
+    pub fn print(&self, label_first: &str, label_second: &str) {
+        for name in self.only_in_first.iter() {
+            println!("Only in {}: {}", label_first, name);
+        }
+        for name in self.only_in_second.iter() {
+            println!("Only in {}: {}", label_second, name);
+        }
+        for (name_first, name_second) in self.different_names.iter() {
+            println!("Different names: {} / {}", name_first, name_second);
+        }
+        for name in self.different_content.iter() {
+            println!("Different content: {}", name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn category_problem_reported_once() {
+        let mut topic = Topic::new("P", "Two Categories", "[[$CATEGORY:Books]]\n[[$CATEGORY:People]]\n");
+        assert_eq!(topic.problems, vec!["Multiple $CATEGORY lines."]);
+        topic.parse();
+        assert_eq!(topic.problems, vec!["Multiple $CATEGORY lines."]);
+        assert_eq!(topic.category, None);
+    }
 }
@@ -0,0 +1,129 @@
+use std::{fs, io, path};
+
+// ConnectedText runs on Windows, so references inside topics such as [[$IMG:Images\Cover.jpg]]
+// use backslashes and the file system doesn't care about case. These functions turn those
+// references into paths that work on any OS and find the real file even when the case differs.
+
+pub const FOLDER_IMAGES: &str = "Images";
+
+pub fn reference_parts(reference: &str) -> Vec<&str> {
+    // Accept either kind of separator and ignore empty and "." parts, so "Images\\Cover.jpg",
+    // "Images/Cover.jpg" and ".\Images\Cover.jpg" are all the same reference.
+    reference.trim()
+        .split(|c| c == '\\' || c == '/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect()
+}
+
+pub fn reference_to_relative_path(reference: &str) -> path::PathBuf {
+    reference_parts(reference).iter().collect()
+}
+
+pub fn normalize_reference(reference: &str) -> String {
+    // Forward slashes, which is what both URLs and non-Windows paths expect.
+    reference_parts(reference).join("/")
+}
+
+pub fn is_absolute_reference(reference: &str) -> bool {
+    // A drive letter, a network share or a POSIX path.
+    let reference = reference.trim();
+    reference.get(1..2) == Some(":") || reference.starts_with("\\\\") || reference.starts_with("/")
+}
+
+pub fn is_inside_folder(reference: &str) -> bool {
+    // Whether the reference stays inside the folder it's relative to. A ".." part could reach
+    // any file on the disk, so those are treated like absolute references.
+    !is_absolute_reference(reference) && !reference_parts(reference).contains(&"..")
+}
+
+pub fn image_reference_to_file_name(reference: &str) -> Option<String> {
+    // "Images\Cover.jpg" -> "Cover.jpg". Anything not in the Images folder gives None.
+    if !is_inside_folder(reference) {
+        return None;
+    }
+    let parts = reference_parts(reference);
+    if parts.len() > 1 && parts[0].eq_ignore_ascii_case(FOLDER_IMAGES) {
+        Some(parts[1..].join("/"))
+    } else {
+        None
+    }
+}
+
+pub fn resolve_ci(path_base: &path::Path, reference: &str) -> Option<path::PathBuf> {
+    // Walk down from path_base one part at a time. An exact match wins, otherwise take the entry
+    // whose name matches ignoring case, so "images\cover.JPG" finds Images/Cover.jpg on Linux.
+    // References that would leave path_base give None.
+    if !is_inside_folder(reference) {
+        return None;
+    }
+    let mut path = path_base.to_path_buf();
+    for part in reference_parts(reference) {
+        let path_exact = path.join(part);
+        if path_exact.exists() {
+            path = path_exact;
+            continue;
+        }
+        path = find_entry_ci(&path, part)?;
+    }
+    Some(path)
+}
+
+pub fn resolve_ci_or_join(path_base: &path::Path, reference: &str) -> path::PathBuf {
+    // For destinations that may not exist yet: use the existing file or folder if there is one
+    // with a different case, otherwise the path as written. The reference should already have
+    // passed is_inside_folder(), since joining a ".." part would leave path_base.
+    resolve_ci(path_base, reference).unwrap_or_else(|| path_base.join(reference_to_relative_path(reference)))
+}
+
+fn find_entry_ci(path_folder: &path::Path, name: &str) -> Option<path::PathBuf> {
+    let name = name.to_lowercase();
+    let mut matches: Vec<path::PathBuf> = fs::read_dir(path_folder).ok()?
+        .filter_map(|dir_entry| dir_entry.ok())
+        .filter(|dir_entry| dir_entry.file_name().to_string_lossy().to_lowercase() == name)
+        .map(|dir_entry| dir_entry.path())
+        .collect();
+    // On a case-sensitive file system there could be both "Cover.jpg" and "cover.jpg". Sort so
+    // that the same one is always picked.
+    matches.sort();
+    matches.into_iter().next()
+}
+
+pub fn files_in_folder(path_folder: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+    // Every file in the folder and its subfolders, sorted.
+    let mut paths_file = vec![];
+    let mut paths_folder = vec![path_folder.to_path_buf()];
+    while let Some(path_folder) = paths_folder.pop() {
+        for dir_entry in fs::read_dir(&path_folder)? {
+            let path = dir_entry?.path();
+            if path.is_dir() {
+                paths_folder.push(path);
+            } else {
+                paths_file.push(path);
+            }
+        }
+    }
+    paths_file.sort();
+    Ok(paths_file)
+}
+
+pub fn require_folder(path_folder: &path::Path) -> io::Result<()> {
+    // Folders usually come from the command line or the config file, so a missing one is an
+    // error to report rather than something to assert.
+    if path_folder.is_dir() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("No such folder {:?}.", path_folder)))
+    }
+}
+
+pub fn require_file(path_file: &path::Path) -> io::Result<()> {
+    if path_file.is_file() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("No such file {:?}.", path_file)))
+    }
+}
+
+pub fn same_path_ci(path_a: &path::Path, path_b: &path::Path) -> bool {
+    path_a.to_string_lossy().to_lowercase() == path_b.to_string_lossy().to_lowercase()
+}
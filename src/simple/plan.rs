@@ -0,0 +1,322 @@
+use std::{fs, io, path};
+use std::collections::{BTreeMap, BTreeSet};
+use serde::{Deserialize, Serialize};
+use super::input::{self, TextFormat};
+use super::paths;
+
+// A list of file operations worked out ahead of time so that they can be printed and reviewed,
+// saved and loaded as JSON, and then applied. Nothing touches the file system until apply().
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChangePlan {
+    pub label: String,
+    pub operations: Vec<FileOperation>,
+    // Things noticed while making the plan that don't stop it from being applied, such as an image
+    // that's referenced by a topic but has no source file.
+    pub notes: Vec<String>,
+    // How the content of created and overwritten files is encoded. The default is UTF-8 with "\n"
+    // line endings.
+    #[serde(default)]
+    pub text_format: TextFormat,
+    // Used instead of text_format for files in these folders, such as the topic folders of
+    // projects that ConnectedText wrote with different encodings or line endings.
+    #[serde(default)]
+    pub folder_text_formats: BTreeMap<path::PathBuf, TextFormat>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum FileOperation {
+    CreateDir { path: path::PathBuf },
+    Copy { path_source: path::PathBuf, path_dest: path::PathBuf },
+    Rename { path_source: path::PathBuf, path_dest: path::PathBuf },
+    Create { path_dest: path::PathBuf, content: String },
+    Overwrite { path_dest: path::PathBuf, content: String },
+    Delete { path: path::PathBuf },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ConflictHandling {
+    // Don't do anything if any destination file already exists.
+    Fail,
+    Skip,
+    Overwrite,
+    // Use the first free name of the form "Name (2).TXT".
+    Rename,
+}
+
+#[derive(Debug, Default)]
+pub struct ApplyReport {
+    pub done: usize,
+    pub skipped: Vec<path::PathBuf>,
+    pub renamed: Vec<(path::PathBuf, path::PathBuf)>,
+}
+
+impl ConflictHandling {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_ref() {
+            "fail" => Some(ConflictHandling::Fail),
+            "skip" => Some(ConflictHandling::Skip),
+            "overwrite" => Some(ConflictHandling::Overwrite),
+            "rename" => Some(ConflictHandling::Rename),
+            _ => None,
+        }
+    }
+}
+
+impl FileOperation {
+    pub fn path_dest(&self) -> &path::Path {
+        match self {
+            FileOperation::CreateDir { path } => path,
+            FileOperation::Copy { path_dest, .. } => path_dest,
+            FileOperation::Rename { path_dest, .. } => path_dest,
+            FileOperation::Create { path_dest, .. } => path_dest,
+            FileOperation::Overwrite { path_dest, .. } => path_dest,
+            FileOperation::Delete { path } => path,
+        }
+    }
+
+    fn can_conflict(&self) -> bool {
+        // Only operations that expect the destination not to exist yet can conflict.
+        matches!(self, FileOperation::Copy { .. } | FileOperation::Rename { .. } | FileOperation::Create { .. })
+    }
+
+    fn is_conflict(&self) -> bool {
+        // A rename that only changes the case finds its own source as the destination on a file
+        // system that ignores case, which isn't a conflict.
+        if let FileOperation::Rename { path_source, path_dest } = self {
+            if paths::same_path_ci(path_source, path_dest) {
+                return false;
+            }
+        }
+        self.can_conflict() && self.path_dest().exists()
+    }
+
+    fn with_path_dest(&self, path_dest: path::PathBuf) -> Self {
+        match self.clone() {
+            FileOperation::Copy { path_source, .. } => FileOperation::Copy { path_source, path_dest },
+            FileOperation::Rename { path_source, .. } => FileOperation::Rename { path_source, path_dest },
+            FileOperation::Create { content, .. } => FileOperation::Create { path_dest, content },
+            operation => operation,
+        }
+    }
+
+    fn with_source_remapped(&self, remapped: &BTreeMap<path::PathBuf, path::PathBuf>) -> Self {
+        // Point an operation that uses a file an earlier operation created at the place that file
+        // actually went, after the earlier one was renamed to avoid a conflict. Destinations that
+        // are being created are left alone, since they get their own conflict handling.
+        let remap = |path: &path::PathBuf| remapped.get(path).unwrap_or(path).clone();
+        match self {
+            FileOperation::Copy { path_source, path_dest } => FileOperation::Copy { path_source: remap(path_source), path_dest: path_dest.clone() },
+            FileOperation::Rename { path_source, path_dest } => FileOperation::Rename { path_source: remap(path_source), path_dest: path_dest.clone() },
+            FileOperation::Overwrite { path_dest, content } => FileOperation::Overwrite { path_dest: remap(path_dest), content: content.clone() },
+            FileOperation::Delete { path } => FileOperation::Delete { path: remap(path) },
+            operation => operation.clone(),
+        }
+    }
+
+    fn apply(&self, text_format: TextFormat) -> io::Result<()> {
+        match self {
+            FileOperation::CreateDir { path } => {
+                if !path.is_dir() {
+                    fs::create_dir_all(path)?;
+                }
+            },
+            FileOperation::Copy { path_source, path_dest } => {
+                fs::copy(path_source, path_dest)?;
+            },
+            FileOperation::Rename { path_source, path_dest } if paths::same_path_ci(path_source, path_dest) => {
+                // Only the case is changing. Copying would overwrite the source on a file system
+                // that ignores case and then delete it.
+                fs::rename(path_source, path_dest)?;
+            },
+            FileOperation::Rename { path_source, path_dest } => {
+                // fs::rename() fails when the source and destination are on different drives.
+                fs::copy(path_source, path_dest)?;
+                fs::remove_file(path_source)?;
+            },
+            FileOperation::Create { path_dest, content } | FileOperation::Overwrite { path_dest, content } => {
+                input::write_file(path_dest, content, text_format)?;
+            },
+            FileOperation::Delete { path } => {
+                fs::remove_file(path)?;
+            },
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for FileOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileOperation::CreateDir { path } => write!(f, "create folder {}", path.display()),
+            FileOperation::Copy { path_source, path_dest } => write!(f, "copy {} -> {}", path_source.display(), path_dest.display()),
+            FileOperation::Rename { path_source, path_dest } => write!(f, "rename {} -> {}", path_source.display(), path_dest.display()),
+            FileOperation::Create { path_dest, content } => write!(f, "create {} ({} bytes)", path_dest.display(), content.len()),
+            FileOperation::Overwrite { path_dest, content } => write!(f, "overwrite {} ({} bytes)", path_dest.display(), content.len()),
+            FileOperation::Delete { path } => write!(f, "delete {}", path.display()),
+        }
+    }
+}
+
+impl ChangePlan {
+    pub fn new(label: &str) -> Self {
+        Self {
+            label: label.to_string(),
+            operations: vec![],
+            notes: vec![],
+            text_format: TextFormat::default(),
+            folder_text_formats: BTreeMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, operation: FileOperation) {
+        self.operations.push(operation);
+    }
+
+    pub fn add_note(&mut self, note: &str) {
+        self.notes.push(note.to_string());
+    }
+
+    pub fn append(&mut self, mut other: ChangePlan) {
+        self.operations.append(&mut other.operations);
+        self.notes.append(&mut other.notes);
+        self.folder_text_formats.append(&mut other.folder_text_formats);
+    }
+
+    pub fn text_format_for(&self, path_file: &path::Path) -> TextFormat {
+        path_file.parent()
+            .and_then(|path_folder| self.folder_text_formats.get(path_folder))
+            .copied()
+            .unwrap_or(self.text_format)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    pub fn print(&self) {
+        println!("\n{}: {} operation(s), {} conflict(s)\n", self.label, self.operations.len(), self.conflicts().len());
+        for note in self.notes.iter() {
+            println!("  NOTE: {}", note);
+        }
+        for (operation, is_conflict) in self.operations.iter().zip(self.conflict_flags()) {
+            let conflict = if is_conflict { "  CONFLICT: " } else { "  " };
+            println!("{}{}", conflict, operation);
+        }
+    }
+
+    pub fn conflicts(&self) -> Vec<&FileOperation> {
+        self.operations.iter().zip(self.conflict_flags())
+            .filter(|(_, is_conflict)| *is_conflict)
+            .map(|(operation, _)| operation)
+            .collect()
+    }
+
+    fn conflict_flags(&self) -> Vec<bool> {
+        // An operation conflicts if its destination exists now or if an earlier operation in the
+        // plan creates the same file, such as two topics whose names map to one file name. The
+        // files end up on Windows, so destinations that differ only in case are the same file.
+        let mut planned = BTreeSet::new();
+        self.operations.iter()
+            .map(|operation| {
+                if !operation.can_conflict() {
+                    return false;
+                }
+                let is_planned = !planned.insert(path_key(operation.path_dest()));
+                is_planned || operation.is_conflict()
+            })
+            .collect()
+    }
+
+    pub fn save(&self, path_file: &path::Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        fs::write(path_file, json)
+    }
+
+    pub fn load(path_file: &path::Path) -> io::Result<Self> {
+        let json = fs::read_to_string(path_file)?;
+        serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn apply(&self, conflict_handling: ConflictHandling) -> io::Result<ApplyReport> {
+        let conflict_flags = self.conflict_flags();
+        if conflict_handling == ConflictHandling::Fail && conflict_flags.contains(&true) {
+            return Err(conflicts_error(&self.label, &self.conflicts()));
+        }
+        let mut report = ApplyReport::default();
+        // The destinations used so far, so that a renamed file doesn't take a name that a later
+        // operation is going to use, and where each renamed file went.
+        let mut planned: BTreeSet<String> = self.operations.iter()
+            .filter(|operation| operation.can_conflict())
+            .map(|operation| path_key(operation.path_dest()))
+            .collect();
+        let mut remapped = BTreeMap::new();
+        for (operation, is_conflict) in self.operations.iter().zip(conflict_flags) {
+            let operation = operation.with_source_remapped(&remapped);
+            // Check the file system again in case something else created the file since the plan
+            // was checked.
+            if is_conflict || operation.is_conflict() {
+                match conflict_handling {
+                    ConflictHandling::Fail => return Err(conflicts_error(&self.label, &[&operation])),
+                    ConflictHandling::Skip => {
+                        report.skipped.push(operation.path_dest().to_path_buf());
+                        continue;
+                    },
+                    ConflictHandling::Overwrite => {},
+                    ConflictHandling::Rename => {
+                        let path_dest = free_path(operation.path_dest(), &planned);
+                        planned.insert(path_key(&path_dest));
+                        report.renamed.push((operation.path_dest().to_path_buf(), path_dest.clone()));
+                        remapped.insert(operation.path_dest().to_path_buf(), path_dest.clone());
+                        operation.with_path_dest(path_dest).apply(self.text_format_for(operation.path_dest()))?;
+                        report.done += 1;
+                        continue;
+                    },
+                }
+            }
+            operation.apply(self.text_format_for(operation.path_dest()))?;
+            report.done += 1;
+        }
+        Ok(report)
+    }
+}
+
+impl ApplyReport {
+    pub fn append(&mut self, mut other: ApplyReport) {
+        self.done += other.done;
+        self.skipped.append(&mut other.skipped);
+        self.renamed.append(&mut other.renamed);
+    }
+
+    pub fn print(&self) {
+        println!("{} operation(s) done, {} skipped, {} renamed", self.done, self.skipped.len(), self.renamed.len());
+        for path in self.skipped.iter() {
+            println!("  skipped {}", path.display());
+        }
+        for (path_planned, path_used) in self.renamed.iter() {
+            println!("  {} -> {}", path_planned.display(), path_used.display());
+        }
+    }
+}
+
+fn conflicts_error(label: &str, conflicts: &[&FileOperation]) -> io::Error {
+    let paths = conflicts.iter().map(|operation| operation.path_dest().display().to_string()).collect::<Vec<_>>();
+    io::Error::new(io::ErrorKind::AlreadyExists, format!("{}: destination files exist or are used more than once: {:?}", label, paths))
+}
+
+fn path_key(path: &path::Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+fn free_path(path: &path::Path, planned: &BTreeSet<String>) -> path::PathBuf {
+    let stem = path.file_stem().unwrap().to_str().unwrap();
+    let extension = path.extension().map(|x| format!(".{}", x.to_str().unwrap())).unwrap_or_default();
+    let mut number = 2;
+    loop {
+        let path_try = path.with_file_name(format!("{} ({}){}", stem, number, extension));
+        if !path_try.exists() && !planned.contains(&path_key(&path_try)) {
+            return path_try;
+        }
+        number += 1;
+    }
+}
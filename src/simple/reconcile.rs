@@ -0,0 +1,166 @@
+use std::{fmt, io, path};
+use std::collections::BTreeMap;
+use crate::*;
+use util::parse;
+use super::import;
+use super::input::{self, Encoding};
+use super::paths;
+
+// Matching the topics in a full export against a folder of one .TXT file per topic, which is
+// what fix_file_names() and import_topics_from_folder() need to know which topic each file
+// holds. The files are matched by the file name each topic would get from
+// topic_name_to_file_name(), ignoring case, and anything that doesn't line up is reported along
+// with what to do about it rather than being dropped.
+
+#[derive(Clone, Debug, Default)]
+pub struct ReconcileReport {
+    // Each file that could be matched to exactly one topic, and the topic's name.
+    pub matched: BTreeMap<path::PathBuf, String>,
+    pub issues: Vec<ReconcileIssue>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReconcileIssue {
+    TopicMissingFile {
+        topic_name: String,
+        file_name: String,
+    },
+    OrphanFile {
+        path_file: path::PathBuf,
+        // A topic with no file of its own whose content in the export is the same as the file's,
+        // which usually means the topic was renamed after the files were written.
+        same_content_as: Option<String>,
+    },
+    Collision {
+        // Two or more topics whose names differ only in characters that can't be used in a file
+        // name, such as "A/B" and "A:B", so that they map to the same file.
+        file_name: String,
+        topic_names: Vec<String>,
+        path_file: Option<path::PathBuf>,
+    },
+    ContentMismatch {
+        path_file: path::PathBuf,
+        topic_name: String,
+    },
+}
+
+impl ReconcileReport {
+    pub fn is_empty(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    pub fn print(&self) {
+        for issue in self.issues.iter() {
+            println!("{}\n    {}", issue, issue.suggestion());
+        }
+        println!("\n{} file(s) matched to topics, {} issue(s)", self.matched.len(), self.issues.len());
+    }
+}
+
+impl ReconcileIssue {
+    pub fn suggestion(&self) -> String {
+        match self {
+            ReconcileIssue::TopicMissingFile { file_name, .. } =>
+                format!("Export the topic from ConnectedText again, or create \"{}\" from the export file.", file_name),
+            ReconcileIssue::OrphanFile { same_content_as: Some(topic_name), .. } =>
+                format!("Rename the file to \"{}\".", topic_name_to_file_name(topic_name)),
+            ReconcileIssue::OrphanFile { same_content_as: None, .. } =>
+                "Delete the file if the topic was deleted in ConnectedText, or add the topic back.".to_string(),
+            ReconcileIssue::Collision { .. } =>
+                "Rename all but one of the topics in ConnectedText so that they map to different file names.".to_string(),
+            ReconcileIssue::ContentMismatch { .. } =>
+                "Run compare to see the differences, then export the topic again from whichever side is out of date.".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ReconcileIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReconcileIssue::TopicMissingFile { topic_name, file_name } =>
+                write!(f, "Topic without a file: \"{}\" (expected \"{}\")", topic_name, file_name),
+            ReconcileIssue::OrphanFile { path_file, .. } =>
+                write!(f, "File without a topic: \"{}\"", path_file.to_str().unwrap()),
+            ReconcileIssue::Collision { file_name, topic_names, path_file } => {
+                let names = topic_names.iter().map(|x| format!("\"{}\"", x)).collect::<Vec<_>>().join(", ");
+                match path_file {
+                    Some(path_file) => write!(f, "Topics {} all map to \"{}\"", names, path_file.to_str().unwrap()),
+                    None => write!(f, "Topics {} all map to \"{}\", which doesn't exist", names, file_name),
+                }
+            },
+            ReconcileIssue::ContentMismatch { path_file, topic_name } =>
+                write!(f, "Different content: \"{}\" and topic \"{}\" in the export file", path_file.to_str().unwrap(), topic_name),
+        }
+    }
+}
+
+pub fn reconcile(path_full_export_file: &path::Path, path_folder: &path::Path, encoding: Option<Encoding>) -> io::Result<ReconcileReport> {
+    assert!(path_folder.is_absolute());
+    paths::require_folder(path_folder)?;
+
+    // Group the topics by the lowercase file name they map to. Content is compared ignoring
+    // trailing whitespace, since the export file and the topic files don't end topics the same
+    // way.
+    let mut topics_by_file_name: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+    for (topic_name, content) in import::get_all_topics(path_full_export_file, encoding)? {
+        let file_name = topic_name_to_file_name(&topic_name).to_lowercase();
+        topics_by_file_name.entry(file_name).or_insert_with(|| { vec![] }).push((topic_name, content.trim_end().to_string()));
+    }
+
+    let mut report = ReconcileReport::default();
+    let mut orphan_files = vec![];
+    for path_file in parse::get_files_ci(path_folder, "*.txt")? {
+        let file_name = path_file.file_name().unwrap().to_str().unwrap().to_lowercase();
+        let topics = match topics_by_file_name.remove(&file_name) {
+            Some(topics) => topics,
+            None => {
+                orphan_files.push(path_file);
+                continue;
+            },
+        };
+        let decoded = input::read_file(&path_file, encoding)?;
+        decoded.print_problems(path_file.to_str().unwrap());
+        let content_file = decoded.text.trim_end();
+        if topics.len() == 1 {
+            let (topic_name, content) = topics.into_iter().next().unwrap();
+            if content != content_file {
+                report.issues.push(ReconcileIssue::ContentMismatch { path_file: path_file.clone(), topic_name: topic_name.clone() });
+            }
+            report.matched.insert(path_file, topic_name);
+            continue;
+        }
+        // Several topics share this file. If the file's content is the same as exactly one of
+        // them, that's the one it holds.
+        let mut same_content = topics.iter().filter(|(_, content)| content == content_file);
+        if let (Some((topic_name, _)), None) = (same_content.next(), same_content.next()) {
+            report.matched.insert(path_file.clone(), topic_name.clone());
+        }
+        let topic_names = topics.into_iter().map(|(topic_name, _)| topic_name).collect();
+        report.issues.push(ReconcileIssue::Collision { file_name, topic_names, path_file: Some(path_file) });
+    }
+
+    // Whatever is left has no file. A file that didn't match any topic may still hold one of
+    // these under an old name.
+    let mut topics_missing_files = vec![];
+    for (file_name, topics) in topics_by_file_name {
+        if topics.len() > 1 {
+            let topic_names = topics.iter().map(|(topic_name, _)| topic_name.clone()).collect();
+            report.issues.push(ReconcileIssue::Collision { file_name, topic_names, path_file: None });
+        }
+        topics_missing_files.extend(topics);
+    }
+    for path_file in orphan_files {
+        let decoded = input::read_file(&path_file, encoding)?;
+        decoded.print_problems(path_file.to_str().unwrap());
+        let content_file = decoded.text.trim_end();
+        let same_content_as = topics_missing_files.iter()
+            .find(|(_, content)| !content.is_empty() && content == content_file)
+            .map(|(topic_name, _)| topic_name.clone());
+        report.issues.push(ReconcileIssue::OrphanFile { path_file, same_content_as });
+    }
+    for (topic_name, _) in topics_missing_files {
+        let file_name = topic_name_to_file_name(&topic_name);
+        report.issues.push(ReconcileIssue::TopicMissingFile { topic_name, file_name });
+    }
+    Ok(report)
+}
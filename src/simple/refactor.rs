@@ -0,0 +1,726 @@
+use super::change::{Change, ChangeSet};
+use super::import;
+use super::model::{Link, Wiki};
+use super::writer;
+
+impl Wiki {
+    pub fn rename_topic(&self, project_name: &str, old_name: &str, new_name: &str) -> ChangeSet {
+        let mut change_set = ChangeSet::new(&format!("Rename {:?} to {:?}", old_name, new_name));
+        let topic = match self.find_topic(project_name, old_name) {
+            Some(topic) => topic,
+            None => {
+                change_set.add_problem(&format!("No topic {:?} in project {:?}.", old_name, project_name));
+                return change_set;
+            }
+        };
+        let new_name = new_name.trim();
+        if new_name.is_empty() {
+            change_set.add_problem("The new name is blank.");
+            return change_set;
+        }
+        // Allow a change that only affects case, such as "Git commands" to "Git Commands".
+        if !Wiki::same_name(&topic.name, new_name) && self.find_topic(project_name, new_name).is_some() {
+            change_set.add_problem(&format!("There is already a topic {:?} in project {:?}.", new_name, project_name));
+            return change_set;
+        }
+
+        let old_name = topic.name.clone();
+        change_set.push(Change::RenameTopic {
+            project_name: topic.project_name.clone(),
+            old_name: old_name.clone(),
+            new_name: new_name.to_string(),
+        });
+
+        for key in self.link_index().remove(&Wiki::topic_key(project_name, &old_name)).unwrap_or_default() {
+            let linking_topic = &self.topics[&key];
+            let (new_content, count) = rewrite_links(&linking_topic.content, |topic_name, section_name| {
+                if Wiki::same_name(topic_name.trim(), &old_name) {
+                    Some((new_name.to_string(), section_name.map(|x| x.to_string())))
+                } else {
+                    None
+                }
+            });
+            if count > 0 {
+                // The topic being renamed may link to itself, in which case the edit has to be made
+                // under the new name since the rename comes first.
+                let topic_name = if linking_topic.name == old_name { new_name.to_string() } else { linking_topic.name.clone() };
+                change_set.push(Change::EditContent {
+                    project_name: linking_topic.project_name.clone(),
+                    topic_name,
+                    old_content: linking_topic.content.clone(),
+                    new_content,
+                });
+            }
+        }
+        change_set
+    }
+
+    pub fn merge_topics(&self, project_name: &str, source_name: &str, target_name: &str) -> ChangeSet {
+        // Move the text and attributes of the source topic into the target topic, point every link
+        // to the source at the target instead, and delete the source.
+        let mut change_set = ChangeSet::new(&format!("Merge {:?} into {:?}", source_name, target_name));
+        let (source, target) = match (self.find_topic(project_name, source_name), self.find_topic(project_name, target_name)) {
+            (Some(source), Some(target)) => (source, target),
+            (source, target) => {
+                if source.is_none() {
+                    change_set.add_problem(&format!("No topic {:?} in project {:?}.", source_name, project_name));
+                }
+                if target.is_none() {
+                    change_set.add_problem(&format!("No topic {:?} in project {:?}.", target_name, project_name));
+                }
+                return change_set;
+            }
+        };
+        let source_key = Wiki::topic_key(project_name, &source.name);
+        let target_key = Wiki::topic_key(project_name, &target.name);
+        if source_key == target_key {
+            change_set.add_problem(&format!("Can't merge {:?} into itself.", source.name));
+            return change_set;
+        }
+        if source.category != target.category {
+            change_set.add_warning(&format!("Categories differ: {:?} has {:?} and {:?} has {:?}. Keeping {:?}.",
+                source.name, source.category, target.name, target.category, target.category));
+        }
+
+        let mut target_content = target.content.clone();
+        let target_rows = writer::attribute_rows(&target.content);
+        for source_row in writer::attribute_rows(&source.content) {
+            match target_rows.iter().find(|row| row.name == source_row.name) {
+                None => {
+                    target_content = writer::set_attribute_values(&target_content, &source_row.name, &source_row.values);
+                },
+                Some(target_row) => {
+                    let mut values = target_row.values.clone();
+                    for value in source_row.values.iter() {
+                        if !values.contains(value) {
+                            values.push(value.to_string());
+                        }
+                    }
+                    if values.len() == target_row.values.len() {
+                        continue;
+                    }
+                    let is_multiple = self.attribute_types.get(&source_row.name).map_or(false, |attribute_type| attribute_type.is_multiple());
+                    if is_multiple || target_row.values.is_empty() {
+                        target_content = writer::set_attribute_values(&target_content, &source_row.name, &values);
+                    } else {
+                        change_set.add_warning(&format!("Attribute {:?}: keeping {:?} from {:?} and dropping {:?} from {:?}.",
+                            source_row.name, target_row.values, target.name, source_row.values, source.name));
+                    }
+                },
+            }
+        }
+
+        let body = writer::body_without_attributes(&source.content);
+        if !body.is_empty() {
+            target_content = format!("{}\n\n=From {}=\n\n{}\n", target_content.trim_end(), source.name, body);
+        }
+
+        let redirect = |topic_name: &str, section_name: Option<&str>| {
+            if Wiki::same_name(topic_name.trim(), &source.name) {
+                Some((target.name.clone(), section_name.map(|x| x.to_string())))
+            } else {
+                None
+            }
+        };
+        let (target_content, _) = rewrite_links(&target_content, redirect);
+        change_set.push(Change::EditContent {
+            project_name: target.project_name.clone(),
+            topic_name: target.name.clone(),
+            old_content: target.content.clone(),
+            new_content: target_content,
+        });
+
+        for key in self.link_index().remove(&Wiki::topic_key(project_name, &source.name)).unwrap_or_default() {
+            if key == source_key || key == target_key {
+                continue;
+            }
+            let linking_topic = &self.topics[&key];
+            let (new_content, count) = rewrite_links(&linking_topic.content, redirect);
+            if count > 0 {
+                change_set.push(Change::EditContent {
+                    project_name: linking_topic.project_name.clone(),
+                    topic_name: linking_topic.name.clone(),
+                    old_content: linking_topic.content.clone(),
+                    new_content,
+                });
+            }
+        }
+
+        change_set.push(Change::DeleteTopic {
+            project_name: source.project_name.clone(),
+            topic_name: source.name.clone(),
+        });
+        change_set
+    }
+
+    pub fn split_topic(&self, project_name: &str, topic_name: &str, depth: usize) -> ChangeSet {
+        // Move each section headed at the given depth (1 for "=Heading=", 2 for "==Heading==", and
+        // so on) into a new topic named after the heading. The heading stays behind in the original
+        // topic followed by a link to the new topic. Links to the moved sections and to any
+        // subsections within them are pointed at the new topics.
+        let mut change_set = ChangeSet::new(&format!("Split {:?} at depth {}", topic_name, depth));
+        let topic = match self.find_topic(project_name, topic_name) {
+            Some(topic) => topic,
+            None => {
+                change_set.add_problem(&format!("No topic {:?} in project {:?}.", topic_name, project_name));
+                return change_set;
+            }
+        };
+        let topic_key = Wiki::topic_key(project_name, &topic.name);
+        let lines = writer::split_lines(&topic.content);
+
+        // Each section is (first line index, end line index, new topic name, subsection names).
+        let mut sections: Vec<(usize, usize, String, Vec<String>)> = vec![];
+        let mut open_section: Option<(usize, String, Vec<String>)> = None;
+        for (line_index, line) in lines.iter().enumerate() {
+            if let Some((heading_depth, heading)) = writer::heading_depth(line) {
+                if heading_depth <= depth {
+                    if let Some((start_index, name, subsections)) = open_section.take() {
+                        sections.push((start_index, line_index, name, subsections));
+                    }
+                    if heading_depth == depth {
+                        open_section = Some((line_index, heading, vec![]));
+                    }
+                } else if let Some((_, _, subsections)) = open_section.as_mut() {
+                    subsections.push(heading);
+                }
+            }
+        }
+        if let Some((start_index, name, subsections)) = open_section.take() {
+            sections.push((start_index, lines.len(), name, subsections));
+        }
+        if sections.is_empty() {
+            change_set.add_problem(&format!("{:?} has no headings at depth {}.", topic.name, depth));
+            return change_set;
+        }
+        for (index, (_, _, name, _)) in sections.iter().enumerate() {
+            if self.find_topic(project_name, name).is_some() {
+                change_set.add_problem(&format!("There is already a topic {:?} in project {:?}.", name, project_name));
+            }
+            if sections[..index].iter().any(|(_, _, other_name, _)| Wiki::same_name(other_name, name)) {
+                change_set.add_problem(&format!("More than one section is headed {:?}.", name));
+            }
+        }
+        if !change_set.problems.is_empty() {
+            return change_set;
+        }
+
+        let redirect = |link_topic_name: &str, section_name: Option<&str>| {
+            if !Wiki::same_name(link_topic_name.trim(), &topic.name) {
+                return None;
+            }
+            let section_name = section_name?;
+            for (_, _, name, subsections) in sections.iter() {
+                if Wiki::same_name(section_name.trim(), name) {
+                    return Some((name.clone(), None));
+                }
+                if subsections.iter().any(|subsection| Wiki::same_name(section_name.trim(), subsection)) {
+                    return Some((name.clone(), Some(section_name.to_string())));
+                }
+            }
+            None
+        };
+
+        let category_line = lines.iter().find(|line| writer::is_category_line(line)).map(|line| line.trim().to_string());
+        let mut new_content = String::new();
+        let mut next_line_index = 0;
+        let mut new_topics = vec![];
+        for (start_index, end_index, name, _) in sections.iter() {
+            new_content.push_str(&lines[next_line_index..*start_index].concat());
+            new_content.push_str(lines[*start_index]);
+            if !lines[*start_index].ends_with('\n') {
+                new_content.push('\n');
+            }
+            new_content.push_str(&format!("[[{}]]\n\n", name));
+            next_line_index = *end_index;
+
+            let body = lines[start_index + 1..*end_index].concat();
+            let section_content = match &category_line {
+                Some(category_line) => format!("{}\n\n{}\n", category_line, body.trim()),
+                None => format!("{}\n", body.trim()),
+            };
+            let (section_content, _) = rewrite_links(&section_content, redirect);
+            new_topics.push((name.clone(), section_content));
+        }
+        new_content.push_str(&lines[next_line_index..].concat());
+        let (new_content, _) = rewrite_links(&new_content, redirect);
+
+        change_set.push(Change::EditContent {
+            project_name: topic.project_name.clone(),
+            topic_name: topic.name.clone(),
+            old_content: topic.content.clone(),
+            new_content,
+        });
+        for (name, content) in new_topics {
+            change_set.push(Change::CreateTopic {
+                project_name: topic.project_name.clone(),
+                topic_name: name,
+                content,
+            });
+        }
+        for key in self.link_index().remove(&Wiki::topic_key(project_name, &topic.name)).unwrap_or_default() {
+            if key == topic_key {
+                continue;
+            }
+            let linking_topic = &self.topics[&key];
+            let (new_content, count) = rewrite_links(&linking_topic.content, redirect);
+            if count > 0 {
+                change_set.push(Change::EditContent {
+                    project_name: linking_topic.project_name.clone(),
+                    topic_name: linking_topic.name.clone(),
+                    old_content: linking_topic.content.clone(),
+                    new_content,
+                });
+            }
+        }
+        change_set
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkFixup {
+    // Leave every link in place and only report the ones whose resolution changes.
+    Leave,
+    // Replace links that would stop resolving or resolve to a different topic with their label,
+    // or the topic name if there's no label. This covers links to a moved topic from the old
+    // project and links from a moved topic to topics that stay behind.
+    Unlink,
+    // As Unlink, and also unlink previously dangling links that would now resolve to a moved
+    // topic or, from a moved topic, to a topic in the new project, so that no link changes
+    // what it points to.
+    UnlinkAll,
+}
+
+#[derive(Debug, Default)]
+pub struct MoveReport {
+    // Each entry is (linking topic, linked topic name).
+    pub broken_inbound: Vec<(String, String)>,
+    pub dangling_outbound: Vec<(String, String)>,
+    pub retargeted_outbound: Vec<(String, String)>,
+    pub newly_resolved: Vec<(String, String)>,
+}
+
+impl MoveReport {
+    pub fn print(&self) {
+        print_link_pairs("Links in the old project that will no longer resolve", &self.broken_inbound);
+        print_link_pairs("Links from moved topics that will no longer resolve", &self.dangling_outbound);
+        print_link_pairs("Links from moved topics that will resolve to a different topic", &self.retargeted_outbound);
+        print_link_pairs("Links that will now resolve to a moved topic", &self.newly_resolved);
+    }
+
+    pub fn affected_topics(&self) -> Vec<String> {
+        let mut topic_names = self.broken_inbound.iter()
+            .chain(self.dangling_outbound.iter())
+            .chain(self.retargeted_outbound.iter())
+            .chain(self.newly_resolved.iter())
+            .map(|(topic_name, _)| topic_name.to_string())
+            .collect::<Vec<_>>();
+        topic_names.sort();
+        topic_names.dedup();
+        topic_names
+    }
+}
+
+fn print_link_pairs(label: &str, pairs: &Vec<(String, String)>) {
+    println!("\n{} ({}):", label, pairs.len());
+    for (topic_name, link_topic_name) in pairs.iter() {
+        println!("  {} -> {}", topic_name, link_topic_name);
+    }
+}
+
+impl Wiki {
+    pub fn move_topic(&self, old_project_name: &str, topic_name: &str, new_project_name: &str, fixup: LinkFixup) -> (ChangeSet, MoveReport) {
+        self.move_topics(old_project_name, &[topic_name], new_project_name, fixup)
+    }
+
+    pub fn move_category(&self, old_project_name: &str, category: &str, new_project_name: &str, fixup: LinkFixup) -> (ChangeSet, MoveReport) {
+        let topic_names = self.topics.values()
+            .filter(|topic| Wiki::same_name(&topic.project_name, old_project_name))
+            .filter(|topic| topic.category.as_ref().map_or(false, |x| Wiki::same_name(x, category)))
+            .map(|topic| topic.name.as_str())
+            .collect::<Vec<_>>();
+        if topic_names.is_empty() {
+            let mut change_set = ChangeSet::new(&format!("Move category {:?} to {}", category, new_project_name));
+            change_set.add_problem(&format!("No topics in category {:?} in project {:?}.", category, old_project_name));
+            return (change_set, MoveReport::default());
+        }
+        self.move_topics(old_project_name, &topic_names, new_project_name, fixup)
+    }
+
+    pub fn move_topics(&self, old_project_name: &str, topic_names: &[&str], new_project_name: &str, fixup: LinkFixup) -> (ChangeSet, MoveReport) {
+        // Internal links always resolve within the linking topic's project, so moving a topic can
+        // break links to it from the old project, break or retarget its own links, and make
+        // previously dangling links in the new project resolve. Links among the moved topics
+        // themselves are unaffected since they all move together.
+        let mut change_set = ChangeSet::new(&format!("Move {} topic(s) from {} to {}", topic_names.len(), old_project_name, new_project_name));
+        let mut report = MoveReport::default();
+        if Wiki::same_name(old_project_name, new_project_name) {
+            change_set.add_problem("The old and new projects are the same.");
+            return (change_set, report);
+        }
+        let mut topics = vec![];
+        for topic_name in topic_names.iter() {
+            match self.find_topic(old_project_name, topic_name) {
+                Some(topic) => {
+                    if self.find_topic(new_project_name, &topic.name).is_some() {
+                        change_set.add_problem(&format!("There is already a topic {:?} in project {:?}.", topic.name, new_project_name));
+                    }
+                    topics.push(topic);
+                },
+                None => change_set.add_problem(&format!("No topic {:?} in project {:?}.", topic_name, old_project_name)),
+            }
+        }
+        if !change_set.problems.is_empty() {
+            return (change_set, report);
+        }
+        let is_moved = |topic_name: &str| topics.iter().any(|topic| Wiki::same_name(&topic.name, topic_name.trim()));
+
+        for topic in topics.iter() {
+            let mut link_topic_names = import::parse_links(&topic.content).into_iter()
+                .filter_map(|link| match link {
+                    Link::Internal { topic_name, .. } => Some(topic_name.trim().to_string()),
+                    _ => None,
+                })
+                .filter(|link_topic_name| !is_moved(link_topic_name))
+                .collect::<Vec<_>>();
+            link_topic_names.sort_by_key(|x| x.to_lowercase());
+            link_topic_names.dedup_by_key(|x| x.to_lowercase());
+            let mut unlink_names = vec![];
+            for link_topic_name in link_topic_names {
+                let pair = (topic.name.clone(), link_topic_name.clone());
+                let unlink = match (self.find_topic(old_project_name, &link_topic_name).is_some(), self.find_topic(new_project_name, &link_topic_name).is_some()) {
+                    (true, false) => {
+                        report.dangling_outbound.push(pair);
+                        fixup != LinkFixup::Leave
+                    },
+                    (true, true) => {
+                        report.retargeted_outbound.push(pair);
+                        fixup != LinkFixup::Leave
+                    },
+                    (false, true) => {
+                        report.newly_resolved.push(pair);
+                        fixup == LinkFixup::UnlinkAll
+                    },
+                    (false, false) => false,
+                };
+                if unlink {
+                    unlink_names.push(link_topic_name.to_lowercase());
+                }
+            }
+            // Edit the topic while it's still in the old project, since the changes are applied
+            // in order.
+            if !unlink_names.is_empty() {
+                let (new_content, count) = unlink_links(&topic.content, |topic_name, _| unlink_names.contains(&topic_name.trim().to_lowercase()));
+                if count > 0 {
+                    change_set.push(Change::EditContent {
+                        project_name: topic.project_name.clone(),
+                        topic_name: topic.name.clone(),
+                        old_content: topic.content.clone(),
+                        new_content,
+                    });
+                }
+            }
+            change_set.push(Change::MoveTopic {
+                old_project_name: topic.project_name.clone(),
+                new_project_name: new_project_name.to_string(),
+                topic_name: topic.name.clone(),
+            });
+        }
+
+        let link_index = self.link_index();
+        let mut linking_keys = vec![];
+        for topic in topics.iter() {
+            if let Some(keys) = link_index.get(&Wiki::topic_key(old_project_name, &topic.name)) {
+                for key in keys.iter() {
+                    let linking_topic = &self.topics[key];
+                    if !is_moved(&linking_topic.name) {
+                        report.broken_inbound.push((linking_topic.name.clone(), topic.name.clone()));
+                        if !linking_keys.contains(key) {
+                            linking_keys.push(key.to_string());
+                        }
+                    }
+                }
+            }
+            if let Some(keys) = link_index.get(&Wiki::topic_key(new_project_name, &topic.name)) {
+                for key in keys.iter() {
+                    report.newly_resolved.push((self.topics[key].name.clone(), topic.name.clone()));
+                    if fixup == LinkFixup::UnlinkAll && !linking_keys.contains(key) {
+                        linking_keys.push(key.to_string());
+                    }
+                }
+            }
+        }
+
+        if fixup != LinkFixup::Leave {
+            // The linking topics all stay where they are, in either the old or the new project,
+            // and every link they have to a moved topic changes what it resolves to.
+            for key in linking_keys.iter() {
+                let linking_topic = &self.topics[key];
+                let (new_content, count) = unlink_links(&linking_topic.content, |topic_name, _| is_moved(topic_name));
+                if count > 0 {
+                    change_set.push(Change::EditContent {
+                        project_name: linking_topic.project_name.clone(),
+                        topic_name: linking_topic.name.clone(),
+                        old_content: linking_topic.content.clone(),
+                        new_content,
+                    });
+                }
+            }
+        }
+        (change_set, report)
+    }
+}
+
+pub fn rewrite_links<F>(content: &str, f: F) -> (String, usize)
+    where F: Fn(&str, Option<&str>) -> Option<(String, Option<String>)>
+{
+    // Rewrite internal links such as [[Old Name]] and [[Old Name#Section|label]]. The closure gets
+    // the topic and section names from each link and returns the replacement topic and section
+    // names, or None to leave the link alone. The label is always kept.
+    rewrite_link_entries(content, |topic_name, section_name, label| {
+        let (new_topic_name, new_section_name) = f(topic_name, section_name)?;
+        let mut s = format!("[[{}", new_topic_name);
+        if let Some(new_section_name) = new_section_name {
+            s.push_str(&format!("#{}", new_section_name));
+        }
+        if let Some(label) = label {
+            s.push_str(&format!("|{}", label));
+        }
+        s.push_str("]]");
+        Some(s)
+    })
+}
+
+pub fn unlink_links<F>(content: &str, f: F) -> (String, usize)
+    where F: Fn(&str, Option<&str>) -> bool
+{
+    // Replace matching internal links with plain text: the label if there is one, otherwise the
+    // topic name.
+    rewrite_link_entries(content, |topic_name, section_name, label| {
+        if f(topic_name, section_name) {
+            Some(label.unwrap_or(topic_name).trim().to_string())
+        } else {
+            None
+        }
+    })
+}
+
+fn rewrite_link_entries<F>(content: &str, f: F) -> (String, usize)
+    where F: Fn(&str, Option<&str>, Option<&str>) -> Option<String>
+{
+    // The closure gets the topic name, section name and label of each internal link and returns
+    // the full replacement text including any brackets, or None to leave the link alone. Tags
+    // like $IMG and $URL and attribute values like [[Subject:=History]] are skipped the same way
+    // as in import::parse_links().
+    let mut s = String::with_capacity(content.len());
+    let mut count = 0;
+    let mut remaining = content;
+    while let Some(start) = remaining.find("[[") {
+        let after_start = &remaining[start + 2..];
+        let end = match after_start.find("]]") {
+            Some(end) => end,
+            None => break,
+        };
+        let entry = &after_start[..end];
+        s.push_str(&remaining[..start]);
+        let mut replacement = None;
+        if !entry.starts_with("$") && !entry.contains(":=") {
+            let (link, label) = match entry.split_once("|") {
+                Some((link, label)) => (link, Some(label)),
+                None => (entry, None),
+            };
+            let (topic_name, section_name) = match link.split_once("#") {
+                Some((topic_name, section_name)) => (topic_name, Some(section_name)),
+                None => (link, None),
+            };
+            replacement = f(topic_name, section_name, label);
+        }
+        match replacement {
+            Some(replacement) => {
+                s.push_str(&replacement);
+                count += 1;
+            },
+            None => {
+                s.push_str("[[");
+                s.push_str(entry);
+                s.push_str("]]");
+            },
+        }
+        remaining = &after_start[end + 2..];
+    }
+    s.push_str(remaining);
+    (s, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::model::Topic;
+
+    fn wiki(topics: &[(&str, &str, &str)]) -> Wiki {
+        let mut wiki = Wiki::new();
+        for (project_name, topic_name, content) in topics.iter() {
+            wiki.add_topic(Topic::new(project_name, topic_name, content));
+        }
+        wiki.resolve_attributes();
+        wiki
+    }
+
+    fn edited_content<'a>(change_set: &'a ChangeSet, topic_name: &str) -> Option<&'a str> {
+        change_set.changes.iter().find_map(|change| match change {
+            Change::EditContent { topic_name: edited, new_content, .. } if edited == topic_name => Some(new_content.as_str()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn rewrite_link_forms() {
+        let content = "[[Old]] [[old#Part|the part]] [[ Old |label]] [[Other]] [[$URL:http://x.com/Old]] ||Ref||[[Ref:=Old]]|| [[Old";
+        let (new_content, count) = rewrite_links(content, |topic_name, section_name| {
+            if Wiki::same_name(topic_name.trim(), "Old") { Some(("New".to_string(), section_name.map(|x| x.to_string()))) } else { None }
+        });
+        assert_eq!(count, 3);
+        assert_eq!(new_content, "[[New]] [[New#Part|the part]] [[New|label]] [[Other]] [[$URL:http://x.com/Old]] ||Ref||[[Ref:=Old]]|| [[Old");
+    }
+
+    #[test]
+    fn unlink_link_forms() {
+        let (new_content, count) = unlink_links("[[Gone]] and [[gone#Part| the label ]] but [[Kept]]", |topic_name, _| Wiki::same_name(topic_name, "Gone"));
+        assert_eq!(count, 2);
+        assert_eq!(new_content, "Gone and the label but [[Kept]]");
+    }
+
+    #[test]
+    fn rename_rewrites_links() {
+        let wiki = wiki(&[
+            ("P", "Über", "See [[Über]].\n"),
+            ("P", "Linking", "[[über#Section|label]] and [[ÜBER]] and [[Other]]\n"),
+            ("Q", "Elsewhere", "[[Über]] is in another project.\n"),
+        ]);
+        let change_set = wiki.rename_topic("P", "Über", "Neu");
+        assert!(change_set.problems.is_empty());
+        assert_eq!(edited_content(&change_set, "Linking"), Some("[[Neu#Section|label]] and [[Neu]] and [[Other]]\n"));
+        // The topic's link to itself is edited under its new name.
+        assert_eq!(edited_content(&change_set, "Neu"), Some("See [[Neu]].\n"));
+        assert_eq!(edited_content(&change_set, "Elsewhere"), None);
+
+        let mut wiki = wiki;
+        change_set.apply(&mut wiki).unwrap();
+        assert!(wiki.find_topic("P", "über").is_none());
+        assert_eq!(wiki.find_topic("P", "neu").unwrap().content, "See [[Neu]].\n");
+    }
+
+    #[test]
+    fn rename_problems() {
+        let wiki = wiki(&[("P", "Git commands", "Text\n"), ("P", "Other", "Text\n")]);
+        assert!(wiki.rename_topic("P", "Git commands", "Git Commands").problems.is_empty());
+        assert_eq!(wiki.rename_topic("P", "Git commands", "OTHER").problems.len(), 1);
+        assert_eq!(wiki.rename_topic("P", "Missing", "New").problems.len(), 1);
+        assert_eq!(wiki.rename_topic("P", "Other", " ").problems.len(), 1);
+    }
+
+    #[test]
+    fn merge_combines_attributes() {
+        let wiki = wiki(&[
+            ("P", "Target", "[[$CATEGORY:Books]]\n\n{|\n||Author||[[Author:=A]]||\n||Year||[[Year:=2000]]||\n|}\n\nTarget text, see [[Source#Notes|notes]].\n"),
+            ("P", "Source", "[[$CATEGORY:Books]]\n\n{|\n||Author||[[Author:=B]], [[Author:=A]]||\n||Year||[[Year:=2001]]||\n||Format||[[Format:=Paper]]||\n|}\n\nSource text.\n"),
+            ("P", "Linking", "[[source]] and [[Target]]\n"),
+        ]);
+        let change_set = wiki.merge_topics("P", "Source", "Target");
+        assert!(change_set.problems.is_empty());
+        // Author can have more than one value so both are kept, while Year can't and keeps the
+        // target's value with a warning.
+        assert_eq!(edited_content(&change_set, "Target"), Some("[[$CATEGORY:Books]]\n\n{|\n||Author||[[Author:=A]], [[Author:=B]]||\n||Year||[[Year:=2000]]||\n||Format||[[Format:=Paper]]||\n|}\n\nTarget text, see [[Target#Notes|notes]].\n\n=From Source=\n\nSource text.\n"));
+        assert_eq!(change_set.warnings.len(), 1);
+        assert!(change_set.warnings[0].contains("Year"));
+        assert_eq!(edited_content(&change_set, "Linking"), Some("[[Target]] and [[Target]]\n"));
+        assert!(matches!(change_set.changes.last(), Some(Change::DeleteTopic { topic_name, .. }) if topic_name == "Source"));
+        assert_eq!(wiki.merge_topics("P", "Source", "source").problems.len(), 1);
+    }
+
+    #[test]
+    fn split_at_first_and_last_heading() {
+        let wiki = wiki(&[
+            ("P", "Topic", "[[$CATEGORY:Notes]]\n\nIntro\n\n=First=\nOne [[Topic#Sub]]\n==Sub==\nSub text\n=Last=\nLast text\n"),
+            ("P", "Other", "[[Topic#Last|end]] [[Topic#Intro]] [[topic#sub]] [[Topic]]\n"),
+            ("P", "Starts", "=A=\na\n=B=\nb"),
+        ]);
+        let change_set = wiki.split_topic("P", "Topic", 1);
+        assert!(change_set.problems.is_empty());
+        assert_eq!(edited_content(&change_set, "Topic"), Some("[[$CATEGORY:Notes]]\n\nIntro\n\n=First=\n[[First]]\n\n=Last=\n[[Last]]\n\n"));
+        let created = change_set.changes.iter().filter_map(|change| match change {
+            Change::CreateTopic { topic_name, content, .. } => Some((topic_name.as_str(), content.as_str())),
+            _ => None,
+        }).collect::<Vec<_>>();
+        assert_eq!(created, vec![
+            ("First", "[[$CATEGORY:Notes]]\n\nOne [[First#Sub]]\n==Sub==\nSub text\n"),
+            ("Last", "[[$CATEGORY:Notes]]\n\nLast text\n"),
+        ]);
+        assert_eq!(edited_content(&change_set, "Other"), Some("[[Last|end]] [[Topic#Intro]] [[First#sub]] [[Topic]]\n"));
+
+        // A topic that starts with a heading and has no line ending at the end.
+        let change_set = wiki.split_topic("P", "Starts", 1);
+        assert_eq!(edited_content(&change_set, "Starts"), Some("=A=\n[[A]]\n\n=B=\n[[B]]\n\n"));
+        assert_eq!(change_set.changes.len(), 3);
+
+        assert_eq!(wiki.split_topic("P", "Starts", 2).problems.len(), 1);
+        // "First" would be made twice.
+        let wiki = self::wiki(&[("P", "Twice", "=First=\na\n=first=\nb\n")]);
+        assert_eq!(wiki.split_topic("P", "Twice", 1).problems.len(), 1);
+    }
+
+    fn move_wiki() -> Wiki {
+        wiki(&[
+            ("P", "Moved", "[[$CATEGORY:Über]]\n[[Stays]] [[Both]] [[OnlyNew]] [[Moved2]] [[Nowhere]]\n"),
+            ("P", "Moved2", "[[$CATEGORY:über]]\nText\n"),
+            ("P", "Stays", "[[Moved]] and [[moved2|two]]\n"),
+            ("P", "Both", "Text\n"),
+            ("Q", "Both", "Text\n"),
+            ("Q", "OnlyNew", "Text\n"),
+            ("Q", "Waiting", "[[Moved]]\n"),
+        ])
+    }
+
+    #[test]
+    fn move_report() {
+        let (change_set, report) = move_wiki().move_category("p", "ÜBER", "Q", LinkFixup::Leave);
+        assert!(change_set.problems.is_empty());
+        let pair = |a: &str, b: &str| (a.to_string(), b.to_string());
+        assert_eq!(report.dangling_outbound, vec![pair("Moved", "Stays")]);
+        assert_eq!(report.retargeted_outbound, vec![pair("Moved", "Both")]);
+        assert_eq!(report.newly_resolved, vec![pair("Moved", "OnlyNew"), pair("Waiting", "Moved")]);
+        assert_eq!(report.broken_inbound, vec![pair("Stays", "Moved"), pair("Stays", "Moved2")]);
+        // Leave only moves the topics.
+        assert_eq!(change_set.changes.len(), 2);
+        assert!(change_set.changes.iter().all(|change| matches!(change, Change::MoveTopic { .. })));
+    }
+
+    #[test]
+    fn move_unlink() {
+        let mut wiki = move_wiki();
+        let (change_set, _) = wiki.move_topics("P", &["Moved", "Moved2"], "Q", LinkFixup::Unlink);
+        assert_eq!(edited_content(&change_set, "Moved"), Some("[[$CATEGORY:Über]]\nStays Both [[OnlyNew]] [[Moved2]] [[Nowhere]]\n"));
+        assert_eq!(edited_content(&change_set, "Stays"), Some("Moved and two\n"));
+        assert_eq!(edited_content(&change_set, "Waiting"), None);
+        change_set.apply(&mut wiki).unwrap();
+        assert!(wiki.find_topic("P", "Moved").is_none());
+        assert_eq!(wiki.find_topic("Q", "Moved").unwrap().content, "[[$CATEGORY:Über]]\nStays Both [[OnlyNew]] [[Moved2]] [[Nowhere]]\n");
+    }
+
+    #[test]
+    fn move_unlink_all() {
+        let (change_set, _) = move_wiki().move_topics("P", &["Moved", "Moved2"], "Q", LinkFixup::UnlinkAll);
+        assert_eq!(edited_content(&change_set, "Moved"), Some("[[$CATEGORY:Über]]\nStays Both OnlyNew [[Moved2]] [[Nowhere]]\n"));
+        assert_eq!(edited_content(&change_set, "Stays"), Some("Moved and two\n"));
+        assert_eq!(edited_content(&change_set, "Waiting"), Some("Moved\n"));
+    }
+
+    #[test]
+    fn move_problems() {
+        let wiki = move_wiki();
+        assert_eq!(wiki.move_topic("P", "Both", "Q", LinkFixup::Leave).0.problems.len(), 1);
+        assert_eq!(wiki.move_topic("P", "Moved", "p", LinkFixup::Leave).0.problems.len(), 1);
+        assert_eq!(wiki.move_topic("P", "Missing", "Q", LinkFixup::Leave).0.problems.len(), 1);
+        assert_eq!(wiki.move_category("P", "None", "Q", LinkFixup::Leave).0.problems.len(), 1);
+    }
+}
@@ -0,0 +1,139 @@
+use std::{fs, io, path};
+use std::collections::{BTreeMap, HashSet};
+use std::io::BufRead;
+use crate::*;
+use super::input::{self, DecodeProblem, Encoding, LineEnding, TextFormat};
+use super::model::{Topic, Wiki};
+
+// Reading a {{Topic}}-delimited export one topic at a time. The combined export can be hundreds
+// of megabytes, so instead of decoding the whole file and splitting it into a map of sections
+// like import::import_topics(), this keeps only the current topic in memory.
+
+// Enough of the file to tell UTF-8 from Windows-1252 when no encoding is given.
+const SAMPLE_SIZE: usize = 64 * 1024;
+
+pub struct TopicStream<R: BufRead> {
+    reader: R,
+    project_name: String,
+    encoding: Encoding,
+    line_ending: LineEnding,
+    bom: bool,
+    // Where the next line starts, as a byte offset in the file and a line number starting at 1.
+    position: usize,
+    line_number: usize,
+    current: Option<(String, String)>,
+    bytes_line: Vec<u8>,
+    pub problems: Vec<DecodeProblem>,
+}
+
+impl TopicStream<io::BufReader<fs::File>> {
+    pub fn open(path_file: &path::Path, project_name: &str, encoding: Option<Encoding>) -> io::Result<Self> {
+        let reader = io::BufReader::with_capacity(SAMPLE_SIZE, fs::File::open(path_file)?);
+        Self::new(reader, project_name, encoding)
+    }
+}
+
+impl<R: BufRead> TopicStream<R> {
+    pub fn new(mut reader: R, project_name: &str, encoding: Option<Encoding>) -> io::Result<Self> {
+        // Look at the start of the file without consuming it, except for any byte order mark.
+        let sample = reader.fill_buf()?;
+        let (sample_text, bom) = input::strip_bom(sample);
+        let encoding = encoding.unwrap_or_else(|| if bom { Encoding::Utf8 } else { input::detect_encoding(sample_text) });
+        // The line endings come from the same sample, since topics are handed out before the rest
+        // of the file has been read.
+        let count_crlf = sample_text.windows(2).filter(|x| *x == b"\r\n").count();
+        let count_lf = sample_text.iter().filter(|x| **x == b'\n').count() - count_crlf;
+        let line_ending = if count_crlf > count_lf { LineEnding::CrLf } else { LineEnding::Lf };
+        let position = sample.len() - sample_text.len();
+        reader.consume(position);
+        Ok(Self {
+            reader,
+            project_name: project_name.to_string(),
+            encoding,
+            line_ending,
+            bom,
+            position,
+            line_number: 1,
+            current: None,
+            bytes_line: vec![],
+            problems: vec![],
+        })
+    }
+
+    pub fn text_format(&self) -> TextFormat {
+        TextFormat { encoding: self.encoding, line_ending: self.line_ending, bom: self.bom }
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        self.bytes_line.clear();
+        if self.reader.read_until(b'\n', &mut self.bytes_line)? == 0 {
+            return Ok(None);
+        }
+        let (line, bad_positions) = input::decode_bytes(&self.bytes_line, self.encoding);
+        for (position_text, position_bytes, bytes) in bad_positions {
+            let column = line[..position_text].chars().count() + 1;
+            self.problems.push(DecodeProblem { position: self.position + position_bytes, line: self.line_number, column, bytes });
+        }
+        self.position += self.bytes_line.len();
+        self.line_number += 1;
+        let (line, _, _) = input::normalize_line_endings(&line);
+        Ok(Some(line))
+    }
+
+    fn next_topic(&mut self) -> io::Result<Option<Topic>> {
+        while let Some(line) = self.read_line()? {
+            match line.strip_prefix(DELIMITER_TOPIC) {
+                Some(name) => {
+                    let previous = self.current.replace((name.trim().to_string(), String::new()));
+                    if let Some((name, content)) = previous {
+                        return Ok(Some(self.make_topic(&name, &content)));
+                    }
+                },
+                None => {
+                    // Anything before the first delimiter isn't part of a topic.
+                    if let Some((_, content)) = self.current.as_mut() {
+                        content.push_str(&line);
+                    }
+                },
+            }
+        }
+        Ok(self.current.take().map(|(name, content)| self.make_topic(&name, &content)))
+    }
+
+    fn make_topic(&self, name: &str, content: &str) -> Topic {
+        // Attributes and links are filled in as they would be after import, but the attribute
+        // statistics are thrown away since they'd grow with the whole export.
+        let mut topic = Topic::new(&self.project_name, name, content);
+        topic.resolve(&mut BTreeMap::new());
+        topic
+    }
+}
+
+impl<R: BufRead> Iterator for TopicStream<R> {
+    type Item = io::Result<Topic>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_topic().transpose()
+    }
+}
+
+pub fn topic_names(path_file: &path::Path, project_name: &str, encoding: Option<Encoding>) -> io::Result<Vec<String>> {
+    // The name of every topic in the file, for things like links to missing topics and export
+    // file names that need to know about all of the topics but not their content. Only the
+    // delimiter lines matter, so the topics aren't built, which would also report their
+    // attribute problems a second time.
+    let mut stream = TopicStream::open(path_file, project_name, encoding)?;
+    let mut names = vec![];
+    while let Some(line) = stream.read_line()? {
+        if let Some(name) = line.strip_prefix(DELIMITER_TOPIC) {
+            names.push(name.trim().to_string());
+        }
+    }
+    Ok(names)
+}
+
+pub fn topic_keys(path_file: &path::Path, project_name: &str, encoding: Option<Encoding>) -> io::Result<HashSet<String>> {
+    Ok(topic_names(path_file, project_name, encoding)?.iter()
+        .map(|name| Wiki::topic_key(project_name, name))
+        .collect())
+}
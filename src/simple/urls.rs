@@ -0,0 +1,397 @@
+use std::collections::BTreeMap;
+use util::parse;
+use super::model::{Link, Topic, Wiki};
+
+// Links to web pages, either [[$URL:https://example.com|Label]] or a URL typed into the text. The
+// link keeps the URL as it's written so that it can be found again in the content, and
+// normalize_url() gives the form used to compare and group them.
+
+const SCHEMES: [&str; 5] = ["http", "https", "ftp", "mailto", "file"];
+
+// Query parameters that only say where a visitor came from.
+const TRACKING_PARAMS: [&str; 13] = ["fbclid", "gclid", "dclid", "gbraid", "wbraid", "msclkid", "mc_cid",
+    "mc_eid", "igshid", "yclid", "_hsenc", "_hsmi", "ref_src"];
+
+pub fn parse_url_links(content: &str) -> Vec<Link> {
+    // The $URL links first and then the bare URLs in the text outside of double square brackets.
+    let mut links = vec![];
+    for entry in parse::delimited_entries(content, "[[$URL:", "]]").iter() {
+        let (url, label) = match entry.split_once("|") {
+            Some((url, label)) => (url.trim(), Some(label.trim().to_string()).filter(|x| !x.is_empty())),
+            None => (entry.trim(), None),
+        };
+        links.push(Link::Url { url: url.to_string(), label });
+    }
+    for url in bare_urls(content) {
+        links.push(Link::Url { url, label: None });
+    }
+    links
+}
+
+pub fn bare_urls(content: &str) -> Vec<String> {
+    bare_url_spans(content).into_iter()
+        .map(|(start, end)| content[start..end].to_string())
+        .collect()
+}
+
+pub fn url_spans(content: &str) -> Vec<(usize, usize)> {
+    // The start and end of each URL in the content, both in $URL links and bare, in the order
+    // they appear. Used to replace URLs without touching anything around them.
+    let mut spans = bare_url_spans(content);
+    let mut position = 0;
+    while let Some(start) = content[position..].find("[[$URL:") {
+        let start_url = position + start + "[[$URL:".len();
+        let end_entry = match content[start_url..].find("]]") {
+            Some(end) => start_url + end,
+            None => break,
+        };
+        let entry = &content[start_url..end_entry];
+        let url = entry.split('|').next().unwrap();
+        let start_trimmed = start_url + (url.len() - url.trim_start().len());
+        spans.push((start_trimmed, start_trimmed + url.trim().len()));
+        position = end_entry + 2;
+    }
+    spans.sort();
+    spans
+}
+
+fn bare_url_spans(content: &str) -> Vec<(usize, usize)> {
+    let mut spans = vec![];
+    for (offset, text) in text_outside_brackets(content) {
+        let mut position = 0;
+        while let Some(start) = find_url_start(&text[position..]) {
+            let after = &text[position + start..];
+            let end = after.find(|c: char| c.is_whitespace() || c == '<' || c == '>' || c == '"').unwrap_or(after.len());
+            let url = trim_url_end(&after[..end]);
+            if url.contains("://") || url.starts_with("www.") {
+                spans.push((offset + position + start, offset + position + start + url.len()));
+            }
+            position += start + end;
+        }
+    }
+    spans
+}
+
+fn text_outside_brackets(content: &str) -> Vec<(usize, &str)> {
+    // Each piece of text with its position in the content.
+    let mut parts = vec![];
+    let mut position = 0;
+    while let Some(start) = content[position..].find("[[") {
+        parts.push((position, &content[position..position + start]));
+        position = match content[position + start..].find("]]") {
+            Some(end) => position + start + end + 2,
+            None => content.len(),
+        };
+    }
+    parts.push((position, &content[position..]));
+    parts
+}
+
+fn find_url_start(text: &str) -> Option<usize> {
+    // The start of "http://", "https://", "ftp://" or "www." at the start of a word.
+    let candidates = ["http://", "https://", "ftp://", "www."];
+    // Only ASCII is lowercased so that the positions are the same as in text.
+    let lower = text.to_ascii_lowercase();
+    candidates.iter()
+        .flat_map(|candidate| lower.match_indices(candidate).map(|(position, _)| position).collect::<Vec<_>>())
+        .filter(|position| *position == 0 || !lower[..*position].chars().last().unwrap().is_alphanumeric())
+        .min()
+}
+
+fn trim_url_end(url: &str) -> &str {
+    // Punctuation at the end is usually part of the sentence, except for a closing parenthesis that
+    // matches one in the URL, as in https://en.wikipedia.org/wiki/Rust_(programming_language).
+    let mut url = url;
+    loop {
+        let last = match url.chars().last() {
+            Some(last) => last,
+            None => return url,
+        };
+        let is_balanced = last == ')' && url.matches('(').count() >= url.matches(')').count();
+        if ".,;:!?'*".contains(last) || ((last == ')' || last == ']' || last == '}') && !is_balanced) {
+            url = &url[..url.len() - 1];
+        } else {
+            return url;
+        }
+    }
+}
+
+pub fn validate_url(url: &str) -> Vec<String> {
+    // Problems with the syntax only. Whether the page is still there is a different question.
+    let mut problems = vec![];
+    let url = url.trim();
+    if url.is_empty() {
+        return vec!["Empty URL.".to_string()];
+    }
+    if url.to_lowercase().starts_with("www.") {
+        return validate_url(&format!("http://{}", url));
+    }
+    if url.chars().any(|c| c.is_whitespace()) {
+        problems.push("Contains a space.".to_string());
+    }
+    let (scheme, rest) = match url.split_once(":") {
+        Some((scheme, rest)) if !scheme.is_empty() && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)) => (scheme.to_lowercase(), rest),
+        _ => {
+            problems.push("No scheme such as https://.".to_string());
+            return problems;
+        },
+    };
+    if !SCHEMES.contains(&scheme.as_str()) {
+        problems.push(format!("Unknown scheme \"{}\".", scheme));
+        return problems;
+    }
+    if scheme == "mailto" {
+        if !rest.contains('@') {
+            problems.push("No @ in the email address.".to_string());
+        }
+        return problems;
+    }
+    let rest = match rest.strip_prefix("//") {
+        Some(rest) => rest,
+        None => {
+            problems.push(format!("Expected \"//\" after \"{}:\".", scheme));
+            return problems;
+        },
+    };
+    let authority = rest.split(|c| c == '/' || c == '?' || c == '#').next().unwrap();
+    let host_port = authority.rsplit('@').next().unwrap();
+    let (host, port) = split_port(host_port);
+    if host.is_empty() && scheme != "file" {
+        problems.push("No host name.".to_string());
+    } else if !host.starts_with('[') {
+        if host.split('.').any(|label| label.is_empty()) && !host.is_empty() {
+            problems.push(format!("Host name \"{}\" has an empty part.", host));
+        }
+        if host.chars().any(|c| !(c.is_alphanumeric() || c == '-' || c == '.' || c == '_')) {
+            problems.push(format!("Host name \"{}\" has characters that aren't allowed.", host));
+        }
+    }
+    if let Some(port) = port {
+        if port.parse::<u16>().is_err() {
+            problems.push(format!("Port \"{}\" isn't a number.", port));
+        }
+    }
+    problems
+}
+
+fn split_port(host_port: &str) -> (&str, Option<&str>) {
+    // An IPv6 address in square brackets has colons of its own.
+    let position_bracket = host_port.rfind(']').unwrap_or(0);
+    match host_port[position_bracket..].rfind(':') {
+        Some(position) => (&host_port[..position_bracket + position], Some(&host_port[position_bracket + position + 1..])),
+        None => (host_port, None),
+    }
+}
+
+pub fn normalize_url(url: &str) -> String {
+    // Lowercase the scheme and host, add a scheme to "www." URLs, drop the default port, the
+    // trailing slash, an empty fragment and tracking parameters such as utm_source. Two links
+    // with the same normalized URL go to the same page.
+    let url = url.trim();
+    let url = if url.to_lowercase().starts_with("www.") { format!("http://{}", url) } else { url.to_string() };
+    let (scheme, rest) = match url.split_once("://") {
+        Some((scheme, rest)) => (scheme.to_lowercase(), rest),
+        None => {
+            return match url.split_once(":") {
+                Some((scheme, rest)) => format!("{}:{}", scheme.to_lowercase(), rest),
+                None => url,
+            };
+        },
+    };
+    let (rest, fragment) = match rest.split_once("#") {
+        Some((rest, fragment)) if !fragment.is_empty() => (rest, Some(fragment)),
+        Some((rest, _)) => (rest, None),
+        None => (rest, None),
+    };
+    let (rest, query) = match rest.split_once("?") {
+        Some((rest, query)) => (rest, Some(query)),
+        None => (rest, None),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(position) => (&rest[..position], &rest[position..]),
+        None => (rest, ""),
+    };
+    let (user_info, host_port) = match authority.rsplit_once("@") {
+        Some((user_info, host_port)) => (Some(user_info), host_port),
+        None => (None, authority),
+    };
+    let (host, port) = split_port(host_port);
+    let port = port.filter(|port| !(scheme == "http" && *port == "80") && !(scheme == "https" && *port == "443"));
+
+    let mut s = format!("{}://", scheme);
+    if let Some(user_info) = user_info {
+        s.push_str(&format!("{}@", user_info));
+    }
+    s.push_str(&host.to_lowercase());
+    if let Some(port) = port {
+        s.push_str(&format!(":{}", port));
+    }
+    s.push_str(path.trim_end_matches('/'));
+    if let Some(query) = query {
+        let params: Vec<&str> = query.split('&')
+            .filter(|param| !param.is_empty())
+            .filter(|param| {
+                let name = param.split('=').next().unwrap().to_lowercase();
+                !name.starts_with("utm_") && !TRACKING_PARAMS.contains(&name.as_str())
+            })
+            .collect();
+        if !params.is_empty() {
+            s.push_str(&format!("?{}", params.join("&")));
+        }
+    }
+    if let Some(fragment) = fragment {
+        s.push_str(&format!("#{}", fragment));
+    }
+    s
+}
+
+pub fn page_url(url: &str) -> String {
+    // The normalized URL without the scheme, "www.", query string or fragment, so that
+    // http://www.example.com/a?page=2 and https://example.com/a are the same page even though
+    // they aren't the same URL.
+    let normalized = normalize_url(url);
+    let rest = normalized.split_once("://").map_or(normalized.as_str(), |x| x.1);
+    let rest = rest.split(|c| c == '?' || c == '#').next().unwrap();
+    let rest = rest.strip_prefix("www.").unwrap_or(rest);
+    rest.trim_end_matches('/').to_string()
+}
+
+pub fn url_domain(url: &str) -> Option<String> {
+    // The host without "www.", as in "en.wikipedia.org".
+    let normalized = normalize_url(url);
+    let rest = normalized.split_once("://")?.1;
+    let authority = rest.split(|c| c == '/' || c == '?' || c == '#').next().unwrap();
+    let (host, _) = split_port(authority.rsplit('@').next().unwrap());
+    let host = host.strip_prefix("www.").unwrap_or(host);
+    if host.is_empty() { None } else { Some(host.to_string()) }
+}
+
+pub fn url_links(topic: &Topic) -> Vec<(String, Option<String>)> {
+    // The URL and label of each URL link in the topic.
+    let links = if topic.is_resolved { topic.links.clone() } else { parse_url_links(&topic.content) };
+    links.into_iter()
+        .filter_map(|link| match link {
+            Link::Url { url, label } => Some((url, label)),
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn report_domains(wiki: &Wiki) {
+    // Which sites the wiki depends on, with the number of links and topics for each, and the URLs
+    // that aren't valid.
+    let mut domains: BTreeMap<String, (usize, Vec<String>)> = BTreeMap::new();
+    let mut invalid = vec![];
+    for topic in wiki.topics.values() {
+        let label = format!("{}: {}", topic.project_name, topic.name);
+        for (url, _) in url_links(topic) {
+            let problems = validate_url(&url);
+            if !problems.is_empty() {
+                invalid.push(format!("{}: {}: {}", label, url, problems.join(" ")));
+                continue;
+            }
+            let domain = url_domain(&url).unwrap_or_else(|| "(none)".to_string());
+            let entry = domains.entry(domain).or_insert_with(|| { (0, vec![]) });
+            entry.0 += 1;
+            if !entry.1.contains(&label) {
+                entry.1.push(label.clone());
+            }
+        }
+    }
+    let mut domains: Vec<(String, (usize, Vec<String>))> = domains.into_iter().collect();
+    domains.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then(a.0.cmp(&b.0)));
+    for (domain, (count_links, topics)) in domains.iter() {
+        println!("{:>6} link(s) in {:>5} topic(s): {}", count_links, topics.len(), domain);
+    }
+    for line in invalid.iter() {
+        println!("Invalid: {}", line);
+    }
+    println!("\n{} domain(s), {} link(s), {} invalid", domains.len(), domains.iter().map(|x| (x.1).0).sum::<usize>(), invalid.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize() {
+        assert_eq!(normalize_url(" HTTPS://Example.COM/Path/ "), "https://example.com/Path");
+        assert_eq!(normalize_url("http://example.com:80/"), "http://example.com");
+        assert_eq!(normalize_url("https://example.com:443/a"), "https://example.com/a");
+        assert_eq!(normalize_url("https://example.com:8443/a"), "https://example.com:8443/a");
+        assert_eq!(normalize_url("www.Example.com/a"), "http://www.example.com/a");
+        assert_eq!(normalize_url("https://example.com/a?utm_source=x&id=3&FBCLID=y&&gclid=z"), "https://example.com/a?id=3");
+        assert_eq!(normalize_url("https://example.com/a?utm_medium=x"), "https://example.com/a");
+        assert_eq!(normalize_url("https://example.com/a#"), "https://example.com/a");
+        assert_eq!(normalize_url("https://example.com/a#Part"), "https://example.com/a#Part");
+        assert_eq!(normalize_url("https://User@Example.com/"), "https://User@example.com");
+        assert_eq!(normalize_url("http://[::1]:8080/x"), "http://[::1]:8080/x");
+        assert_eq!(normalize_url("http://[::1]:80/x"), "http://[::1]/x");
+        assert_eq!(normalize_url("MAILTO:someone@example.com"), "mailto:someone@example.com");
+        assert_eq!(normalize_url("no scheme"), "no scheme");
+    }
+
+    #[test]
+    fn validate() {
+        let is_valid = |url: &str| validate_url(url).is_empty();
+        assert!(is_valid("https://example.com/a?b=c#d"));
+        assert!(is_valid("www.example.com"));
+        assert!(is_valid("https://user@example.com:8080/"));
+        assert!(is_valid("http://[::1]:8080/"));
+        assert!(is_valid("http://[2001:db8::1]/"));
+        assert!(is_valid("file:///C:/Documents/Report.pdf"));
+        assert!(is_valid("mailto:someone@example.com"));
+        assert_eq!(validate_url(""), vec!["Empty URL."]);
+        assert_eq!(validate_url("example.com"), vec!["No scheme such as https://."]);
+        assert_eq!(validate_url("gopher://example.com"), vec!["Unknown scheme \"gopher\"."]);
+        assert_eq!(validate_url("mailto:someone"), vec!["No @ in the email address."]);
+        assert_eq!(validate_url("https:example.com"), vec!["Expected \"//\" after \"https:\"."]);
+        assert_eq!(validate_url("https:///a"), vec!["No host name."]);
+        assert_eq!(validate_url("https://example..com"), vec!["Host name \"example..com\" has an empty part."]);
+        assert_eq!(validate_url("https://exa$mple.com"), vec!["Host name \"exa$mple.com\" has characters that aren't allowed."]);
+        assert_eq!(validate_url("http://[::1]:99999/"), vec!["Port \"99999\" isn't a number."]);
+        assert_eq!(validate_url("https://example.com:port"), vec!["Port \"port\" isn't a number."]);
+        assert_eq!(validate_url("https://example.com/a b").len(), 1);
+    }
+
+    #[test]
+    fn trim_end() {
+        assert_eq!(trim_url_end("https://example.com/a."), "https://example.com/a");
+        assert_eq!(trim_url_end("https://example.com/a?!),"), "https://example.com/a");
+        assert_eq!(trim_url_end("https://en.wikipedia.org/wiki/Rust_(programming_language)"), "https://en.wikipedia.org/wiki/Rust_(programming_language)");
+        assert_eq!(trim_url_end("https://en.wikipedia.org/wiki/Rust_(programming_language))."), "https://en.wikipedia.org/wiki/Rust_(programming_language)");
+        assert_eq!(trim_url_end("https://example.com/a]"), "https://example.com/a");
+        assert_eq!(trim_url_end("..."), "");
+    }
+
+    #[test]
+    fn bare() {
+        let content = "See https://en.wikipedia.org/wiki/Rust_(programming_language). Also (www.example.com/a), \
+            [[$URL:https://linked.example.com|Linked]] [[Topic|http://not.a.bare.url]] mid-wordhttp://no.example.com \
+            \"ftp://files.example.com/x\" and HTTPS://Upper.example.com!";
+        assert_eq!(bare_urls(content), vec![
+            "https://en.wikipedia.org/wiki/Rust_(programming_language)",
+            "www.example.com/a",
+            "ftp://files.example.com/x",
+            "HTTPS://Upper.example.com",
+        ]);
+        let links = parse_url_links(content);
+        assert_eq!(links.len(), 5);
+        match &links[0] {
+            Link::Url { url, label } => assert_eq!((url.as_str(), label.as_deref()), ("https://linked.example.com", Some("Linked"))),
+            link => panic!("{:?}", link),
+        }
+        for (start, end) in url_spans(content) {
+            assert!(content[start..end].to_lowercase().contains("example.com") || content[start..end].contains("wikipedia"));
+        }
+    }
+
+    #[test]
+    fn page() {
+        assert_eq!(page_url("http://www.Example.com/a/?page=2#top"), "example.com/a");
+        assert_eq!(page_url("https://example.com/a"), "example.com/a");
+        assert_eq!(page_url("www.example.com"), "example.com");
+        assert_eq!(url_domain("https://www.en.Wikipedia.org:8080/wiki"), Some("en.wikipedia.org".to_string()));
+        assert_eq!(url_domain("mailto:someone@example.com"), None);
+    }
+}
@@ -0,0 +1,225 @@
+use std::{fs, io, path, thread, time};
+use std::collections::{BTreeMap, BTreeSet};
+use util::parse;
+use super::cache::{self, CacheUpdate, ImportCache};
+use super::export::{self, ExportFormat, ExportNames};
+use super::input::{self, Encoding};
+use super::lint::{self, Diagnostic};
+use super::model::{Topic, Wiki};
+use super::paths;
+use super::plan::{ConflictHandling, FileOperation};
+use super::reconcile;
+
+// Watching a folder of one-.TXT-per-topic files while they're being cleaned up. The folder is
+// polled rather than using file system notifications, which behave differently on each OS and
+// on network drives. Only files whose size or modified time changed are read again, and only
+// the topics affected by a change are linted and exported again.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FileStamp {
+    modified: Option<time::SystemTime>,
+    len: u64,
+    content_hash: u64,
+}
+
+pub struct FolderWatch {
+    pub path_folder: path::PathBuf,
+    pub project_name: String,
+    pub encoding: Option<Encoding>,
+    pub wiki: Wiki,
+    files: BTreeMap<path::PathBuf, (FileStamp, String)>,
+    // The topic each file holds, from matching the files against the full export. File names
+    // can't be turned back into topic names since characters like "/" are replaced.
+    topic_names: Option<BTreeMap<path::PathBuf, String>>,
+    cache: ImportCache,
+    path_cache_file: Option<path::PathBuf>,
+    // The current diagnostics for each topic key, so that a new lint can be compared with them.
+    diagnostics: BTreeMap<String, Vec<Diagnostic>>,
+    // The file each topic key was last exported to. A topic's file name can depend on the other
+    // topics in the project, so once it's gone its name can't be worked out again.
+    exported: BTreeMap<String, path::PathBuf>,
+}
+
+pub struct WatchSteps {
+    pub lint: bool,
+    pub export: Option<(path::PathBuf, ExportFormat)>,
+}
+
+impl FolderWatch {
+    pub fn new(path_folder: &path::Path, project_name: &str, encoding: Option<Encoding>, path_full_export_file: Option<&path::Path>, path_cache_file: Option<&path::Path>) -> io::Result<Self> {
+        // The cache is tied to the folder rather than an export file, so it needs its own file
+        // apart from the project's cache_file.
+        assert!(path_folder.is_absolute());
+        paths::require_folder(path_folder)?;
+        let topic_names = match path_full_export_file {
+            Some(path_full_export_file) => Some(reconcile::reconcile(path_full_export_file, path_folder, encoding)?.matched),
+            None => None,
+        };
+        let cache = match path_cache_file {
+            Some(path_cache_file) => ImportCache::load(path_cache_file, path_folder, project_name),
+            None => ImportCache::new(path_folder, project_name),
+        };
+        Ok(Self {
+            path_folder: path_folder.to_path_buf(),
+            project_name: project_name.to_string(),
+            encoding,
+            wiki: Wiki::new(),
+            files: BTreeMap::new(),
+            topic_names,
+            cache,
+            path_cache_file: path_cache_file.map(|x| x.to_path_buf()),
+            diagnostics: BTreeMap::new(),
+            exported: BTreeMap::new(),
+        })
+    }
+
+    pub fn scan(&mut self) -> io::Result<(CacheUpdate, Vec<Topic>)> {
+        // Bring the wiki in line with the files in the folder. Also returns the topics that were
+        // removed.
+        let mut update = CacheUpdate::default();
+        let mut content_hashes = BTreeMap::new();
+        let paths_file = parse::get_files_ci(&self.path_folder, "*.txt")?;
+        for path_file in paths_file.iter() {
+            let metadata = fs::metadata(path_file)?;
+            let previous = self.files.get(path_file).map(|(stamp, _)| *stamp);
+            if let Some(previous) = previous {
+                if previous.modified == metadata.modified().ok() && previous.len == metadata.len() {
+                    update.reused += 1;
+                    continue;
+                }
+            }
+            let decoded = input::read_file(path_file, self.encoding)?;
+            decoded.print_problems(path_file.to_str().unwrap());
+            let content_hash = cache::hash_text(&decoded.text);
+            let stamp = FileStamp { modified: metadata.modified().ok(), len: metadata.len(), content_hash };
+            let topic_name = self.topic_name(path_file);
+            let key = Wiki::topic_key(&self.project_name, &topic_name);
+            match previous {
+                // Touched but not changed.
+                Some(previous) if previous.content_hash == content_hash => {
+                    update.reused += 1;
+                    self.files.insert(path_file.clone(), (stamp, key));
+                    continue;
+                },
+                Some(_) => update.changed.push(key.clone()),
+                None => update.added.push(key.clone()),
+            }
+            let mut topic = Topic::new(&self.project_name, &topic_name, &decoded.text);
+            // On the first scan most topics are usually the same as when the cache was saved, so
+            // take what was parsed from them last time. They still count as added since this
+            // watch hasn't linted or exported them yet.
+            if let Some(cached) = self.cache.topics.get(&key).filter(|x| x.content_hash == content_hash && x.name == topic_name) {
+                topic.category = cached.category.clone();
+                topic.attributes = cached.attributes.clone();
+                topic.links = cached.links.clone();
+                topic.is_resolved = true;
+            }
+            self.wiki.add_topic(topic);
+            content_hashes.insert(key.clone(), content_hash);
+            self.files.insert(path_file.clone(), (stamp, key));
+        }
+        let paths_removed: Vec<path::PathBuf> = self.files.keys().filter(|x| !paths_file.contains(x)).cloned().collect();
+        let mut removed_topics = vec![];
+        for path_file in paths_removed {
+            let (_, key) = self.files.remove(&path_file).unwrap();
+            removed_topics.extend(self.wiki.topics.remove(&key));
+            update.removed.push(key);
+        }
+        if !update.is_empty() {
+            self.wiki.resolve_attributes();
+            self.cache.record(&self.wiki, &update, &content_hashes);
+            if let Some(path_cache_file) = &self.path_cache_file {
+                self.cache.save(path_cache_file)?;
+            }
+        }
+        Ok((update, removed_topics))
+    }
+
+    fn topic_name(&self, path_file: &path::Path) -> String {
+        // A file that wasn't matched against the export, such as one for a topic created since
+        // the export was made, falls back to its file name.
+        match self.topic_names.as_ref().and_then(|topic_names| topic_names.get(path_file)) {
+            Some(topic_name) => topic_name.clone(),
+            None => path_file.file_stem().unwrap().to_str().unwrap().to_string(),
+        }
+    }
+
+    pub fn lint(&mut self, update: &CacheUpdate) -> (Vec<Diagnostic>, Vec<Diagnostic>) {
+        // Lint only the affected topics and return the diagnostics that are new and the ones that
+        // have gone away since the last time.
+        let mut new = vec![];
+        let mut resolved = vec![];
+        for key in update.removed.iter() {
+            resolved.append(&mut self.diagnostics.remove(key).unwrap_or_default());
+        }
+        for key in update.affected_topics(&self.cache) {
+            let topic = match self.wiki.topics.get(&key) {
+                Some(topic) => topic,
+                None => continue,
+            };
+            let current = lint::lint_topic(&self.wiki, topic);
+            let previous = self.diagnostics.remove(&key).unwrap_or_default();
+            new.extend(current.iter().filter(|x| !previous.contains(x)).cloned());
+            resolved.extend(previous.into_iter().filter(|x| !current.contains(x)));
+            if !current.is_empty() {
+                self.diagnostics.insert(key, current);
+            }
+        }
+        new.sort();
+        resolved.sort();
+        (new, resolved)
+    }
+
+    pub fn export(&mut self, update: &CacheUpdate, removed_topics: &[Topic], path_dest: &path::Path, export_format: ExportFormat) -> io::Result<usize> {
+        // Only topics whose own content changed need to be written again. Exported files for
+        // topics that are gone are deleted.
+        let keys: BTreeSet<String> = update.added.iter().chain(update.changed.iter()).cloned().collect();
+        let mut plan = export::plan_export_topics(&self.wiki, &keys, path_dest, export_format);
+        let names = ExportNames::from_wiki(&self.wiki, export_format);
+        let paths_written: BTreeMap<String, path::PathBuf> = keys.iter()
+            .map(|key| (key.clone(), export::export_path(&self.wiki.topics[key], path_dest, &names)))
+            .collect();
+        for topic in removed_topics.iter() {
+            // Another topic may have just been written to the same file.
+            let key = Wiki::topic_key(&topic.project_name, &topic.name);
+            if let Some(path_file) = self.exported.remove(&key).filter(|x| x.is_file() && !paths_written.values().any(|y| y == x)) {
+                plan.push(FileOperation::Delete { path: path_file });
+            }
+        }
+        let done = plan.apply(ConflictHandling::Overwrite)?.done;
+        self.exported.extend(paths_written);
+        Ok(done)
+    }
+
+    pub fn run_steps(&mut self, update: &CacheUpdate, removed_topics: &[Topic], steps: &WatchSteps) -> io::Result<()> {
+        if steps.lint {
+            let (new, resolved) = self.lint(update);
+            for diagnostic in new.iter() {
+                println!("NEW       {}", diagnostic);
+            }
+            for diagnostic in resolved.iter() {
+                println!("RESOLVED  {}", diagnostic);
+            }
+        }
+        if let Some((path_dest, export_format)) = &steps.export {
+            let count = self.export(update, removed_topics, path_dest, *export_format)?;
+            if count > 0 {
+                println!("Exported {} file(s) to {}", count, path_dest.to_str().unwrap());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn watch(&mut self, steps: &WatchSteps, interval: time::Duration) -> io::Result<()> {
+        // Runs until there's an error. The first scan loads every file and reports all of the
+        // diagnostics as new.
+        loop {
+            let (update, removed_topics) = self.scan()?;
+            if !update.is_empty() {
+                update.print();
+                self.run_steps(&update, &removed_topics, steps)?;
+            }
+            thread::sleep(interval);
+        }
+    }
+}
@@ -0,0 +1,298 @@
+// Round-trip editing of topic content. Everything here works on the content as it came from the
+// export or topic file and changes only the lines it has to, so spacing and the rest of the text
+// come back out exactly as they went in. Line endings are always "\n" here: input::decode()
+// normalises them and the plan's TextFormat puts the file's own line endings back on write.
+
+use crate::*;
+use super::urls;
+
+#[derive(Clone, Debug)]
+pub struct AttributeRow {
+    pub line_index: usize,
+    pub name: String,
+    pub values: Vec<String>,
+}
+
+pub fn split_lines(content: &str) -> Vec<&str> {
+    // Each line keeps its own line ending so that joining them gives back the original content.
+    content.split_inclusive('\n').collect()
+}
+
+fn trim_line_ending(line: &str) -> &str {
+    line.trim_end_matches('\n')
+}
+
+fn own_line_ending<'a>(line: &'a str) -> &'a str {
+    &line[trim_line_ending(line).len()..]
+}
+
+pub fn is_attribute_row(line: &str) -> bool {
+    // The same test used by Topic::parse_attributes().
+    line.contains("||[[") && line.contains(":=")
+}
+
+pub fn is_category_line(line: &str) -> bool {
+    line.trim().starts_with("[[$CATEGORY:")
+}
+
+pub fn parse_attribute_row(line: &str) -> Option<(String, Vec<String>)> {
+    // Example:
+    // ||Author||[[Author:=Jason Fried]], [[Author:=Matthew Linderman]]||
+    let line = trim_line_ending(line).trim();
+    if !is_attribute_row(line) || !line.starts_with("||") || !line.ends_with("||") {
+        return None;
+    }
+    let (name, values_part) = line[2..].split_once("||")?;
+    let mut values = vec![];
+    for entry in values_part.split("[[").skip(1) {
+        let entry = entry.split("]]").next().unwrap();
+        if let Some((_, value)) = entry.split_once(":=") {
+            if value != CT_DUMMY_VALUE {
+                values.push(value.to_string());
+            }
+        }
+    }
+    Some((name.trim().to_string(), values))
+}
+
+pub fn attribute_rows(content: &str) -> Vec<AttributeRow> {
+    split_lines(content).iter()
+        .enumerate()
+        .filter_map(|(line_index, line)| parse_attribute_row(line)
+            .map(|(name, values)| AttributeRow { line_index, name, values }))
+        .collect()
+}
+
+pub fn find_attribute_row(content: &str, attr_name: &str) -> Option<AttributeRow> {
+    attribute_rows(content).into_iter().find(|row| row.name == attr_name)
+}
+
+pub fn gen_attribute_row(attr_name: &str, values: &[String]) -> String {
+    if values.is_empty() {
+        gen::gen_table_field_string_single(attr_name, None)
+    } else {
+        gen::gen_table_field_string_multiple(attr_name, &values.to_vec())
+    }
+}
+
+pub fn set_attribute_values(content: &str, attr_name: &str, values: &[String]) -> String {
+    // Replace the row for this attribute in place. If there's no such row, add one after the last
+    // attribute row, and if the topic has no attribute table at all, create one just below the
+    // $CATEGORY line or at the top of the topic.
+    let new_row = gen_attribute_row(attr_name, values);
+    let mut lines = split_lines(content).iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    if let Some(row) = find_attribute_row(content, attr_name) {
+        let old_line = &lines[row.line_index];
+        lines[row.line_index] = format!("{}{}", new_row, own_line_ending(old_line));
+        return lines.concat();
+    }
+    let (insert_index, insert_text) = match attribute_rows(content).last() {
+        Some(last_row) => (last_row.line_index + 1, format!("{}\n", new_row)),
+        None => match lines.iter().position(|line| is_category_line(line)) {
+            Some(category_index) => (category_index + 1, format!("\n{{|\n{}\n|}}\n", new_row)),
+            None => (0, format!("{{|\n{}\n|}}\n\n", new_row)),
+        },
+    };
+    if insert_index > 0 && !lines[insert_index - 1].ends_with('\n') {
+        lines[insert_index - 1].push('\n');
+    }
+    lines.insert(insert_index, insert_text);
+    lines.concat()
+}
+
+pub fn remove_attribute(content: &str, attr_name: &str) -> String {
+    match find_attribute_row(content, attr_name) {
+        Some(row) => {
+            let mut lines = split_lines(content);
+            lines.remove(row.line_index);
+            lines.concat()
+        },
+        None => content.to_string(),
+    }
+}
+
+pub fn edit_attribute_rows<F>(content: &str, mut f: F) -> String
+    where F: FnMut(&str, &[String]) -> Vec<(String, Vec<String>)>
+{
+    // Call the closure with the name and values of each attribute row. It returns the rows to put
+    // in its place: none to remove the row, one to change it, or more to split it. A row that comes
+    // back unchanged keeps its original text. If the edits leave two rows with the same attribute
+    // name, the values are combined into the first row and the later row is dropped, since
+    // Topic::parse_attributes() won't accept an attribute that appears more than once.
+    let mut new_lines: Vec<String> = vec![];
+    let mut row_positions: Vec<(String, usize, Vec<String>)> = vec![];
+    for line in split_lines(content) {
+        let (name, values) = match parse_attribute_row(line) {
+            Some(row) => row,
+            None => {
+                new_lines.push(line.to_string());
+                continue;
+            }
+        };
+        let new_rows = f(&name, &values);
+        let is_unchanged = new_rows.len() == 1 && new_rows[0].0 == name && new_rows[0].1 == values;
+        let last_row_index = new_rows.len().saturating_sub(1);
+        for (row_index, (new_name, new_values)) in new_rows.into_iter().enumerate() {
+            match row_positions.iter_mut().find(|(name, _, _)| *name == new_name) {
+                Some((_, line_index, existing_values)) => {
+                    for value in new_values {
+                        if !existing_values.contains(&value) {
+                            existing_values.push(value);
+                        }
+                    }
+                    let old_eol = own_line_ending(&new_lines[*line_index]).to_string();
+                    new_lines[*line_index] = format!("{}{}", gen_attribute_row(&new_name, existing_values), old_eol);
+                },
+                None => {
+                    let new_line = if is_unchanged {
+                        line.to_string()
+                    } else {
+                        // When a row is split, only the last of the new rows takes the original
+                        // row's line ending, which may be none if it was the last line.
+                        let this_eol = if row_index == last_row_index { own_line_ending(line) } else { "\n" };
+                        format!("{}{}", gen_attribute_row(&new_name, &new_values), this_eol)
+                    };
+                    row_positions.push((new_name, new_lines.len(), new_values));
+                    new_lines.push(new_line);
+                },
+            }
+        }
+    }
+    new_lines.concat()
+}
+
+pub fn body_without_attributes(content: &str) -> String {
+    // The content minus the $CATEGORY line and the attribute table, used when moving the text of
+    // one topic into another. A table is only dropped as a whole if it holds nothing but attribute
+    // rows; attribute rows in a mixed table are removed one at a time.
+    let lines = split_lines(content);
+    let mut keep = vec![true; lines.len()];
+    let mut line_index = 0;
+    while line_index < lines.len() {
+        let line = lines[line_index].trim();
+        if is_category_line(line) || is_attribute_row(line) {
+            keep[line_index] = false;
+        } else if line == "{|" {
+            let mut end_index = line_index + 1;
+            while end_index < lines.len() && is_attribute_row(lines[end_index]) {
+                end_index += 1;
+            }
+            if end_index > line_index + 1 && (end_index == lines.len() || lines[end_index].trim() == "|}") {
+                for index in line_index..std::cmp::min(end_index + 1, lines.len()) {
+                    keep[index] = false;
+                }
+                line_index = end_index;
+            }
+        }
+        line_index += 1;
+    }
+    lines.iter().zip(keep.iter())
+        .filter(|(_, keep)| **keep)
+        .map(|(line, _)| *line)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+pub fn heading_depth(line: &str) -> Option<(usize, String)> {
+    // Headings look like "=Heading=" or "==Subheading==" as written by gen::gen_header().
+    let line = trim_line_ending(line).trim();
+    let depth = line.chars().take_while(|c| *c == '=').count();
+    if depth == 0 || line.len() <= depth * 2 || !line.ends_with(&"=".repeat(depth)) {
+        return None;
+    }
+    let text = line[depth..line.len() - depth].trim();
+    if text.is_empty() || text.starts_with('=') || text.ends_with('=') {
+        return None;
+    }
+    Some((depth, text.to_string()))
+}
+
+pub fn replace_urls<F>(content: &str, mut f: F) -> String
+    where F: FnMut(&str) -> Option<String>
+{
+    // Call the closure with each URL in the content, whether in a $URL link or bare, and put the
+    // URL it returns in its place. Labels and everything else stay as they were.
+    let mut s = String::new();
+    let mut position = 0;
+    for (start, end) in urls::url_spans(content) {
+        if let Some(new_url) = f(&content[start..end]) {
+            s.push_str(&content[position..start]);
+            s.push_str(&new_url);
+            position = end;
+        }
+    }
+    s.push_str(&content[position..]);
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_round_trip() {
+        let content = "a\nb\r\n\nc";
+        assert_eq!(split_lines(content), vec!["a\n", "b\r\n", "\n", "c"]);
+        assert_eq!(split_lines(content).concat(), content);
+        assert!(split_lines("").is_empty());
+    }
+
+    #[test]
+    fn set_values() {
+        let content = "[[$CATEGORY:Books]]\n\n{|\n||Author||[[Author:=A]]||\n||Year||[[Year:=2000]]||\n|}\n\nText\n";
+        // In place.
+        assert_eq!(set_attribute_values(content, "Year", &["2001".to_string()]),
+            "[[$CATEGORY:Books]]\n\n{|\n||Author||[[Author:=A]]||\n||Year||[[Year:=2001]]||\n|}\n\nText\n");
+        // After the last row.
+        assert_eq!(set_attribute_values(content, "Format", &["Paper".to_string(), "Kindle".to_string()]),
+            "[[$CATEGORY:Books]]\n\n{|\n||Author||[[Author:=A]]||\n||Year||[[Year:=2000]]||\n||Format||[[Format:=Paper]], [[Format:=Kindle]]||\n|}\n\nText\n");
+        // No values gives the placeholder.
+        assert_eq!(set_attribute_values(content, "Author", &[]),
+            "[[$CATEGORY:Books]]\n\n{|\n||Author||[[Author:=***]]||\n||Year||[[Year:=2000]]||\n|}\n\nText\n");
+        // A new table below the category line, or at the top without one.
+        assert_eq!(set_attribute_values("[[$CATEGORY:Books]]\nText", "Year", &["2000".to_string()]),
+            "[[$CATEGORY:Books]]\n\n{|\n||Year||[[Year:=2000]]||\n|}\nText");
+        assert_eq!(set_attribute_values("[[$CATEGORY:Books]]", "Year", &["2000".to_string()]),
+            "[[$CATEGORY:Books]]\n\n{|\n||Year||[[Year:=2000]]||\n|}\n");
+        assert_eq!(set_attribute_values("Text\n", "Year", &["2000".to_string()]),
+            "{|\n||Year||[[Year:=2000]]||\n|}\n\nText\n");
+        assert_eq!(remove_attribute(content, "Author"), "[[$CATEGORY:Books]]\n\n{|\n||Year||[[Year:=2000]]||\n|}\n\nText\n");
+    }
+
+    #[test]
+    fn edit_rows() {
+        let content = "{|\n|| Author ||[[Author:=A]]||\n||Narrator||[[Narrator:=B]], [[Narrator:=A]]||\n||Tags||[[Tags:=x]], [[Tags:=y]]||\n||Old||[[Old:=z]]||\n|}";
+        let new_content = edit_attribute_rows(content, |name, values| match name {
+            // Merged into the Author row above.
+            "Narrator" => vec![("Author".to_string(), values.to_vec())],
+            // Split in two.
+            "Tags" => vec![("Tag 1".to_string(), vec![values[0].clone()]), ("Tag 2".to_string(), vec![values[1].clone()])],
+            "Old" => vec![],
+            _ => vec![(name.to_string(), values.to_vec())],
+        });
+        assert_eq!(new_content, "{|\n||Author||[[Author:=A]], [[Author:=B]]||\n||Tag 1||[[Tag 1:=x]]||\n||Tag 2||[[Tag 2:=y]]||\n|}");
+        // A row that isn't changed keeps its own spacing.
+        let unchanged = edit_attribute_rows(content, |name, values| vec![(name.to_string(), values.to_vec())]);
+        assert_eq!(unchanged, content);
+        // The last row keeps having no line ending when split.
+        assert_eq!(edit_attribute_rows("||Tags||[[Tags:=x]], [[Tags:=y]]||", |_, values| values.iter().map(|x| (x.clone(), vec![x.clone()])).collect()),
+            "||x||[[x:=x]]||\n||y||[[y:=y]]||");
+    }
+
+    #[test]
+    fn body_and_headings() {
+        assert_eq!(body_without_attributes("[[$CATEGORY:Books]]\n\n{|\n||Year||[[Year:=2000]]||\n|}\n\nText\n"), "Text");
+        assert_eq!(heading_depth("==Sub heading== \n"), Some((2, "Sub heading".to_string())));
+        assert_eq!(heading_depth("=Unbalanced=="), None);
+        assert_eq!(heading_depth("=="), None);
+        assert_eq!(heading_depth("Text"), None);
+    }
+
+    #[test]
+    fn replace_urls_in_place() {
+        let content = "[[$URL:http://a.com|A]] and http://b.com. and [[$URL: http://c.com ]]";
+        let new_content = replace_urls(content, |url| if url == "http://c.com" { None } else { Some(url.replace("http:", "https:")) });
+        assert_eq!(new_content, "[[$URL:https://a.com|A]] and https://b.com. and [[$URL: http://c.com ]]");
+    }
+}